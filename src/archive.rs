@@ -0,0 +1,597 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use futures::{Stream, StreamExt};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use zip::result::ZipError;
+use zip::ZipArchive;
+
+use crate::backend_registry::BackendConfig;
+use crate::config_loader::ServiceConfig;
+use crate::chat_backend::{
+    BackendEvent, Capabilities, Channel, ChatBackend, Credentials, HistoryError, LoginError, Message, PostError,
+};
+use crate::time::timestamp;
+
+/// The files of a Slack export listing conversations: public channels,
+/// private channels, group and direct messages.
+const SLACK_CONVERSATION_LISTS: &[&str] = &["channels.json", "groups.json", "mpims.json", "dms.json"];
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(io::Error),
+    Zip(ZipError),
+    /// A file of a Slack export is not what Slack writes.
+    Json { file: String, error: serde_json::Error },
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveError::Io(e) => write!(f, "{}", e),
+            ArchiveError::Zip(e) => write!(f, "Invalid zip file: {}", e),
+            ArchiveError::Json { file, error } => write!(f, "Invalid {} in Slack export: {}", file, error),
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<io::Error> for ArchiveError {
+    fn from(error: io::Error) -> Self {
+        ArchiveError::Io(error)
+    }
+}
+
+impl From<ZipError> for ArchiveError {
+    fn from(error: ZipError) -> Self {
+        ArchiveError::Zip(error)
+    }
+}
+
+/// The conversations of a Slack workspace export or of an mbox file.
+#[derive(Debug, Clone, Default)]
+pub struct Archive {
+    pub channels: Vec<Channel>,
+    /// Every message, ordered by channel then id.
+    pub messages: Vec<Message>,
+}
+
+impl Archive {
+    /// Reads a Slack export when `path` ends in `.zip`, and an mbox file
+    /// otherwise.
+    pub fn open(path: &Path) -> Result<Self, ArchiveError> {
+        let file = File::open(path)?;
+        if is_zip(path) {
+            read_slack_export(file)
+        } else {
+            Ok(read_mbox(mailbox(path), BufReader::new(file))?)
+        }
+    }
+
+    /// Up to `limit` messages of a channel older than message `before`, or
+    /// the latest ones, oldest first.
+    pub fn history(&self, channel_id: &str, before: Option<u64>, limit: usize) -> Vec<Message> {
+        let older: Vec<&Message> = self
+            .messages
+            .iter()
+            .filter(|message| message.channel_id == channel_id && before.is_none_or(|before| message.id < before))
+            .collect();
+        older[older.len().saturating_sub(limit)..].iter().map(|message| (*message).clone()).collect()
+    }
+}
+
+/// Reads the conversations of an archive without its messages.
+pub fn read_channels(path: &Path) -> Result<Vec<Channel>, ArchiveError> {
+    if !is_zip(path) {
+        return Ok(vec![mailbox(path)]);
+    }
+    let mut zip = ZipArchive::new(File::open(path)?)?;
+    let names = slack_user_names(&mut zip)?;
+    Ok(slack_conversations(&mut zip, &names)?.into_iter().map(|(_, channel)| channel).collect())
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// The single channel of an mbox file, named after the file.
+fn mailbox(path: &Path) -> Channel {
+    let name = path.file_stem().unwrap_or(path.as_os_str()).to_string_lossy().into_owned();
+    Channel { id: name.clone(), name, direct: false }
+}
+
+#[derive(Deserialize)]
+struct SlackUser {
+    id: String,
+    name: String,
+    #[serde(default)]
+    real_name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SlackConversation {
+    id: String,
+    /// Missing for direct messages, which are named after their members.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct SlackMessage {
+    ts: String,
+    #[serde(default)]
+    user: Option<String>,
+    /// The name of bots and integrations, which have no user.
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    text: String,
+}
+
+/// Reads a Slack workspace export: the conversation and user lists at the
+/// top, and a directory per conversation holding a JSON file per day.
+pub fn read_slack_export<R: Read + Seek>(reader: R) -> Result<Archive, ArchiveError> {
+    let mut zip = ZipArchive::new(reader)?;
+    let names = slack_user_names(&mut zip)?;
+    let conversations = slack_conversations(&mut zip, &names)?;
+    let directories: HashMap<&str, &str> =
+        conversations.iter().map(|(directory, channel)| (directory.as_str(), channel.id.as_str())).collect();
+
+    let files: Vec<String> = zip.file_names().map(|name| name.map(|name| name.into_owned())).collect::<Result<_, _>>()?;
+    let mut messages = Vec::new();
+    for file in files {
+        let Some((directory, day)) = file.split_once('/') else {
+            continue;
+        };
+        let Some(channel_id) = directories.get(directory) else {
+            continue;
+        };
+        if !day.ends_with(".json") || day.contains('/') {
+            continue;
+        }
+        let day: Vec<SlackMessage> = read_json(&mut zip, &file)?.unwrap_or_default();
+        messages.extend(day.into_iter().filter_map(|message| slack_message(channel_id, message, &names)));
+    }
+    messages.sort_by(|a, b| (&a.channel_id, a.id).cmp(&(&b.channel_id, b.id)));
+    messages.dedup_by(|a, b| (&a.channel_id, a.id) == (&b.channel_id, b.id));
+    let channels = conversations.into_iter().map(|(_, channel)| channel).collect();
+    Ok(Archive { channels, messages })
+}
+
+/// The display name of every user of a Slack export, by id.
+fn slack_user_names<R: Read + Seek>(zip: &mut ZipArchive<R>) -> Result<HashMap<String, String>, ArchiveError> {
+    let users: Vec<SlackUser> = read_json(zip, "users.json")?.unwrap_or_default();
+    Ok(users
+        .into_iter()
+        .map(|user| (user.id, user.real_name.filter(|name| !name.is_empty()).unwrap_or(user.name)))
+        .collect())
+}
+
+/// Every conversation of a Slack export, with the directory holding its
+/// messages: its name, or its id for direct messages.
+fn slack_conversations<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    names: &HashMap<String, String>,
+) -> Result<Vec<(String, Channel)>, ArchiveError> {
+    let mut conversations = Vec::new();
+    for list in SLACK_CONVERSATION_LISTS {
+        for conversation in read_json::<_, Vec<SlackConversation>>(zip, list)?.unwrap_or_default() {
+            let direct = ["mpims.json", "dms.json"].contains(list);
+            let (directory, name) = match conversation.name {
+                Some(name) => (name.clone(), name),
+                None => {
+                    let members: Vec<&str> = conversation
+                        .members
+                        .iter()
+                        .map(|member| names.get(member).unwrap_or(member).as_str())
+                        .collect();
+                    (conversation.id.clone(), members.join(", "))
+                }
+            };
+            conversations.push((directory, Channel { id: conversation.id, name, direct }));
+        }
+    }
+    Ok(conversations)
+}
+
+/// Parses a file of the export, or returns `None` if it has no such file.
+fn read_json<R: Read + Seek, T: DeserializeOwned>(zip: &mut ZipArchive<R>, name: &str) -> Result<Option<T>, ArchiveError> {
+    let file = match zip.by_name(name) {
+        Ok(file) => file,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    serde_json::from_reader(file).map(Some).map_err(|error| ArchiveError::Json { file: name.to_string(), error })
+}
+
+/// Turns a Slack message into a stored one. Its id is its timestamp in
+/// microseconds, which is unique within a channel.
+fn slack_message(channel_id: &str, message: SlackMessage, names: &HashMap<String, String>) -> Option<Message> {
+    let (seconds, fraction) = message.ts.split_once('.').unwrap_or((&message.ts, ""));
+    let seconds: u64 = seconds.parse().ok()?;
+    let micros: u64 = format!("{:0<6.6}", fraction).parse().ok()?;
+    let author = match (&message.user, message.username) {
+        (Some(user), _) => names.get(user).cloned().unwrap_or_else(|| user.clone()),
+        (None, Some(username)) => username,
+        (None, None) => "unknown".to_string(),
+    };
+    Some(Message {
+        // The store keeps ids as SQLite integers.
+        id: seconds.checked_mul(1_000_000)?.checked_add(micros).filter(|id| *id <= i64::MAX as u64)?,
+        channel_id: channel_id.to_string(),
+        author,
+        content: slack_text(&message.text, names),
+        timestamp: Some(seconds),
+        attachments: Vec::new(),
+    })
+}
+
+/// Writes user mentions, `<@U123>`, as `@Name`.
+fn slack_text(text: &str, names: &HashMap<String, String>) -> String {
+    let mut written = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("<@") {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        let user = &rest[start + 2..start + end];
+        let user = user.split_once('|').map_or(user, |(id, _)| id);
+        written.push_str(&rest[..start]);
+        written.push('@');
+        written.push_str(names.get(user).map_or(user, String::as_str));
+        rest = &rest[start + end + 1..];
+    }
+    written.push_str(rest);
+    written
+}
+
+/// Reads an mbox file into a single channel. Messages are numbered in the
+/// order of the file; `>From ` quoting is undone, but MIME parts are kept as
+/// they are.
+pub fn read_mbox(channel: Channel, reader: impl BufRead) -> io::Result<Archive> {
+    let mut mails: Vec<Vec<String>> = Vec::new();
+    for line in reader.split(b'\n') {
+        let line = String::from_utf8_lossy(&line?).trim_end_matches('\r').to_string();
+        if line.starts_with("From ") {
+            mails.push(Vec::new());
+        } else if let Some(mail) = mails.last_mut() {
+            let unquoted = line.trim_start_matches('>');
+            let line = if unquoted.starts_with("From ") && unquoted.len() < line.len() { &line[1..] } else { &line };
+            mail.push(line.to_string());
+        }
+    }
+    let messages = mails.iter().enumerate().map(|(index, lines)| mail(&channel.id, index as u64 + 1, lines)).collect();
+    Ok(Archive { channels: vec![channel], messages })
+}
+
+/// Turns a mail into a message whose content is its subject and body.
+fn mail(channel_id: &str, id: u64, lines: &[String]) -> Message {
+    let blank = lines.iter().position(|line| line.is_empty()).unwrap_or(lines.len());
+    let mut headers: Vec<(String, String)> = Vec::new();
+    for line in &lines[..blank] {
+        match (line.starts_with([' ', '\t']), headers.last_mut(), line.split_once(':')) {
+            (true, Some((_, value)), _) => {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            (_, _, Some((name, value))) => headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string())),
+            _ => {}
+        }
+    }
+    let header = |name: &str| headers.iter().find(|(header, _)| header == name).map(|(_, value)| value.as_str());
+    let body = lines.get(blank + 1..).unwrap_or_default().join("\n");
+    let body = body.trim_end();
+    let content = match header("subject").filter(|subject| !subject.is_empty()) {
+        Some(subject) if body.is_empty() => subject.to_string(),
+        Some(subject) => format!("{}\n\n{}", subject, body),
+        None => body.to_string(),
+    };
+    Message {
+        id,
+        channel_id: channel_id.to_string(),
+        author: header("from").map_or_else(|| "unknown".to_string(), sender_name),
+        content,
+        timestamp: header("date").and_then(parse_mail_date),
+        attachments: Vec::new(),
+    }
+}
+
+/// The display name of a `From:` address, or the address itself.
+fn sender_name(from: &str) -> String {
+    match from.split_once('<') {
+        Some((name, _)) if !name.trim().trim_matches('"').is_empty() => name.trim().trim_matches('"').to_string(),
+        Some((_, address)) => address.trim_end_matches('>').to_string(),
+        None => from.to_string(),
+    }
+}
+
+/// Parses an RFC 2822 date such as `Fri, 1 Mar 2024 12:00:00 +0100`.
+/// Zone names other than UT and GMT are read as UTC.
+pub fn parse_mail_date(date: &str) -> Option<u64> {
+    let date = date.split_once(',').map_or(date, |(_, date)| date);
+    let mut fields = date.split_whitespace();
+    let day: u8 = fields.next()?.parse().ok()?;
+    let month = fields.next()?.to_ascii_lowercase();
+    let month = ["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"]
+        .iter()
+        .position(|name| month.starts_with(name))? as i64
+        + 1;
+    let year: u16 = match fields.next()?.parse().ok()? {
+        year @ 0..50 => year + 2000,
+        year @ 50..1000 => year + 1900,
+        year => year,
+    };
+    let mut time = fields.next()?.split(':').map(|field| field.parse::<u8>().ok());
+    let (hours, minutes, seconds) = (time.next()??, time.next()??, time.next().unwrap_or(Some(0))?);
+    let offset = match fields.next() {
+        Some(zone)
+            if zone.len() == 5
+                && (zone.starts_with('+') || zone.starts_with('-'))
+                && zone[1..].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            let minutes = zone[1..3].parse::<i64>().ok()? * 60 + zone[3..].parse::<i64>().ok()?;
+            if zone.starts_with('-') {
+                -minutes
+            } else {
+                minutes
+            }
+        }
+        _ => 0,
+    };
+    let time_of_day = i64::from(hours) * 3600 + i64::from(minutes) * 60 + i64::from(seconds);
+    let timestamp = timestamp(i64::from(year), month, i64::from(day), time_of_day)?.checked_sub(offset * 60)?;
+    u64::try_from(timestamp).ok()
+}
+
+/// Options of `backend = "archive"` services.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ArchiveConfig {
+    /// The Slack export (`.zip`) or mbox file the service reads.
+    path: PathBuf,
+}
+
+impl BackendConfig for ArchiveConfig {
+    const KIND: &'static str = "archive";
+
+    fn instantiate(self) -> Box<dyn ChatBackend + Send + Sync> {
+        Box::new(ArchiveBackend::new(self.path))
+    }
+}
+
+/// The service table of an `archive` service reading `path`.
+pub fn archive_service_options(path: &Path) -> toml::Table {
+    let mut options = toml::Table::new();
+    options.insert("backend".to_string(), ArchiveConfig::KIND.into());
+    options.insert("path".to_string(), path.to_string_lossy().into_owned().into());
+    options
+}
+
+/// Whether a configured service is the `archive` service reading `path`.
+pub fn is_archive_of(config: &ServiceConfig, path: &Path) -> bool {
+    let configured = config.options().get("path").and_then(|configured| configured.as_str());
+    config.backend() == ArchiveConfig::KIND
+        && configured.is_some_and(|configured| std::fs::canonicalize(configured).is_ok_and(|configured| configured == path))
+}
+
+/// Checks that the archive at canonical `path` may be imported under
+/// `service`, configured as `config`: only a new service or the archive
+/// service reading the same file may be, never the history of another
+/// service. Returns whether the service is already configured.
+pub fn check_import_target(service: &str, config: Option<&ServiceConfig>, path: &Path) -> Result<bool, String> {
+    match config {
+        None => Ok(false),
+        Some(config) if is_archive_of(config, path) => Ok(true),
+        Some(_) => Err(format!("Service '{}' already exists and does not read {}", service, path.display())),
+    }
+}
+
+/// A read-only service serving the conversations of an archive. Its
+/// messages are usually imported into the store beforehand, so they are
+/// only read when history is missing from there. Its channels are read
+/// when its event stream starts, and listed from then on.
+pub struct ArchiveBackend {
+    path: PathBuf,
+    channels: Arc<Mutex<Vec<Channel>>>,
+    loaded: Mutex<Option<Arc<Archive>>>,
+}
+
+impl ArchiveBackend {
+    pub fn new(path: PathBuf) -> Self {
+        ArchiveBackend { path, channels: Arc::new(Mutex::new(Vec::new())), loaded: Mutex::new(None) }
+    }
+
+    async fn archive(&self) -> Result<Arc<Archive>, HistoryError> {
+        if let Some(archive) = self.loaded.lock().unwrap().clone() {
+            return Ok(archive);
+        }
+        let path = self.path.clone();
+        let archive = tokio::task::spawn_blocking(move || Archive::open(&path))
+            .await
+            .expect("reading an archive does not panic")
+            .map_err(|e| HistoryError::ConnectionError(format!("Cannot read {}: {}", self.path.display(), e)))?;
+        let archive = Arc::new(archive);
+        *self.loaded.lock().unwrap() = Some(archive.clone());
+        Ok(archive)
+    }
+}
+
+#[async_trait]
+impl ChatBackend for ArchiveBackend {
+    async fn login(&self, _credentials: &Credentials) -> Result<String, LoginError> {
+        Ok("archive".to_string())
+    }
+
+    fn list_channels(&self) -> BackendEvent {
+        BackendEvent::ChannelList { channels: self.channels.lock().unwrap().clone() }
+    }
+
+    fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
+        let (path, channels) = (self.path.clone(), self.channels.clone());
+        let listed = futures::stream::once(async move {
+            let (path, read) = tokio::task::spawn_blocking(move || {
+                let read = read_channels(&path);
+                (path, read)
+            })
+            .await
+            .expect("reading an archive does not panic");
+            match read {
+                Ok(listed) => {
+                    *channels.lock().unwrap() = listed.clone();
+                    Some(BackendEvent::ChannelList { channels: listed })
+                }
+                Err(e) => {
+                    eprintln!("Cannot read the channels of {}: {}", path.display(), e);
+                    None
+                }
+            }
+        });
+        // Nothing new ever arrives in an archive.
+        Box::pin(listed.filter_map(futures::future::ready).chain(futures::stream::pending()))
+    }
+
+    async fn post_message(&self, _channel_id: &str, _content: &str, _idempotency_key: &str) -> Result<(), PostError> {
+        Err(PostError::PermissionDenied)
+    }
+
+    async fn fetch_history(&self, channel_id: &str, before: Option<u64>, limit: usize) -> Result<Vec<Message>, HistoryError> {
+        let archive = self.archive().await?;
+        if !archive.channels.iter().any(|channel| channel.id == channel_id) {
+            return Err(HistoryError::ChannelNotFound);
+        }
+        Ok(archive.history(channel_id, before, limit))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+
+    use zip::write::SimpleFileOptions;
+    use zip::ZipWriter;
+
+    fn slack_export(files: &[(&str, &str)]) -> Cursor<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in files {
+            zip.start_file(*name, SimpleFileOptions::default()).unwrap();
+            zip.write_all(contents.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap()
+    }
+
+    #[test]
+    fn test_read_slack_export() {
+        let export = slack_export(&[
+            ("users.json", r#"[{"id": "U1", "name": "alice", "real_name": "Alice Liddell"}, {"id": "U2", "name": "bob"}]"#),
+            ("channels.json", r#"[{"id": "C1", "name": "general", "members": ["U1", "U2"]}]"#),
+            ("dms.json", r#"[{"id": "D1", "members": ["U1", "U2"]}]"#),
+            ("general/", ""),
+            ("general/2024-03-02.json", r#"[{"type": "message", "user": "U2", "text": "Thanks <@U1>!", "ts": "1709380800.000200"}]"#),
+            (
+                "general/2024-03-01.json",
+                r#"[{"type": "message", "user": "U1", "text": "Hello", "ts": "1709294400.5"},
+                    {"type": "message", "subtype": "bot_message", "username": "deploybot", "text": "Deployed", "ts": "1709294460.000100"}]"#,
+            ),
+            ("D1/2024-03-01.json", r#"[{"type": "message", "user": "U3", "text": "psst", "ts": "1709294400.000000"}]"#),
+        ]);
+        let archive = read_slack_export(export).unwrap();
+
+        let channels: Vec<(&str, &str)> =
+            archive.channels.iter().map(|channel| (channel.id.as_str(), channel.name.as_str())).collect();
+        assert_eq!(channels, [("C1", "general"), ("D1", "Alice Liddell, bob")]);
+        assert!(archive.channels[1].direct);
+        let messages: Vec<(&str, u64, &str, &str)> = archive
+            .messages
+            .iter()
+            .map(|message| (message.channel_id.as_str(), message.id, message.author.as_str(), message.content.as_str()))
+            .collect();
+        assert_eq!(
+            messages,
+            [
+                ("C1", 1709294400500000, "Alice Liddell", "Hello"),
+                ("C1", 1709294460000100, "deploybot", "Deployed"),
+                ("C1", 1709380800000200, "bob", "Thanks @Alice Liddell!"),
+                ("D1", 1709294400000000, "U3", "psst"),
+            ]
+        );
+        assert_eq!(archive.messages[0].timestamp, Some(1709294400));
+        assert_eq!(archive.history("C1", Some(1709380800000200), 1)[0].content, "Deployed");
+    }
+
+    #[test]
+    fn test_read_mbox() {
+        let mbox = "From alice@example.org Fri Mar  1 12:00:00 2024\n\
+                    From: \"Alice Liddell\" <alice@example.org>\n\
+                    Subject: Release\n \tnotes\n\
+                    Date: Fri, 1 Mar 2024 13:00:00 +0100\n\
+                    \n\
+                    Here they are.\n\
+                    >From the team.\n\
+                    \n\
+                    From bob@example.org Sat Mar  2 08:00:00 2024\n\
+                    From: bob@example.org\n\
+                    \n\
+                    No subject.\n";
+        let channel = Channel { id: "inbox".to_string(), name: "inbox".to_string(), direct: false };
+        let archive = read_mbox(channel, mbox.as_bytes()).unwrap();
+
+        assert_eq!(archive.messages.len(), 2);
+        let first = &archive.messages[0];
+        assert_eq!((first.id, first.author.as_str()), (1, "Alice Liddell"));
+        assert_eq!(first.content, "Release notes\n\nHere they are.\nFrom the team.");
+        assert_eq!(first.timestamp, Some(1709294400));
+        let second = &archive.messages[1];
+        assert_eq!((second.id, second.author.as_str(), second.content.as_str()), (2, "bob@example.org", "No subject."));
+        assert_eq!(second.timestamp, None);
+    }
+
+    #[test]
+    fn test_parse_mail_date() {
+        assert_eq!(parse_mail_date("Fri, 1 Mar 2024 12:00:00 +0000"), Some(1709294400));
+        assert_eq!(parse_mail_date("1 Mar 2024 07:00 -0500 (EST)"), Some(1709294400));
+        assert_eq!(parse_mail_date("Fri, 01 Mar 24 12:00:00 GMT"), Some(1709294400));
+        assert_eq!(parse_mail_date("yesterday"), None);
+        assert_eq!(parse_mail_date("Fri, 1 Mar 2024 12:00:00 +0é0"), Some(1709294400), "A malformed zone is ignored");
+        assert_eq!(parse_mail_date("1 Mar 99999999999999999999 12:00:00 +0000"), None);
+    }
+
+    #[test]
+    fn test_slack_timestamps_out_of_range_are_skipped() {
+        let message = |ts: &str| SlackMessage { ts: ts.to_string(), user: None, username: None, text: String::new() };
+        assert_eq!(slack_message("c1", message("1709294400.000100"), &HashMap::new()).unwrap().id, 1709294400000100);
+        assert!(slack_message("c1", message("18446744073709551.000000"), &HashMap::new()).is_none());
+        assert_eq!(slack_message("c1", message("9223372036854.775807"), &HashMap::new()).unwrap().id, i64::MAX as u64);
+        assert!(slack_message("c1", message("9223372036854.775808"), &HashMap::new()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_archive_channels_are_read_when_streaming_starts() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("saved.mbox");
+        std::fs::write(&path, "From a@example.com Sat Mar  2 12:00:00 2024\nSubject: Hi\n\nHello\n").unwrap();
+        let backend = ArchiveBackend::new(path.clone());
+        let listed = backend.get_messages().next().await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        for event in [listed, backend.list_channels()] {
+            match event {
+                BackendEvent::ChannelList { channels } => assert_eq!(channels[0].name, "saved"),
+                other => panic!("Unexpected event {:?}", other),
+            }
+        }
+    }
+}
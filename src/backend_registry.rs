@@ -0,0 +1,77 @@
+use serde::de::DeserializeOwned;
+
+use crate::archive::ArchiveConfig;
+use crate::chat_backend::{Capabilities, ChatBackend};
+use crate::config_loader::deserialize_backend_options;
+use crate::dummy_backend::DummyConfig;
+
+/// The options a backend kind reads from its service tables, besides the
+/// `backend` key and the credential keys shared by every service. The
+/// struct should use `#[serde(deny_unknown_fields)]` so typos are reported.
+pub trait BackendConfig: DeserializeOwned {
+    /// The value of the `backend` key selecting this kind, e.g. `"dummy"`.
+    const KIND: &'static str;
+
+    /// Creates a backend instance. Logging in happens later, when the
+    /// service is started.
+    fn instantiate(self) -> Box<dyn ChatBackend + Send + Sync>;
+
+    /// The features every backend of this kind supports, reported for
+    /// services that are configured but not running.
+    fn capabilities() -> Capabilities {
+        Capabilities::default()
+    }
+}
+
+/// A registered backend kind, with its config type erased.
+pub struct BackendKind {
+    pub name: &'static str,
+    /// Checks the options in service table `name` of a config file `source`.
+    validate: fn(&str, &str) -> Result<(), toml::de::Error>,
+    /// Creates a backend from options that have passed validation.
+    create: fn(toml::Table) -> Result<Box<dyn ChatBackend + Send + Sync>, toml::de::Error>,
+    capabilities: fn() -> Capabilities,
+}
+
+impl BackendKind {
+    pub const fn of<C: BackendConfig>() -> Self {
+        BackendKind {
+            name: C::KIND,
+            validate: validate_options::<C>,
+            create: create_backend::<C>,
+            capabilities: C::capabilities,
+        }
+    }
+
+    pub fn validate(&self, source: &str, table: &str) -> Result<(), toml::de::Error> {
+        (self.validate)(source, table)
+    }
+
+    pub fn create(&self, options: toml::Table) -> Result<Box<dyn ChatBackend + Send + Sync>, toml::de::Error> {
+        (self.create)(options)
+    }
+
+    pub fn capabilities(&self) -> Capabilities {
+        (self.capabilities)()
+    }
+}
+
+fn validate_options<C: BackendConfig>(source: &str, table: &str) -> Result<(), toml::de::Error> {
+    deserialize_backend_options::<C>(source, table).map(|_| ())
+}
+
+fn create_backend<C: BackendConfig>(options: toml::Table) -> Result<Box<dyn ChatBackend + Send + Sync>, toml::de::Error> {
+    toml::Value::Table(options).try_into::<C>().map(C::instantiate)
+}
+
+/// Every backend kind this build can instantiate. Adding a backend only
+/// takes an entry here.
+pub const BACKENDS: &[BackendKind] = &[
+    BackendKind::of::<DummyConfig>(),
+    BackendKind::of::<ArchiveConfig>(),
+];
+
+/// Looks up the backend kind named by a service's `backend` key.
+pub fn find_backend(name: &str) -> Option<&'static BackendKind> {
+    BACKENDS.iter().find(|kind| kind.name == name)
+}
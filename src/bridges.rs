@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+use crate::chat_backend::{BackendEvent, Channel, ConfiguredService};
+use crate::command_processor::CommandContext;
+use crate::event_bus::Subscription;
+use crate::outbox::random_id;
+use crate::protocol::{FrontendEvent, ServiceEvent};
+use crate::store::RelayEcho;
+
+/// A channel of a bridge, written `service/channel` with the channel's id or name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct BridgeEndpoint {
+    service: String,
+    channel: String,
+}
+
+impl TryFrom<String> for BridgeEndpoint {
+    type Error = String;
+
+    fn try_from(endpoint: String) -> Result<Self, Self::Error> {
+        match endpoint.split_once('/') {
+            Some((service, channel)) if !service.is_empty() && !channel.is_empty() => {
+                Ok(BridgeEndpoint { service: service.to_string(), channel: channel.to_string() })
+            }
+            _ => Err(format!("invalid bridge channel '{}', expected `service/channel`", endpoint)),
+        }
+    }
+}
+
+impl BridgeEndpoint {
+    /// Whether a channel of `service` is this endpoint.
+    fn matches(&self, service: &str, channel_id: &str, channels: &HashMap<(String, String), Channel>) -> bool {
+        self.service == service
+            && (self.channel == channel_id
+                || channels
+                    .get(&(service.to_string(), channel_id.to_string()))
+                    .is_some_and(|channel| channel.name == self.channel))
+    }
+
+    /// The id of the endpoint's channel, looked up by name if it is not an
+    /// id its service listed.
+    fn channel_id(&self, channels: &HashMap<(String, String), Channel>) -> String {
+        if channels.contains_key(&(self.service.clone(), self.channel.clone())) {
+            return self.channel.clone();
+        }
+        channels
+            .iter()
+            .find(|((service, _), channel)| *service == self.service && channel.name == self.channel)
+            .map_or_else(|| self.channel.clone(), |(_, channel)| channel.id.clone())
+    }
+}
+
+/// A `[[daemon.bridges]]` entry: channels that mirror each other's messages.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BridgeConfig {
+    #[serde(deserialize_with = "deserialize_endpoints")]
+    channels: Vec<BridgeEndpoint>,
+    /// How relayed messages read, with `{service}`, `{channel}`, `{author}`
+    /// and `{body}` filled in from the original.
+    #[serde(default = "default_format")]
+    format: String,
+}
+
+fn deserialize_endpoints<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<BridgeEndpoint>, D::Error> {
+    let endpoints = Vec::<BridgeEndpoint>::deserialize(deserializer)?;
+    if endpoints.len() < 2 {
+        return Err(D::Error::custom("a bridge needs at least two channels"));
+    }
+    Ok(endpoints)
+}
+
+fn default_format() -> String {
+    "<{author}> {body}".to_string()
+}
+
+/// A copy of a message relayed by a bridge. `message_id` is known once
+/// the copy came back from its service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelayedCopy {
+    pub service: String,
+    pub channel_id: String,
+    pub message_id: Option<u64>,
+    pub body: String,
+}
+
+/// Fills the `{service}`, `{channel}`, `{author}` and `{body}` placeholders
+/// of `format`. Other braces are kept as they are.
+fn attribute(format: &str, service: &str, channel: &str, author: &str, body: &str) -> String {
+    let placeholders = [("{service}", service), ("{channel}", channel), ("{author}", author), ("{body}", body)];
+    let mut relayed = String::new();
+    let mut rest = format;
+    while let Some(start) = rest.find('{') {
+        relayed.push_str(&rest[..start]);
+        rest = &rest[start..];
+        match placeholders.iter().find(|(placeholder, _)| rest.starts_with(placeholder)) {
+            Some((placeholder, value)) => {
+                relayed.push_str(value);
+                rest = &rest[placeholder.len()..];
+            }
+            None => {
+                relayed.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    relayed.push_str(rest);
+    relayed
+}
+
+/// The other channels of every bridge that a channel of `service` is part of.
+fn targets<'a>(
+    bridges: &'a [BridgeConfig],
+    channels: &HashMap<(String, String), Channel>,
+    service: &str,
+    channel_id: &str,
+) -> Vec<(&'a BridgeConfig, &'a BridgeEndpoint)> {
+    bridges
+        .iter()
+        .filter(|bridge| bridge.channels.iter().any(|endpoint| endpoint.matches(service, channel_id, channels)))
+        .flat_map(|bridge| {
+            bridge
+                .channels
+                .iter()
+                .filter(|endpoint| !endpoint.matches(service, channel_id, channels))
+                .map(move |endpoint| (bridge, endpoint))
+        })
+        .collect()
+}
+
+/// Relays the messages of bridged channels to the other channels of their
+/// bridges through the outbox, and their edits and deletions to the
+/// services that support them. Copies coming back from their services are
+/// recognized from the store and never relayed again.
+pub async fn run_bridges(bridges: Vec<BridgeConfig>, context: CommandContext, mut subscription: Subscription) {
+    let store = &context.store;
+    let mut channels: HashMap<(String, String), Channel> = HashMap::new();
+    loop {
+        let (service, event) = match subscription.next_event().await {
+            FrontendEvent::Service(ServiceEvent { service, event }) => (service, event),
+            _ => continue,
+        };
+        match event {
+            BackendEvent::ChannelList { channels: listed } => {
+                for channel in listed {
+                    channels.insert((service.clone(), channel.id.clone()), channel);
+                }
+            }
+            BackendEvent::Message { channel_id, message_id, body, author, idempotency_key, .. } => {
+                let targets = targets(&bridges, &channels, &service, &channel_id);
+                if targets.is_empty() {
+                    continue;
+                }
+                let echoes_keys = match context.backends.lock().await.get(&service) {
+                    Some(configured) => configured.backend.echoes_idempotency_keys(),
+                    None => false,
+                };
+                let (claimed_service, claimed_channel_id, claimed_body) = (service.clone(), channel_id.clone(), body.clone());
+                let claimed = store.blocking(move |store| {
+                    let echo = match echoes_keys {
+                        true => RelayEcho::Key(idempotency_key.as_deref()),
+                        false => RelayEcho::Body(&claimed_body),
+                    };
+                    store.claim_relay(&claimed_service, &claimed_channel_id, message_id, echo)
+                });
+                match claimed.await {
+                    Ok(false) => {}
+                    Ok(true) => continue,
+                    Err(e) => {
+                        eprintln!("Failed to look up relayed messages: {}", e);
+                        continue;
+                    }
+                }
+                let channel_name = channels.get(&(service.clone(), channel_id.clone())).map_or(&channel_id, |channel| &channel.name);
+                for (bridge, target) in targets {
+                    let copy = RelayedCopy {
+                        service: target.service.clone(),
+                        channel_id: target.channel_id(&channels),
+                        message_id: None,
+                        body: attribute(&bridge.format, &service, channel_name, &author, &body),
+                    };
+                    // Recorded before posting, so that the copy is recognized however fast it comes back.
+                    let key = random_id();
+                    let (origin_service, origin_channel_id, relayed_author) = (service.clone(), channel_id.clone(), author.clone());
+                    let (relayed, relayed_key) = (copy.clone(), key.clone());
+                    let added = store.blocking(move |store| {
+                        store.add_relay(&origin_service, &origin_channel_id, message_id, &relayed_author, &relayed, &relayed_key)
+                    });
+                    if let Err(e) = added.await {
+                        eprintln!("Failed to relay a message of {} {}: {}", service, channel_id, e);
+                        continue;
+                    }
+                    let outbox = context.outbox.clone();
+                    tokio::spawn(async move {
+                        outbox.post_with_key(&copy.service, &copy.channel_id, &copy.body, None, key).await
+                    });
+                }
+            }
+            BackendEvent::MessageEdited { ref channel_id, message_id, .. }
+            | BackendEvent::MessageDeleted { ref channel_id, message_id } => {
+                let targets = targets(&bridges, &channels, &service, channel_id);
+                if targets.is_empty() {
+                    continue;
+                }
+                let (origin_service, origin_channel_id) = (service.clone(), channel_id.clone());
+                let copies = store.blocking(move |store| match store.is_relayed_copy(&origin_service, &origin_channel_id, message_id) {
+                    Ok(false) => store.relayed_copies(&origin_service, &origin_channel_id, message_id),
+                    _ => Ok(Vec::new()),
+                });
+                let copies = match copies.await {
+                    Ok(copies) => copies,
+                    Err(e) => {
+                        eprintln!("Failed to look up relayed messages: {}", e);
+                        continue;
+                    }
+                };
+                let channel_name = channels.get(&(service.clone(), channel_id.clone())).map_or(channel_id, |channel| &channel.name);
+                for (author, copy) in copies {
+                    let Some(bridge) = targets
+                        .iter()
+                        .find(|(_, target)| target.service == copy.service && target.channel_id(&channels) == copy.channel_id)
+                        .map(|(bridge, _)| bridge)
+                    else {
+                        continue;
+                    };
+                    let capabilities = |configured: &ConfiguredService| match &event {
+                        BackendEvent::MessageEdited { .. } => configured.capabilities.edits,
+                        _ => configured.capabilities.deletes,
+                    };
+                    let backend = match context.backends.lock().await.get(&copy.service) {
+                        Some(configured) if capabilities(configured) => configured.backend.clone(),
+                        _ => continue,
+                    };
+                    let body = match &event {
+                        BackendEvent::MessageEdited { body, .. } => {
+                            Some(attribute(&bridge.format, &service, channel_name, &author, body))
+                        }
+                        _ => None,
+                    };
+                    tokio::spawn(async move {
+                        let copy_id = copy.message_id.expect("relayed copies that came back have an id");
+                        let result = match body {
+                            Some(body) => backend.edit_message(&copy.channel_id, copy_id, &body).await,
+                            None => backend.delete_message(&copy.channel_id, copy_id).await,
+                        };
+                        if let Err(e) = result {
+                            eprintln!("Failed to relay a change to {} {}: {}", copy.service, copy.channel_id, e);
+                        }
+                    });
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use futures::Stream;
+    use tokio::sync::{mpsc, Mutex};
+
+    use crate::chat_backend::{Capabilities, ChatBackend, ConfiguredService, Credentials, LoginError, PostError};
+    use crate::event_bus::EventBus;
+    use crate::service_manager::ServiceManager;
+
+    /// A backend that reports every post, edit and deletion it is asked for,
+    /// and keeps the idempotency keys of its posts.
+    struct RecordingBackend {
+        calls: mpsc::UnboundedSender<String>,
+        keys: Arc<std::sync::Mutex<Vec<String>>>,
+        echoes_keys: bool,
+    }
+
+    #[async_trait]
+    impl ChatBackend for RecordingBackend {
+        async fn login(&self, _credentials: &Credentials) -> Result<String, LoginError> {
+            Ok("session".to_string())
+        }
+
+        fn list_channels(&self) -> BackendEvent {
+            BackendEvent::ChannelList { channels: vec![] }
+        }
+
+        fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
+            Box::pin(futures::stream::empty())
+        }
+
+        async fn post_message(&self, channel_id: &str, content: &str, idempotency_key: &str) -> Result<(), PostError> {
+            self.keys.lock().unwrap().push(idempotency_key.to_string());
+            self.calls.send(format!("post {} {}", channel_id, content)).unwrap();
+            Ok(())
+        }
+
+        async fn edit_message(&self, channel_id: &str, message_id: u64, body: &str) -> Result<(), PostError> {
+            self.calls.send(format!("edit {} {} {}", channel_id, message_id, body)).unwrap();
+            Ok(())
+        }
+
+        async fn delete_message(&self, channel_id: &str, message_id: u64) -> Result<(), PostError> {
+            self.calls.send(format!("delete {} {}", channel_id, message_id)).unwrap();
+            Ok(())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities { edits: true, deletes: true, ..Capabilities::default() }
+        }
+
+        fn echoes_idempotency_keys(&self) -> bool {
+            self.echoes_keys
+        }
+    }
+
+    #[test]
+    fn test_attribute() {
+        assert_eq!(attribute("[{service}] <{author}> {body}", "irc", "#ops", "alice", "{author}"), "[irc] <alice> {author}");
+        assert_eq!(attribute("{channel}: {nick} {", "irc", "#ops", "alice", "hi"), "#ops: {nick} {");
+    }
+
+    #[tokio::test]
+    async fn test_relayed_copies_are_not_echoed() {
+        let (irc_calls, mut irc) = mpsc::unbounded_channel();
+        let (matrix_calls, mut matrix) = mpsc::unbounded_channel();
+        let keys = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let irc_backend = RecordingBackend { calls: irc_calls, keys: keys.clone(), echoes_keys: false };
+        let matrix_backend = RecordingBackend { calls: matrix_calls, keys: keys.clone(), echoes_keys: true };
+        let backends = HashMap::from([
+            ("irc".to_string(), ConfiguredService::new("irc", Box::new(irc_backend))),
+            ("matrix".to_string(), ConfiguredService::new("matrix", Box::new(matrix_backend))),
+        ]);
+        let context = CommandContext::new(ServiceManager::new(Arc::new(Mutex::new(backends)), EventBus::new()));
+        #[derive(Deserialize)]
+        struct Bridges {
+            bridges: Vec<BridgeConfig>,
+        }
+        let config: Bridges = toml::from_str("[[bridges]]\nchannels = [\"irc/#ops\", \"matrix/ops\"]").unwrap();
+        let subscription = context.events.subscribe_lossless("bridges");
+        tokio::spawn(run_bridges(config.bridges, context.clone(), subscription));
+
+        let publish = |service: &str, event| context.events.publish(ServiceEvent::new(service, event));
+        let message = |channel_id: &str, message_id, author: &str, body: &str| BackendEvent::Message {
+            channel_id: channel_id.to_string(),
+            message_id,
+            body: body.to_string(),
+            author: author.to_string(),
+            attachments: Vec::new(),
+            idempotency_key: None,
+        };
+        async fn next(calls: &mut mpsc::UnboundedReceiver<String>) -> String {
+            tokio::time::timeout(Duration::from_secs(5), calls.recv()).await.unwrap().unwrap()
+        }
+        let room = Channel { id: "!r1".to_string(), name: "ops".to_string(), direct: false };
+        publish("matrix", BackendEvent::ChannelList { channels: vec![room] });
+
+        publish("irc", message("#ops", 1, "alice", "hi"));
+        assert_eq!(next(&mut matrix).await, "post !r1 <alice> hi");
+        // Someone on Matrix writes the same text before the copy comes back.
+        publish("matrix", message("!r1", 49, "carol", "<alice> hi"));
+        assert_eq!(next(&mut irc).await, "post #ops <carol> <alice> hi");
+        // The copy comes back from Matrix with its key, and must not be relayed to IRC.
+        let mut echo = message("!r1", 50, "bridge", "<alice> hi");
+        if let BackendEvent::Message { idempotency_key, .. } = &mut echo {
+            *idempotency_key = keys.lock().unwrap().first().cloned();
+        }
+        publish("matrix", echo);
+        publish("matrix", message("!r1", 51, "bob", "hello"));
+        assert_eq!(next(&mut irc).await, "post #ops <bob> hello");
+        // Backends that do not report keys have their copies recognized by body.
+        publish("irc", message("#ops", 2, "me", "<carol> <alice> hi"));
+        publish("irc", message("#ops", 3, "me", "<bob> hello"));
+
+        publish("irc", BackendEvent::MessageEdited { channel_id: "#ops".to_string(), message_id: 1, body: "hi all".to_string() });
+        assert_eq!(next(&mut matrix).await, "edit !r1 50 <alice> hi all");
+        publish("matrix", BackendEvent::MessageEdited { channel_id: "!r1".to_string(), message_id: 50, body: "<alice> hi all".to_string() });
+        publish("irc", BackendEvent::MessageDeleted { channel_id: "#ops".to_string(), message_id: 1 });
+        assert_eq!(next(&mut matrix).await, "delete !r1 50");
+        assert!(tokio::time::timeout(Duration::from_millis(200), irc.recv()).await.is_err(), "Nothing was echoed");
+    }
+}
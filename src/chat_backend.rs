@@ -1,12 +1,21 @@
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 use futures::Stream;
+use std::collections::HashMap;
 use std::pin::Pin;
+use std::sync::Arc;
 use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::notifications::NotificationLevel;
+use crate::secrets::{CredentialSource, Secret};
 
 #[derive(Debug)]
+#[allow(dead_code)] // No backend talks to a real server yet.
 pub enum LoginError {
     InvalidCredentials,
     ConnectionError(String),
+    SecretUnavailable(String),
     // Add other variants as needed
 }
 
@@ -18,6 +27,7 @@ impl fmt::Display for LoginError {
         match self {
             LoginError::InvalidCredentials => write!(f, "Invalid credentials provided"),
             LoginError::ConnectionError(msg) => write!(f, "Connection error: {}", msg),
+            LoginError::SecretUnavailable(msg) => write!(f, "Secret unavailable: {}", msg),
         }
     }
 }
@@ -26,7 +36,10 @@ impl fmt::Display for LoginError {
 impl std::error::Error for LoginError {}
 
 #[derive(Debug)]
+#[allow(dead_code)] // The dummy backend never fails to post.
 pub enum PostError {
+    /// The backend cannot perform this kind of change.
+    Unsupported,
     ChannelNotFound,
     PermissionDenied,
     ConnectionError(String),
@@ -36,6 +49,7 @@ pub enum PostError {
 impl fmt::Display for PostError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            PostError::Unsupported => write!(f, "Not supported by this backend"),
             PostError::ChannelNotFound => write!(f, "Channel not found"),
             PostError::PermissionDenied => write!(f, "Permission denied"),
             PostError::ConnectionError(msg) => write!(f, "Connection error: {}", msg),
@@ -45,48 +59,220 @@ impl fmt::Display for PostError {
 
 impl std::error::Error for PostError {}
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug)]
+#[allow(dead_code)] // No backend talks to a real server yet.
+pub enum HistoryError {
+    /// The backend cannot fetch past messages.
+    Unsupported,
+    ChannelNotFound,
+    ConnectionError(String),
+}
+
+impl fmt::Display for HistoryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::Unsupported => write!(f, "History is not supported by this backend"),
+            HistoryError::ChannelNotFound => write!(f, "Channel not found"),
+            HistoryError::ConnectionError(msg) => write!(f, "Connection error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 pub struct Channel {
     pub id: String,
     pub name: String,
+    /// A conversation with one or a few people, rather than a room.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub direct: bool,
     // You can add other fields such as description, members, etc.
 }
 
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A file attached to a message, downloaded by the backend.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+pub struct Attachment {
+    /// The file name shown to users.
+    pub name: String,
+    /// Where the backend saved the file.
+    pub path: String,
+}
+
+/// A past message, as returned by `ChatBackend::fetch_history` and in
+/// `history` replies.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct Message {
     pub id: u64,
     pub channel_id: String,
     pub author: String,
     pub content: String,
-    // Optionally, add other fields like a timestamp.
-}
-
-pub fn display_message(message: &Message) -> String {
-    format!(
-        "{{\"message_id\": {}, \"channel_id\": \"{}\", \"author\": \"{}\", \"body\": \"{}\"}}",
-        message.id, message.channel_id, message.author, message.content
-    )
+    /// When the message was sent, in seconds since the Unix epoch, if the
+    /// backend knows. The store falls back to when it recorded the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
 }
 
 /// Events that the backend sends to frontends, serialized as JSON.
 /// The `#[serde(tag = "event")]` attribute means that each variant
 /// will include an `"event"` field in the JSON output.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, JsonSchema)]
 #[serde(tag = "event")]
+#[allow(dead_code)] // No backend reports edits, deletions or reactions yet.
 pub enum BackendEvent {
     #[serde(rename = "channel_list")]
     ChannelList { channels: Vec<Channel> },
     #[serde(rename = "message")]
-    Message { channel_id: String, message_id: u64, body: String, author: String },
+    Message {
+        channel_id: String,
+        message_id: u64,
+        body: String,
+        author: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<Attachment>,
+        /// The key given to `post_message`, when this is a message posted
+        /// through the daemon coming back from the service. Backends whose
+        /// protocol echoes it set it, so that bridges recognize their copies.
+        #[serde(skip)]
+        idempotency_key: Option<String>,
+    },
+    #[serde(rename = "message_edited")]
+    MessageEdited { channel_id: String, message_id: u64, body: String },
+    #[serde(rename = "message_deleted")]
+    MessageDeleted { channel_id: String, message_id: u64 },
+    #[serde(rename = "reaction_added")]
+    ReactionAdded { channel_id: String, message_id: u64, author: String, reaction: String },
+    #[serde(rename = "reaction_removed")]
+    ReactionRemoved { channel_id: String, message_id: u64, author: String, reaction: String },
+    /// A message matched a notification rule, named by `reason`. Published
+    /// by the daemon, after the message itself.
+    #[serde(rename = "notification")]
+    Notification {
+        channel_id: String,
+        message_id: u64,
+        author: String,
+        body: String,
+        level: NotificationLevel,
+        reason: String,
+    },
+}
+
+/// The optional features a backend supports. Frontends receive these in the
+/// `hello` handshake and can hide actions a service cannot perform.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct Capabilities {
+    pub edits: bool,
+    pub deletes: bool,
+    pub reactions: bool,
+    pub threads: bool,
+    pub attachments: bool,
+    pub search: bool,
+    pub presence: bool,
+}
+
+/// Resolved credentials, handed to `ChatBackend::login`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Credentials {
+    Password { username: String, password: Secret },
+    Token { username: Option<String>, token: Secret },
 }
 
+/// A chat service connection. Methods take `&self` and may be called
+/// concurrently, e.g. posting while the event stream is running, so
+/// implementations keep any mutable state behind their own fine-grained locks.
 #[async_trait]
 pub trait ChatBackend {
-    async fn login(&self, username: &str, password: &str) -> Result<String, LoginError>;
+    async fn login(&self, credentials: &Credentials) -> Result<String, LoginError>;
     fn list_channels(&self) -> BackendEvent;
     fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>>;
-    async fn post_message(&self, channel_id: &str, content: &str) -> Result<(), PostError>;
+    /// Posts a message. `idempotency_key` stays the same when the outbox
+    /// retries the message, so backends whose protocol supports it should pass
+    /// it on and let the server ignore a second copy of a post whose reply
+    /// was lost.
+    async fn post_message(&self, channel_id: &str, content: &str, idempotency_key: &str) -> Result<(), PostError>;
+    /// Replaces the body of a message, for backends with the `edits` capability.
+    async fn edit_message(&self, _channel_id: &str, _message_id: u64, _body: &str) -> Result<(), PostError> {
+        Err(PostError::Unsupported)
+    }
+    /// Deletes a message, for backends with the `deletes` capability.
+    async fn delete_message(&self, _channel_id: &str, _message_id: u64) -> Result<(), PostError> {
+        Err(PostError::Unsupported)
+    }
+    /// Fetches up to `limit` messages of a channel older than message
+    /// `before`, or the latest ones, oldest first. Fewer than `limit` means
+    /// the start of the channel was reached.
+    async fn fetch_history(
+        &self,
+        _channel_id: &str,
+        _before: Option<u64>,
+        _limit: usize,
+    ) -> Result<Vec<Message>, HistoryError> {
+        Err(HistoryError::Unsupported)
+    }
+    /// Tells the server that a channel was read up to message `message_id`.
+    /// Backends without server-side read state need not implement it.
+    async fn mark_read(&self, _channel_id: &str, _message_id: u64) -> Result<(), PostError> {
+        Ok(())
+    }
+    /// Declares which optional features this backend supports.
+    fn capabilities(&self) -> Capabilities;
+    /// Whether messages posted through the daemon come back from the
+    /// service with their `idempotency_key` set.
+    fn echoes_idempotency_keys(&self) -> bool {
+        false
+    }
+    /// Ends the session when the service is stopped or the daemon shuts
+    /// down. Backends without a server-side session need not implement it.
+    async fn logout(&self) -> Result<(), LoginError> {
+        Ok(())
+    }
 }
 
+/// A backend instance shared between the event stream and the command processor.
+pub type SharedBackend = Arc<dyn ChatBackend + Send + Sync>;
+
+/// A backend instance together with what is known about it from its configuration.
+#[derive(Clone)]
+pub struct ConfiguredService {
+    pub backend_kind: String,
+    pub capabilities: Capabilities,
+    /// Where to get the credentials from, for services that need to log in.
+    pub credentials: Option<CredentialSource>,
+    pub backend: SharedBackend,
+}
+
+impl ConfiguredService {
+    pub fn new(backend_kind: impl Into<String>, backend: Box<dyn ChatBackend + Send + Sync>) -> Self {
+        Self {
+            backend_kind: backend_kind.into(),
+            capabilities: backend.capabilities(),
+            credentials: None,
+            backend: Arc::from(backend),
+        }
+    }
+
+    pub fn with_credentials(mut self, credentials: Option<CredentialSource>) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Logs the backend in, resolving the secret from its source first so
+    /// that every login sees the current password or token. Services
+    /// without credentials have nothing to log into.
+    pub async fn login(&self) -> Result<Option<String>, LoginError> {
+        let Some(source) = &self.credentials else {
+            return Ok(None);
+        };
+        let credentials = source
+            .resolve()
+            .await
+            .map_err(|e| LoginError::SecretUnavailable(e.to_string()))?;
+        self.backend.login(&credentials).await.map(Some)
+    }
+}
 
+/// All configured backends, keyed by service name.
+pub type BackendMap = Arc<Mutex<HashMap<String, ConfiguredService>>>;
@@ -0,0 +1,161 @@
+/// What the daemon was asked to do on the command line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CliCommand {
+    /// Run the daemon with the given configuration file.
+    Run {
+        config_path: String,
+        /// Overrides `socket_path` from the `[daemon]` table.
+        socket_path: Option<String>,
+    },
+    /// Validate the configuration file and exit.
+    CheckConfig { config_path: String },
+    /// Print the JSON Schema for `commands` or `events`.
+    PrintSchema(String),
+    /// Export the stored history of a service, or of one of its channels.
+    Export {
+        config_path: String,
+        /// `ndjson`, `markdown`, `html` or `mbox`.
+        format: String,
+        service: String,
+        channel_id: Option<String>,
+        output: String,
+    },
+    /// Import a Slack export or mbox file into the store as a service.
+    Import { config_path: String, archive: String, service: String },
+}
+
+/// Returns the usage text for the program called `program`.
+pub fn usage(program: &str) -> String {
+    format!(
+        "Usage: {0} [--socket <path>] <config_file_path>\n       {0} --check-config <config_file_path>\n       {0} --print-schema <commands|events>\n       {0} --export <ndjson|markdown|html|mbox> <service>[/<channel>] <output> <config_file_path>\n       {0} --import <slack_export.zip|file.mbox> <service> <config_file_path>",
+        program
+    )
+}
+
+/// Parses the command-line arguments, without the program name.
+pub fn parse_args(args: &[String]) -> Result<CliCommand, String> {
+    let mut config_path = None;
+    let mut socket_path = None;
+    let mut check_only = false;
+    let mut export = None;
+    let mut import = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--print-schema" => {
+                let which = args.next().ok_or("--print-schema needs 'commands' or 'events'")?;
+                return Ok(CliCommand::PrintSchema(which.clone()));
+            }
+            "--check-config" => check_only = true,
+            "--export" => {
+                let mut export_args = args.by_ref().take(3);
+                match (export_args.next(), export_args.next(), export_args.next()) {
+                    (Some(format), Some(what), Some(output)) => export = Some((format.clone(), what.clone(), output.clone())),
+                    _ => return Err("--export needs a format, a service or service/channel, and an output path".to_string()),
+                }
+            }
+            "--import" => match (args.next(), args.next()) {
+                (Some(archive), Some(service)) => import = Some((archive.clone(), service.clone())),
+                _ => return Err("--import needs an archive and a service name".to_string()),
+            },
+            "--socket" => {
+                let path = args.next().ok_or("--socket needs a path")?;
+                socket_path = Some(path.clone());
+            }
+            flag if flag.starts_with("--") => return Err(format!("Unknown option '{}'", flag)),
+            path if config_path.is_none() => config_path = Some(path.to_string()),
+            extra => return Err(format!("Unexpected argument '{}'", extra)),
+        }
+    }
+    let config_path = config_path.ok_or("Missing configuration file path")?;
+    if let Some((format, what, output)) = export {
+        let (service, channel_id) = match what.split_once('/') {
+            Some((service, channel_id)) => (service.to_string(), Some(channel_id.to_string())),
+            None => (what, None),
+        };
+        return Ok(CliCommand::Export { config_path, format, service, channel_id, output });
+    }
+    if let Some((archive, service)) = import {
+        return Ok(CliCommand::Import { config_path, archive, service });
+    }
+    if check_only {
+        return Ok(CliCommand::CheckConfig { config_path });
+    }
+    Ok(CliCommand::Run { config_path, socket_path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_run() {
+        assert_eq!(
+            parse_args(&args(&["config.toml"])),
+            Ok(CliCommand::Run { config_path: "config.toml".to_string(), socket_path: None })
+        );
+        assert_eq!(
+            parse_args(&args(&["--socket", "/run/chat.sock", "config.toml"])),
+            Ok(CliCommand::Run {
+                config_path: "config.toml".to_string(),
+                socket_path: Some("/run/chat.sock".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_check_config() {
+        assert_eq!(
+            parse_args(&args(&["--check-config", "config.toml"])),
+            Ok(CliCommand::CheckConfig { config_path: "config.toml".to_string() })
+        );
+    }
+
+    #[test]
+    fn test_parse_print_schema() {
+        assert_eq!(
+            parse_args(&args(&["--print-schema", "events"])),
+            Ok(CliCommand::PrintSchema("events".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_export() {
+        assert_eq!(
+            parse_args(&args(&["--export", "html", "work/general", "general.html", "config.toml"])),
+            Ok(CliCommand::Export {
+                config_path: "config.toml".to_string(),
+                format: "html".to_string(),
+                service: "work".to_string(),
+                channel_id: Some("general".to_string()),
+                output: "general.html".to_string(),
+            })
+        );
+        assert!(parse_args(&args(&["--export", "html", "work"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_import() {
+        assert_eq!(
+            parse_args(&args(&["--import", "old-team.zip", "old_team", "config.toml"])),
+            Ok(CliCommand::Import {
+                config_path: "config.toml".to_string(),
+                archive: "old-team.zip".to_string(),
+                service: "old_team".to_string(),
+            })
+        );
+        assert!(parse_args(&args(&["--import", "old-team.zip"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse_args(&args(&[])).is_err());
+        assert!(parse_args(&args(&["--socket"])).is_err());
+        assert!(parse_args(&args(&["--bogus", "config.toml"])).is_err());
+        assert!(parse_args(&args(&["a.toml", "b.toml"])).is_err());
+    }
+}
@@ -1,16 +1,36 @@
-use std::collections::HashMap;
+use std::env;
 use std::fs;
-use std::path::Path;
+use std::io;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use serde_json::Value;
-use tokio::io::AsyncReadExt;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{UnixListener, UnixStream};
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
 
-use crate::chat_backend::{ChatBackend, BackendEvent};
+use crate::archive::{archive_service_options, check_import_target, Archive};
+use crate::chat_backend::{BackendMap, SharedBackend};
+use crate::event_bus::EventBus;
+use crate::config_loader::DaemonConfig;
+use crate::export::{export, export_path};
+use crate::outbox::Outbox;
+use crate::retention::prune;
+use crate::search::SearchQuery;
+use crate::store::{Store, StoreError};
+use crate::protocol::{parse_command, service_infos, DaemonEvent, FrontendCommand, FrontendEvent, PROTOCOL_VERSION};
+use crate::service_manager::{ServiceError, ServiceManager};
 
 /// A guard that removes the Unix socket file when dropped.
+#[derive(Debug)]
 pub struct UnixSocketGuard {
     pub path: String,
 }
@@ -25,91 +45,571 @@ impl Drop for UnixSocketGuard {
     fn drop(&mut self) {
         if Path::new(&self.path).exists() {
             match fs::remove_file(&self.path) {
-                Ok(_) => println!("Socket file {} removed.", self.path),
+                Ok(_) => eprintln!("Socket file {} removed.", self.path),
                 Err(e) => eprintln!("Failed to remove socket file {}: {}", self.path, e),
             }
         }
     }
 }
 
-/// Processes a single command received over the Unix socket.
-/// Expects the command JSON to contain a "service" field to determine which backend to use.
-pub async fn process_command(
-    mut socket: UnixStream,
-    backends: Arc<Mutex<HashMap<String, Arc<Mutex<Box<dyn ChatBackend + Send + Sync>>>>>>,
-) {
-    let mut buf = vec![0; 1024];
-    if let Ok(n) = socket.read(&mut buf).await {
-        if n > 0 {
-            let cmd_str = String::from_utf8_lossy(&buf[..n]);
-            if let Ok(json_val) = serde_json::from_str::<Value>(&cmd_str) {
-                if let Some(cmd) = json_val.get("command").and_then(|c| c.as_str()) {
-                    // Expect a "service" field to know which backend to use.
-                    let service = json_val
-                        .get("service")
-                        .and_then(|s| s.as_str())
-                        .unwrap_or("");
-                    let backends_guard = backends.lock().await;
-                    if let Some(backend_instance) = backends_guard.get(service) {
-                        match cmd {
-                            "post_message" => {
-                                let channel_id = json_val
-                                    .get("channel_id")
-                                    .and_then(|v| v.as_str())
-                                    .unwrap_or("");
-                                let body = json_val
-                                    .get("body")
-                                    .and_then(|v| v.as_str())
-                                    .unwrap_or("");
-                                if let Err(e) = backend_instance
-                                    .lock()
-                                    .await
-                                    .post_message(channel_id, body)
-                                    .await
-                                {
-                                    eprintln!("Failed to post message: {:?}", e);
+/// The daemon state that frontend sessions operate on.
+#[derive(Clone)]
+pub struct CommandContext {
+    pub backends: BackendMap,
+    pub events: EventBus,
+    pub services: Arc<Mutex<ServiceManager>>,
+    /// Cancelled when the daemon shuts down, to stop accepting connections
+    /// and commands.
+    pub shutdown: CancellationToken,
+    /// Every running frontend session, so shutdown can wait for them.
+    pub sessions: TaskTracker,
+    pub outbox: Arc<Outbox>,
+    pub store: Arc<Store>,
+    /// Where `export` commands write their files.
+    pub export_dir: PathBuf,
+}
+
+impl CommandContext {
+    /// Creates a context whose outbox and message store are kept in memory only.
+    pub fn new(services: ServiceManager) -> Self {
+        Self {
+            backends: services.backends(),
+            events: services.events(),
+            outbox: Arc::new(Outbox::new(services.backends(), services.events())),
+            store: Arc::new(Store::in_memory().expect("an in-memory database can always be opened")),
+            services: Arc::new(Mutex::new(services)),
+            shutdown: CancellationToken::new(),
+            sessions: TaskTracker::new(),
+            export_dir: DaemonConfig::default().export_dir(),
+        }
+    }
+
+    pub fn with_outbox(mut self, outbox: Arc<Outbox>) -> Self {
+        self.outbox = outbox;
+        self
+    }
+
+    pub fn with_store(mut self, store: Arc<Store>) -> Self {
+        self.store = store;
+        self
+    }
+
+    pub fn with_export_dir(mut self, export_dir: PathBuf) -> Self {
+        self.export_dir = export_dir;
+        self
+    }
+}
+
+/// The longest command a frontend may send, in bytes. Longer ones end the
+/// session, so that no peer can make the daemon buffer without limit.
+pub const MAX_COMMAND_LENGTH: usize = 1 << 20;
+
+/// How long a remote frontend has to authenticate after connecting.
+pub const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Splits a byte stream into newline-delimited text frames, so that Unix,
+/// TCP and TLS connections can all be served by `serve_session`.
+pub fn line_transport<S>(
+    stream: S,
+) -> (
+    impl Stream<Item = io::Result<String>> + Unpin,
+    impl Sink<String, Error = io::Error> + Unpin,
+)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (reader, writer) = tokio::io::split(stream);
+    let input = FramedRead::new(reader, LinesCodec::new_with_max_length(MAX_COMMAND_LENGTH)).map(|line| line.map_err(io::Error::other));
+    let output = SinkExt::<String>::sink_map_err(FramedWrite::new(writer, LinesCodec::new()), io::Error::other);
+    (input, output)
+}
+
+/// Serves one frontend connection on the Unix socket.
+pub async fn process_command(socket: UnixStream, context: CommandContext) {
+    let label = match socket.peer_cred().ok().and_then(|credentials| credentials.pid()) {
+        Some(pid) => format!("unix pid {}", pid),
+        None => "unix".to_string(),
+    };
+    let (input, output) = line_transport(socket);
+    serve_session(input, output, context, None, &label).await;
+}
+
+/// Serves one frontend session over any transport that carries one JSON
+/// object per frame.
+///
+/// When `token` is set, the first command must be an `auth` command carrying
+/// that token, sent within `AUTH_TIMEOUT`. A `hello` event describing the protocol version and the
+/// capabilities of every service is then sent, followed by every event the
+/// daemon publishes. Each incoming frame is parsed into a `FrontendCommand`;
+/// parse and execution failures are reported back as a `command_error` event.
+/// `label` names the session in `subscriber_stats`.
+pub async fn serve_session<I, O>(mut input: I, mut output: O, context: CommandContext, token: Option<&str>, label: &str)
+where
+    I: Stream<Item = io::Result<String>> + Unpin,
+    O: Sink<String, Error = io::Error> + Unpin,
+{
+    if let Some(expected) = token {
+        let authenticated = tokio::select! {
+            result = authenticate(&mut input, expected) => result,
+            _ = context.shutdown.cancelled() => return,
+        };
+        if let Err(event) = authenticated {
+            let _ = send_event(&mut output, event).await;
+            return;
+        }
+    }
+
+    let mut events = context.events.subscribe(label);
+    let hello = DaemonEvent::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        services: service_infos(&*context.backends.lock().await),
+    };
+    if let Err(e) = send_event(&mut output, hello).await {
+        eprintln!("Failed to send hello: {}", e);
+        return;
+    }
+
+    // Commands addressed to a backend run concurrently, so that a slow
+    // service does not hold up commands to the others.
+    let mut in_flight: FuturesUnordered<BoxFuture<'static, CommandResult>> = FuturesUnordered::new();
+    let mut input_closed = false;
+    loop {
+        if input_closed && in_flight.is_empty() {
+            if context.shutdown.is_cancelled() {
+                // Deliver what was published before shutting down, ending with `shutting_down`.
+                while let Some(event) = events.try_recv() {
+                    if send_event(&mut output, event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            return;
+        }
+        tokio::select! {
+            _ = context.shutdown.cancelled(), if !input_closed => {
+                // Stop taking commands, but finish the ones already running.
+                input_closed = true;
+            }
+            line = input.next(), if !input_closed => {
+                let line = match line {
+                    Some(Ok(line)) => line,
+                    Some(Err(e)) => {
+                        eprintln!("Failed to read command: {}", e);
+                        return;
+                    }
+                    None => {
+                        // Still report the outcome of commands that are running.
+                        input_closed = true;
+                        continue;
+                    }
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let result = match parse_command(&line) {
+                    Ok(command) if command.service_io() => {
+                        let context = context.clone();
+                        in_flight.push(Box::pin(async move { execute_command(command, &context).await }));
+                        continue;
+                    }
+                    Ok(command) => execute_command(command, &context).await,
+                    Err(e) => Err(e.into()),
+                };
+                if let Err(e) = send_result(&mut output, result).await {
+                    eprintln!("Failed to answer command: {}", e);
+                    return;
+                }
+            }
+            Some(result) = in_flight.next(), if !in_flight.is_empty() => {
+                if let Err(e) = send_result(&mut output, result).await {
+                    eprintln!("Failed to answer command: {}", e);
+                    return;
+                }
+            }
+            event = events.recv() => match event {
+                Ok(event) => {
+                    if send_event(&mut output, event).await.is_err() {
+                        return;
+                    }
+                }
+                Err(overflow) => {
+                    eprintln!("Disconnecting frontend {}: {}", label, overflow);
+                    let _ = send_event(&mut output, DaemonEvent::QueueOverflow { reason: overflow.reason }).await;
+                    return;
+                }
+            },
+        }
+    }
+}
+
+/// Waits for the `auth` command that remote sessions must start with.
+async fn authenticate<I>(input: &mut I, expected: &str) -> Result<(), DaemonEvent>
+where
+    I: Stream<Item = io::Result<String>> + Unpin,
+{
+    let rejected = |reason: &str| DaemonEvent::command_error(reason, Some("token".to_string()));
+    let line = match tokio::time::timeout(AUTH_TIMEOUT, input.next()).await {
+        Ok(Some(Ok(line))) => line,
+        Ok(Some(Err(e))) => return Err(rejected(&format!("Failed to read 'auth': {}", e))),
+        Ok(None) => return Err(rejected("Connection closed before authenticating")),
+        Err(_) => return Err(rejected("Timed out waiting for 'auth'")),
+    };
+    match parse_command(&line) {
+        Ok(FrontendCommand::Auth { token }) if tokens_match(&token, expected) => Ok(()),
+        Ok(FrontendCommand::Auth { .. }) => Err(rejected("Invalid token")),
+        Ok(_) => Err(rejected("The first command must be 'auth'")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Compares two tokens without exiting early on the first differing byte.
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given.bytes().zip(expected.bytes()).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Writes a single event to a frontend as one JSON frame.
+async fn send_event<O>(output: &mut O, event: impl Into<FrontendEvent>) -> io::Result<()>
+where
+    O: Sink<String, Error = io::Error> + Unpin,
+{
+    output.send(serde_json::to_string(&event.into()).unwrap()).await
+}
+
+/// The reply meant for the sending frontend only, if the command has one,
+/// or the error to report.
+pub type CommandResult = Result<Option<DaemonEvent>, DaemonEvent>;
+
+/// Sends a command's reply or error back to the frontend that sent it.
+async fn send_result<O>(output: &mut O, result: CommandResult) -> io::Result<()>
+where
+    O: Sink<String, Error = io::Error> + Unpin,
+{
+    match result {
+        Ok(None) => Ok(()),
+        Ok(Some(reply)) => send_event(output, reply).await,
+        Err(event) => {
+            eprintln!("{}", serde_json::to_string(&FrontendEvent::from(event.clone())).unwrap());
+            send_event(output, event).await
+        }
+    }
+}
+
+/// Runs a store call on the blocking thread pool, so that a slow SQLite
+/// query does not hold up the runtime's worker threads.
+async fn on_store<T: Send + 'static>(
+    store: &Arc<Store>,
+    call: impl FnOnce(&Store) -> Result<T, StoreError> + Send + 'static,
+) -> Result<T, DaemonEvent> {
+    store.blocking(call).await.map_err(|e| DaemonEvent::command_error(e.to_string(), None))
+}
+
+/// Executes a parsed command.
+pub async fn execute_command(command: FrontendCommand, context: &CommandContext) -> CommandResult {
+    let backends = &context.backends;
+    match command {
+        FrontendCommand::PostMessage { service, channel_id, body, local_id } => {
+            backend_for(backends, &service).await?;
+            context.outbox.post(&service, &channel_id, &body, local_id).await;
+            Ok(None)
+        }
+        FrontendCommand::ScheduleMessage { service, channel_id, body, send_at } => {
+            backend_for(backends, &service).await?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+            let send_at = send_at.resolve(now).map_err(|e| DaemonEvent::command_error(e, Some("send_at".to_string())))?;
+            let message =
+                on_store(&context.store, move |store| store.schedule_message(&service, &channel_id, &body, send_at)).await?;
+            context.events.publish(DaemonEvent::MessageScheduled { message });
+            Ok(None)
+        }
+        FrontendCommand::ListScheduledMessages => {
+            let messages = on_store(&context.store, |store| store.scheduled_messages()).await?;
+            Ok(Some(DaemonEvent::ScheduledMessages { messages }))
+        }
+        FrontendCommand::CancelScheduledMessage { id } => {
+            let cancelled = on_store(&context.store, move |store| store.cancel_scheduled(id)).await?;
+            if !cancelled {
+                let reason = format!("No message is scheduled with id {}", id);
+                return Err(DaemonEvent::command_error(reason, Some("id".to_string())));
+            }
+            context.events.publish(DaemonEvent::ScheduledMessageCancelled { id });
+            Ok(None)
+        }
+        FrontendCommand::LeaveChannel { service, channel_id } => {
+            backend_for(backends, &service).await?;
+            eprintln!("Service {} leaving channel {}", service, channel_id);
+            // Add additional handling here if needed.
+            Ok(None)
+        }
+        FrontendCommand::FetchHistory { service, channel_id, before, limit } => {
+            let backend = backend_for(backends, &service).await?;
+            let history = context
+                .store
+                .fetch_history(&service, &backend, &channel_id, before, limit)
+                .await
+                .map_err(|e| DaemonEvent::command_error(e.to_string(), None))?;
+            Ok(Some(DaemonEvent::History { service, channel_id, messages: history.messages, complete: history.complete }))
+        }
+        FrontendCommand::SearchLocal { query, limit, highlight_start, highlight_end } => {
+            let parsed = SearchQuery::parse(&query)
+                .map_err(|e| DaemonEvent::command_error(e.to_string(), Some("query".to_string())))?;
+            let hits =
+                on_store(&context.store, move |store| store.search(&parsed, limit, (&highlight_start, &highlight_end)))
+                    .await?;
+            Ok(Some(DaemonEvent::SearchResults { query, hits }))
+        }
+        FrontendCommand::Export { service, channel_id, format, path } => {
+            let output = export_path(&context.export_dir, &path)
+                .map_err(|e| DaemonEvent::command_error(e, Some("path".to_string())))?;
+            let backend = backends.lock().await.get(&service).map(|configured| configured.backend.clone());
+            if let Some(backend) = backend {
+                let store = &context.store;
+                let channel_ids = match &channel_id {
+                    Some(channel_id) => vec![channel_id.clone()],
+                    None => {
+                        let (listed_service, listed) = (service.clone(), backend.list_channels());
+                        store
+                            .blocking(move |store| {
+                                if let Err(e) = store.record(&listed_service, &listed) {
+                                    eprintln!("Failed to store the channels of {}: {}", listed_service, e);
                                 }
-                            }
-                            "leave_channel" => {
-                                let channel_id = json_val
-                                    .get("channel_id")
-                                    .and_then(|v| v.as_str());
-                                eprintln!(
-                                    "Service {} leaving channel {:?}",
-                                    service, channel_id
-                                );
-                                // Add additional handling here if needed.
-                            }
-                            _ => {
-                                println!("Unknown command: {}", cmd);
-                            }
-                        }
-                    } else {
-                        eprintln!("Service '{}' not found", service);
+                                store.channels(&listed_service).unwrap_or_default()
+                            })
+                            .await
+                            .into_iter()
+                            .map(|channel| channel.id)
+                            .collect()
+                    }
+                };
+                for channel_id in channel_ids {
+                    if let Err(e) = store.backfill(&service, &backend, &channel_id).await {
+                        eprintln!("Exporting the stored history of {} {} only: {}", service, channel_id, e);
                     }
                 }
             }
+            let store = context.store.clone();
+            let (exported_service, exported_path) = (service.clone(), output.clone());
+            let exported = tokio::task::spawn_blocking(move || {
+                fs::create_dir_all(exported_path.parent().expect("export paths are within the export directory"))?;
+                export(&store, &exported_service, channel_id.as_deref(), format, &exported_path)
+            })
+            .await
+            .expect("exports do not panic")
+            .map_err(|e| DaemonEvent::command_error(e.to_string(), None))?;
+            Ok(Some(DaemonEvent::ExportFinished {
+                service,
+                path: output.to_string_lossy().into_owned(),
+                messages: exported.messages,
+                attachments: exported.attachments,
+            }))
+        }
+        FrontendCommand::ImportArchive { service, path, persist } => {
+            let path = tokio::fs::canonicalize(&path).await.map_err(|e| {
+                DaemonEvent::command_error(format!("Cannot read {}: {}", path, e), Some("path".to_string()))
+            })?;
+            let exists = check_import_target(&service, context.services.lock().await.config(&service), &path)
+                .map_err(|reason| DaemonEvent::command_error(reason, Some("service".to_string())))?;
+            let store = context.store.clone();
+            let (imported_service, imported_path) = (service.clone(), path.clone());
+            let (channels, messages) = tokio::task::spawn_blocking(move || {
+                let archive = Archive::open(&imported_path)
+                    .map_err(|e| format!("Cannot read {}: {}", imported_path.display(), e))?;
+                store.import(&imported_service, &archive).map_err(|e| e.to_string())?;
+                Ok::<_, String>((archive.channels.len(), archive.messages.len()))
+            })
+            .await
+            .expect("imports do not panic")
+            .map_err(|reason| DaemonEvent::command_error(reason, Some("path".to_string())))?;
+            if !exists {
+                ServiceManager::add(&context.services, &service, archive_service_options(&path), persist)
+                    .await
+                    .map_err(service_error)?;
+            }
+            Ok(Some(DaemonEvent::ArchiveImported { service, channels, messages }))
+        }
+        FrontendCommand::StarMessage { service, channel_id, message_id, starred } => {
+            let (starred_service, starred_channel_id) = (service.clone(), channel_id.clone());
+            let found = on_store(&context.store, move |store| {
+                store.set_starred(&starred_service, &starred_channel_id, message_id, starred)
+            })
+            .await?;
+            if !found {
+                let reason = format!("Message {} of {} {} is not stored", message_id, service, channel_id);
+                return Err(DaemonEvent::command_error(reason, Some("message_id".to_string())));
+            }
+            Ok(None)
+        }
+        FrontendCommand::FetchInbox { before, limit, unread_only } => {
+            let (items, unread) = on_store(&context.store, move |store| store.inbox(before, limit, unread_only)).await?;
+            Ok(Some(DaemonEvent::Inbox { items, unread }))
+        }
+        FrontendCommand::MarkRead { service, channel_id, message_id } => {
+            let (read_service, read_channel_id) = (service.clone(), channel_id.clone());
+            on_store(&context.store, move |store| store.mark_read(&read_service, &read_channel_id, message_id)).await?;
+            context.events.publish(DaemonEvent::InboxRead {
+                service: service.clone(),
+                channel_id: channel_id.clone(),
+                message_id,
+            });
+            // Items of stopped services are only marked as read locally.
+            let backend = backends.lock().await.get(&service).map(|configured| configured.backend.clone());
+            if let Some(backend) = backend {
+                backend.mark_read(&channel_id, message_id).await.map_err(|e| {
+                    let reason = format!("Marked as read locally only: {}", e);
+                    DaemonEvent::command_error(reason, None)
+                })?;
+            }
+            Ok(None)
+        }
+        FrontendCommand::PruneStorage { dry_run } => {
+            let retention = context.services.lock().await.retention();
+            let store = context.store.clone();
+            let channels = tokio::task::spawn_blocking(move || prune(&store, &retention, dry_run))
+                .await
+                .expect("pruning does not panic")
+                .map_err(|e| DaemonEvent::command_error(e.to_string(), None))?;
+            let pruned = DaemonEvent::StoragePruned { dry_run, channels };
+            if dry_run {
+                return Ok(Some(pruned));
+            }
+            context.events.publish(pruned);
+            Ok(None)
+        }
+        // Only meaningful as the first command of a remote session.
+        FrontendCommand::Auth { .. } => Ok(None),
+        FrontendCommand::AddService { service, config, persist } => {
+            let options = match toml::Value::try_from(config) {
+                Ok(toml::Value::Table(options)) => options,
+                Ok(_) => unreachable!("a JSON object converts to a table"),
+                Err(e) => {
+                    let reason = format!("Service config cannot be written as TOML: {}", e);
+                    return Err(DaemonEvent::command_error(reason, Some("config".to_string())));
+                }
+            };
+            ServiceManager::add(&context.services, &service, options, persist).await.map(|_| None).map_err(service_error)
         }
+        FrontendCommand::RemoveService { service, persist } => {
+            ServiceManager::remove(&context.services, &service, persist).await.map(|_| None).map_err(service_error)
+        }
+        FrontendCommand::EnableService { service, persist } => {
+            ServiceManager::set_enabled(&context.services, &service, true, persist).await.map(|_| None).map_err(service_error)
+        }
+        FrontendCommand::DisableService { service, persist } => {
+            ServiceManager::set_enabled(&context.services, &service, false, persist).await.map(|_| None).map_err(service_error)
+        }
+        FrontendCommand::ListServices => {
+            Ok(Some(DaemonEvent::ServiceList { services: context.services.lock().await.list() }))
+        }
+        FrontendCommand::SubscriberStats => {
+            Ok(Some(DaemonEvent::SubscriberStats { subscribers: context.events.stats() }))
+        }
+    }
+}
+
+/// Reports a failed service lifecycle command, naming the field at fault.
+fn service_error(error: ServiceError) -> DaemonEvent {
+    let field = match error {
+        ServiceError::UnknownService(_) | ServiceError::AlreadyExists(_) => Some("service"),
+        ServiceError::InvalidConfig(_) => Some("config"),
+        ServiceError::NoConfigFile => Some("persist"),
+        ServiceError::WriteBack(_) => None,
+    };
+    DaemonEvent::command_error(error.to_string(), field.map(str::to_string))
+}
+
+/// Looks up the backend of a service, reporting unknown services to the frontend.
+async fn backend_for(backends: &BackendMap, service: &str) -> Result<SharedBackend, DaemonEvent> {
+    backends
+        .lock()
+        .await
+        .get(service)
+        .map(|configured| configured.backend.clone())
+        .ok_or_else(|| {
+            DaemonEvent::command_error(format!("Service '{}' not found", service), Some("service".to_string()))
+        })
+}
+
+/// The socket path used when neither the config file nor the command line set one:
+/// `$XDG_RUNTIME_DIR/kbunified/commands.sock`, or a per-user directory in `/tmp`
+/// when no runtime directory is available.
+pub fn default_socket_path() -> PathBuf {
+    let runtime_dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("kbunified"),
+        _ => PathBuf::from(format!("/tmp/kbunified-{}", current_uid())),
+    };
+    runtime_dir.join("commands.sock")
+}
+
+fn current_uid() -> u32 {
+    // SAFETY: geteuid has no preconditions and cannot fail.
+    unsafe { libc::geteuid() }
+}
+
+/// Binds the command socket at `socket_path`, readable and writable by the
+/// current user only. Its directory is restricted to the current user too,
+/// unless someone else owns it, like `/tmp`. A leftover socket from a
+/// crashed instance is replaced, but binding is refused while another
+/// instance is still listening on it.
+pub fn bind_command_socket(socket_path: &Path) -> io::Result<(UnixListener, UnixSocketGuard)> {
+    let parent = socket_path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    if !parent.exists() {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(parent)?;
+    }
+    let metadata = fs::metadata(parent)?;
+    if metadata.uid() == current_uid() && metadata.mode() & 0o077 != 0 {
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
     }
+    if socket_path.exists() {
+        if std::os::unix::net::UnixStream::connect(socket_path).is_ok() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("another instance is already listening on {}", socket_path.display()),
+            ));
+        }
+        fs::remove_file(socket_path)?;
+    }
+    // The socket is bound in a directory only the current user can enter and
+    // linked into place once restricted, so that nobody else can ever connect
+    // to it. Linking fails rather than replace a socket bound in the meantime.
+    let staging = tempfile::Builder::new().prefix(".kbunified-").tempdir_in(parent)?;
+    let staged_path = staging.path().join("commands.sock");
+    let listener = UnixListener::bind(&staged_path)?;
+    fs::set_permissions(&staged_path, fs::Permissions::from_mode(0o600))?;
+    fs::hard_link(&staged_path, socket_path)?;
+    let socket_guard = UnixSocketGuard::new(socket_path.to_string_lossy());
+    Ok((listener, socket_guard))
 }
 
-/// Creates a Unix socket at `socket_path`, and enters a loop accepting connections
-/// and processing commands using `process_command`.
-pub async fn run_command_socket(
-    socket_path: &str,
-    backends: Arc<Mutex<HashMap<String, Arc<Mutex<Box<dyn ChatBackend + Send + Sync>>>>>>,
-) {
-    if Path::new(socket_path).exists() {
-        fs::remove_file(socket_path).expect("Failed to remove existing socket file");
+/// Only processes owned by the same user may send commands.
+fn peer_is_trusted(socket: &UnixStream) -> bool {
+    match socket.peer_cred() {
+        Ok(credentials) if credentials.uid() == current_uid() => true,
+        Ok(credentials) => {
+            eprintln!("Rejected command connection from uid {}", credentials.uid());
+            false
+        }
+        Err(e) => {
+            eprintln!("Failed to read peer credentials: {}", e);
+            false
+        }
     }
-    let _socket_guard = UnixSocketGuard::new(socket_path);
-    let listener = UnixListener::bind(socket_path).expect("Failed to bind to Unix socket");
+}
 
+/// Enters a loop accepting connections on a socket created by
+/// `bind_command_socket` and processing commands using `process_command`,
+/// until the daemon shuts down.
+pub async fn run_command_socket(listener: UnixListener, context: CommandContext) -> io::Result<()> {
     loop {
-        let (socket, _) = listener.accept().await.expect("Failed to accept connection");
-        let backends_clone = backends.clone();
-        tokio::spawn(async move {
-            process_command(socket, backends_clone).await;
+        let (socket, _) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = context.shutdown.cancelled() => return Ok(()),
+        };
+        if !peer_is_trusted(&socket) {
+            continue;
+        }
+        let context = context.clone();
+        context.sessions.clone().spawn(async move {
+            process_command(socket, context).await;
         });
     }
 }
@@ -118,17 +618,16 @@ pub async fn run_command_socket(
 mod tests {
     use super::*;
     use std::collections::HashMap;
-    use async_trait::async_trait;
     use std::sync::Arc;
     use tokio::net::UnixStream;
-    use tokio::io::AsyncWriteExt;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
     use tokio::sync::Mutex;
     use serde_json::json;
     use futures::stream::Stream;
     use std::pin::Pin;
 
     // Assume that ChatBackend, BackendEvent, LoginError, and PostError are defined in your crate.
-    use crate::chat_backend::{ChatBackend, BackendEvent};
+    use crate::chat_backend::{ChatBackend, BackendEvent, Capabilities, ConfiguredService};
 
     // Define a simple test backend that records calls to post_message.
     struct TestBackend {
@@ -143,7 +642,7 @@ mod tests {
 
     #[async_trait::async_trait]
     impl ChatBackend for TestBackend {
-        async fn login(&self, _username: &str, _password: &str) -> Result<String, crate::chat_backend::LoginError> {
+        async fn login(&self, _credentials: &crate::chat_backend::Credentials) -> Result<String, crate::chat_backend::LoginError> {
             Ok("dummy_token".to_string())
         }
 
@@ -157,11 +656,22 @@ mod tests {
             Box::pin(futures::stream::empty())
         }
 
-        async fn post_message(&self, channel_id: &str, content: &str) -> Result<(), crate::chat_backend::PostError> {
+        async fn post_message(&self, channel_id: &str, content: &str, _idempotency_key: &str) -> Result<(), crate::chat_backend::PostError> {
             let mut msgs = self.posted_messages.lock().await;
             msgs.push((channel_id.to_string(), content.to_string()));
             Ok(())
         }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities { edits: true, ..Capabilities::default() }
+        }
+    }
+
+    // Reads every event the server wrote, one JSON object per line.
+    async fn read_events(client: &mut UnixStream) -> Vec<serde_json::Value> {
+        let mut output = String::new();
+        client.read_to_string(&mut output).await.unwrap();
+        output.lines().map(|line| serde_json::from_str(line).unwrap()).collect()
     }
 
     // Test for process_command with a valid post_message command.
@@ -171,10 +681,10 @@ mod tests {
         let test_backend = TestBackend::new();
         let posted_messages = test_backend.posted_messages.clone();
 
-        let mut services: HashMap<String, Arc<Mutex<Box<dyn ChatBackend + Send + Sync>>>> = HashMap::new();
+        let mut services: HashMap<String, ConfiguredService> = HashMap::new();
         services.insert(
             "test_service".to_string(),
-            Arc::new(Mutex::new(Box::new(test_backend) as Box<dyn ChatBackend + Send + Sync>))
+            ConfiguredService::new("test", Box::new(test_backend))
         );
         let backends = Arc::new(Mutex::new(services));
 
@@ -195,7 +705,7 @@ mod tests {
         client.shutdown().await.unwrap();
 
         // Process the command on the server side.
-        process_command(server, backends.clone()).await;
+        process_command(server, CommandContext::new(ServiceManager::new(backends, EventBus::new()))).await;
 
         // Check that the test backend recorded the post_message call.
         let msgs = posted_messages.lock().await;
@@ -204,11 +714,70 @@ mod tests {
         assert_eq!(msgs[0].1, "Hello, test!");
     }
 
+    // A backend whose posts only complete once another post is in progress.
+    struct BarrierBackend {
+        barrier: Arc<tokio::sync::Barrier>,
+        posted: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    #[async_trait::async_trait]
+    impl ChatBackend for BarrierBackend {
+        async fn login(&self, _credentials: &crate::chat_backend::Credentials) -> Result<String, crate::chat_backend::LoginError> {
+            Ok("session".to_string())
+        }
+
+        fn list_channels(&self) -> BackendEvent {
+            BackendEvent::ChannelList { channels: vec![] }
+        }
+
+        fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
+            Box::pin(futures::stream::empty())
+        }
+
+        async fn post_message(&self, _channel_id: &str, _content: &str, _idempotency_key: &str) -> Result<(), crate::chat_backend::PostError> {
+            self.barrier.wait().await;
+            self.posted.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+    }
+
+    // Test that posts to different services, and to different channels of the
+    // same service, run in parallel: each pair of posts only completes if both
+    // are in flight at once.
+    #[tokio::test]
+    async fn test_commands_run_concurrently() {
+        let barrier = Arc::new(tokio::sync::Barrier::new(2));
+        let posted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let mut services: HashMap<String, ConfiguredService> = HashMap::new();
+        for name in ["a", "b"] {
+            let backend = BarrierBackend { barrier: barrier.clone(), posted: posted.clone() };
+            services.insert(name.to_string(), ConfiguredService::new("barrier", Box::new(backend)));
+        }
+        let backends = Arc::new(Mutex::new(services));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        for (service, channel_id) in [("a", "c1"), ("b", "c1"), ("a", "c2"), ("a", "c3")] {
+            let command = json!({"command": "post_message", "service": service, "channel_id": channel_id, "body": "hi"});
+            client.write_all(format!("{}\n", command).as_bytes()).await.unwrap();
+        }
+        client.shutdown().await.unwrap();
+
+        let session = process_command(server, CommandContext::new(ServiceManager::new(backends, EventBus::new())));
+        tokio::time::timeout(std::time::Duration::from_secs(5), session)
+            .await
+            .expect("Posts were serialized instead of running in parallel");
+        assert_eq!(posted.load(std::sync::atomic::Ordering::SeqCst), 4);
+    }
+
     // Test for process_command with an unknown service.
     #[tokio::test]
     async fn test_process_command_unknown_service() {
         // Create an empty backend mapping.
-        let services: HashMap<String, Arc<Mutex<Box<dyn ChatBackend + Send + Sync>>>> = HashMap::new();
+        let services: HashMap<String, ConfiguredService> = HashMap::new();
         let backends = Arc::new(Mutex::new(services));
 
         // Create a UnixStream pair.
@@ -225,8 +794,259 @@ mod tests {
         client.write_all(command_str.as_bytes()).await.unwrap();
         client.shutdown().await.unwrap();
 
-        // Call process_command. It should print an error but not panic.
-        process_command(server, backends.clone()).await;
-        // Nothing to assert—just ensure no panic occurs.
+        // Call process_command. It should report the error but not panic.
+        process_command(server, CommandContext::new(ServiceManager::new(backends, EventBus::new()))).await;
+
+        let events = read_events(&mut client).await;
+        assert_eq!(events.len(), 2, "Expected hello followed by an error");
+        assert_eq!(events[1]["event"], "command_error");
+        assert_eq!(events[1]["field"], "service");
+    }
+
+    // Test that a malformed command is reported back with the offending field.
+    #[tokio::test]
+    async fn test_process_command_missing_field() {
+        let test_backend = TestBackend::new();
+        let posted_messages = test_backend.posted_messages.clone();
+        let mut services: HashMap<String, ConfiguredService> = HashMap::new();
+        services.insert(
+            "test_service".to_string(),
+            ConfiguredService::new("test", Box::new(test_backend))
+        );
+        let backends = Arc::new(Mutex::new(services));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let command = json!({
+            "command": "post_message",
+            "service": "test_service",
+            "body": "Hello, test!"
+        });
+        client.write_all(command.to_string().as_bytes()).await.unwrap();
+        client.shutdown().await.unwrap();
+
+        process_command(server, CommandContext::new(ServiceManager::new(backends, EventBus::new()))).await;
+
+        let events = read_events(&mut client).await;
+        assert_eq!(events[1]["event"], "command_error");
+        assert_eq!(events[1]["field"], "channel_id");
+        assert!(posted_messages.lock().await.is_empty(), "Nothing should have been posted");
+    }
+
+    // Test that every connection starts with a hello describing the services.
+    #[tokio::test]
+    async fn test_process_command_sends_hello() {
+        let mut services: HashMap<String, ConfiguredService> = HashMap::new();
+        services.insert(
+            "test_service".to_string(),
+            ConfiguredService::new("test", Box::new(TestBackend::new()))
+        );
+        let backends = Arc::new(Mutex::new(services));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.shutdown().await.unwrap();
+        process_command(server, CommandContext::new(ServiceManager::new(backends, EventBus::new()))).await;
+
+        let events = read_events(&mut client).await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "hello");
+        assert_eq!(events[0]["protocol_version"], PROTOCOL_VERSION);
+        let service = &events[0]["services"][0];
+        assert_eq!(service["name"], "test_service");
+        assert_eq!(service["backend"], "test");
+        assert_eq!(service["capabilities"]["edits"], true);
+        assert_eq!(service["capabilities"]["threads"], false);
+    }
+
+    // Test that published events are forwarded to connected frontends.
+    #[tokio::test]
+    async fn test_process_command_forwards_events() {
+        let services: HashMap<String, ConfiguredService> = HashMap::new();
+        let events = EventBus::new();
+        let context = CommandContext::new(ServiceManager::new(Arc::new(Mutex::new(services)), events.clone()));
+
+        let (client, server) = UnixStream::pair().unwrap();
+        tokio::spawn(process_command(server, context));
+
+        let mut lines = tokio::io::BufReader::new(client).lines();
+        let hello: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(hello["event"], "hello");
+
+        events.publish(crate::protocol::ServiceEvent::new("test_service", BackendEvent::ChannelList { channels: vec![] }));
+        let event: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(event["service"], "test_service");
+        assert_eq!(event["event"], "channel_list");
+    }
+
+    // Test that services can be added, disabled and listed at runtime, and
+    // that persisted changes keep the rest of the config file intact.
+    #[tokio::test]
+    async fn test_service_lifecycle_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.toml");
+        let config_path = config_path.to_str().unwrap();
+        fs::write(config_path, "# My services\n[existing]\nbackend = \"dummy\"\ninterval_ms = 60000\n").unwrap();
+
+        let events = EventBus::new();
+        let mut manager =
+            ServiceManager::new(Arc::new(Mutex::new(HashMap::new())), events.clone()).with_config_file(config_path);
+        manager.start_all(crate::config_loader::load_config(config_path).unwrap().services).await;
+        let context = CommandContext::new(manager);
+
+        let (client, server) = UnixStream::pair().unwrap();
+        tokio::spawn(process_command(server, context.clone()));
+        let (reader, mut writer) = client.into_split();
+        let mut lines = tokio::io::BufReader::new(reader).lines();
+        // Skips the events the dummy services keep streaming and their status changes.
+        let mut next_daemon_event = async || loop {
+            let event: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+            if !matches!(event["event"].as_str(), Some("channel_list" | "message" | "service_status")) {
+                return event;
+            }
+        };
+        assert_eq!(next_daemon_event().await["event"], "hello");
+
+        let commands = [
+            json!({"command": "add_service", "service": "extra", "persist": true,
+                   "config": {"backend": "dummy", "interval_ms": 60000}}),
+            json!({"command": "disable_service", "service": "existing", "persist": true}),
+            json!({"command": "list_services"}),
+            json!({"command": "add_service", "service": "broken", "config": {"backend": "dumy"}}),
+            json!({"command": "remove_service", "service": "missing"}),
+        ];
+        for command in commands {
+            writer.write_all(format!("{}\n", command).as_bytes()).await.unwrap();
+        }
+
+        // Replies may overtake events broadcast to every frontend, so only
+        // the order within each kind is checked.
+        let mut replies = Vec::new();
+        for _ in 0..5 {
+            replies.push(next_daemon_event().await);
+        }
+        let of_kind = |kind: &str| replies.iter().filter(|event| event["event"] == kind).collect::<Vec<_>>();
+        let added = of_kind("service_added");
+        assert_eq!(added.len(), 1);
+        assert_eq!(added[0]["service"]["name"], "extra");
+        let removed = of_kind("service_removed");
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0]["service"], "existing");
+        let list = of_kind("service_list");
+        assert_eq!(list[0]["services"][0]["name"], "existing");
+        assert_eq!(list[0]["services"][0]["enabled"], false);
+        assert_eq!(list[0]["services"][1]["name"], "extra");
+        assert_eq!(list[0]["services"][1]["enabled"], true);
+        let errors = of_kind("command_error");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0]["field"], "config");
+        assert_eq!(errors[1]["field"], "service");
+
+        let mut running: Vec<String> = context.backends.lock().await.keys().cloned().collect();
+        running.sort();
+        assert_eq!(running, ["extra"]);
+        let written = fs::read_to_string(config_path).unwrap();
+        assert!(written.starts_with("# My services\n[existing]\n"), "{}", written);
+        assert!(written.contains("enabled = false"), "{}", written);
+        assert!(written.contains("[extra]\nbackend = \"dummy\"\ninterval_ms = 60000\n"), "{}", written);
+    }
+
+    #[tokio::test]
+    async fn test_import_archive_only_into_its_own_service() {
+        let dir = tempfile::tempdir().unwrap();
+        let mbox = dir.path().join("saved.mbox");
+        fs::write(&mbox, "From bob@example.org Sat Mar  2 08:00:00 2024\nFrom: bob@example.org\n\nHello.\n").unwrap();
+        let options: toml::Table = toml::from_str("backend = \"dummy\"\ninterval_ms = 60000").unwrap();
+        let services = HashMap::from([("chat".to_string(), crate::config_loader::parse_service("chat", &options).unwrap())]);
+        let mut manager = ServiceManager::new(Arc::new(Mutex::new(HashMap::new())), EventBus::new());
+        manager.start_all(services).await;
+        let context = CommandContext::new(manager);
+        let import = |service: &str| FrontendCommand::ImportArchive {
+            service: service.to_string(),
+            path: mbox.to_string_lossy().into_owned(),
+            persist: false,
+        };
+
+        match execute_command(import("chat"), &context).await {
+            Err(DaemonEvent::CommandError { field, .. }) => assert_eq!(field.as_deref(), Some("service")),
+            other => panic!("Unexpected result {:?}", other),
+        }
+        assert!(context.store.channels("chat").unwrap().is_empty(), "Nothing is imported into another service");
+        for _ in 0..2 {
+            let imported = execute_command(import("saved"), &context).await;
+            assert!(matches!(imported, Ok(Some(DaemonEvent::ArchiveImported { messages: 1, .. }))), "{:?}", imported);
+        }
+    }
+
+    // Test that shutting down stops the accept loop and ends open sessions
+    // once they have delivered the `shutting_down` event.
+    #[tokio::test]
+    async fn test_shutdown_closes_sessions() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("commands.sock");
+        let (listener, _guard) = bind_command_socket(&socket_path).unwrap();
+        let context = CommandContext::new(ServiceManager::new(Arc::new(Mutex::new(HashMap::new())), EventBus::new()));
+        let accept_loop = tokio::spawn(run_command_socket(listener, context.clone()));
+
+        let client = UnixStream::connect(&socket_path).await.unwrap();
+        let mut lines = tokio::io::BufReader::new(client).lines();
+        let hello: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(hello["event"], "hello");
+
+        context.events.publish(DaemonEvent::ShuttingDown);
+        context.shutdown.cancel();
+        context.sessions.close();
+        let deadline = std::time::Duration::from_secs(5);
+        tokio::time::timeout(deadline, accept_loop).await.unwrap().unwrap().unwrap();
+        tokio::time::timeout(deadline, context.sessions.wait()).await.unwrap();
+
+        let last: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(last["event"], "shutting_down");
+        assert!(lines.next_line().await.unwrap().is_none(), "The session should be closed");
+    }
+
+    // Test that frontends can see how far behind each subscriber is.
+    #[tokio::test]
+    async fn test_subscriber_stats() {
+        let events = EventBus::new();
+        let _stdout = events.subscribe("stdout");
+        let context = CommandContext::new(ServiceManager::new(Arc::new(Mutex::new(HashMap::new())), events));
+
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(b"{\"command\": \"subscriber_stats\"}\n").await.unwrap();
+        client.shutdown().await.unwrap();
+        process_command(server, context).await;
+
+        let events = read_events(&mut client).await;
+        assert_eq!(events[1]["event"], "subscriber_stats");
+        let subscribers = events[1]["subscribers"].as_array().unwrap();
+        assert_eq!(subscribers.len(), 2);
+        assert_eq!(subscribers[0]["label"], "stdout");
+        assert_eq!(subscribers[0]["queued"], 0);
+        assert!(subscribers[1]["label"].as_str().unwrap().starts_with("unix"));
+    }
+
+    // Test that the socket is private and that stale sockets are replaced.
+    #[tokio::test]
+    async fn test_bind_command_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("nested").join("commands.sock");
+
+        // A socket file nobody listens on is stale and gets replaced.
+        drop(std::os::unix::net::UnixListener::bind(dir.path().join("unused.sock")).unwrap());
+        fs::DirBuilder::new().mode(0o755).create(dir.path().join("nested")).unwrap();
+        fs::rename(dir.path().join("unused.sock"), &socket_path).unwrap();
+
+        let (_listener, _guard) = bind_command_socket(&socket_path).unwrap();
+        let mode = fs::metadata(&socket_path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let mode = fs::metadata(dir.path().join("nested")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700, "An existing directory is restricted too");
+        assert_eq!(fs::read_dir(dir.path().join("nested")).unwrap().count(), 1, "Nothing is left beside the socket");
+
+        // A live instance must not be replaced.
+        let err = bind_command_socket(&socket_path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert!(socket_path.exists(), "The live socket must not be removed");
     }
 }
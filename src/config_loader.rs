@@ -1,86 +1,627 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
-use std::sync::Arc;
-use tokio::sync::Mutex;
-use serde::Deserialize;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
 
-// Assume these modules exist and provide the relevant types.
-use crate::chat_backend;
-use crate::chat_backend::ChatBackend;
-use crate::dummy_backend;
+use serde::de::value::MapAccessDeserializer;
+use serde::de::{DeserializeOwned, DeserializeSeed, IgnoredAny, IntoDeserializer, MapAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use toml::Spanned;
 
-#[derive(Debug, Deserialize)]
-struct Config {
-    #[serde(flatten)]
-    services: HashMap<String, ServiceConfig>,
+use crate::backend_registry::find_backend;
+use crate::bridges::BridgeConfig;
+use crate::chat_backend::ConfiguredService;
+use crate::event_bus::{OverflowStrategy, DEFAULT_QUEUE_CAPACITY};
+use crate::inbox::InboxConfig;
+use crate::notifications::NotificationConfig;
+use crate::retention::RetentionConfig;
+use crate::secrets::{CredentialSource, Secret, SecretSource};
+
+/// Name of the reserved table holding the daemon's own settings.
+const DAEMON_TABLE: &str = "daemon";
+
+/// Keys every service table may have, whatever its backend. All other keys
+/// belong to the backend's own config type.
+const COMMON_KEYS: &[&str] = &[
+    "backend",
+    "enabled",
+    "username",
+    "password",
+    "password_command",
+    "password_env",
+    "password_file",
+    "token",
+    "token_command",
+    "token_env",
+    "token_file",
+    "retention",
+    "inbox",
+];
+
+/// A single problem found in a configuration file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub file: String,
+    /// 1-based line and column, when the problem can be located in the file.
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "{}:{}:{}: {}", self.file, line, column, self.message),
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Every problem found in a configuration file, so they can be fixed in one go.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, error) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", error)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+/// A configuration file that has been parsed and validated.
+#[derive(Debug)]
+pub struct Config {
+    /// Settings for the daemon itself; every other table is a service.
+    pub daemon: DaemonConfig,
+    pub services: HashMap<String, ServiceConfig>,
+}
+
+/// The reserved `[daemon]` table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Where to create the command socket. Defaults to
+    /// `$XDG_RUNTIME_DIR/kbunified/commands.sock`.
+    #[serde(default)]
+    pub socket_path: Option<String>,
+    /// Optional listener for remote or browser frontends.
+    #[serde(default)]
+    pub remote: Option<RemoteConfig>,
+    /// How events are buffered for each frontend.
+    #[serde(default)]
+    pub events: EventQueueConfig,
+    /// Where the daemon keeps data across restarts, such as unsent messages.
+    /// Defaults to `$XDG_STATE_HOME/kbunified`.
+    #[serde(default)]
+    pub state_dir: Option<String>,
+    /// Where the `export` command writes files. Defaults to `exports` in the
+    /// state directory.
+    #[serde(default)]
+    pub export_dir: Option<String>,
+    /// Rules deciding which messages to highlight, notify or route to the inbox.
+    #[serde(default)]
+    pub notifications: NotificationConfig,
+    /// Channels on different services that mirror each other.
+    #[serde(default)]
+    pub bridges: Vec<BridgeConfig>,
+}
+
+impl DaemonConfig {
+    /// The configured state directory, or `$XDG_STATE_HOME/kbunified`, falling
+    /// back to `~/.local/state/kbunified` when that variable is not set.
+    pub fn state_dir(&self) -> PathBuf {
+        if let Some(dir) = &self.state_dir {
+            return PathBuf::from(dir);
+        }
+        match std::env::var_os("XDG_STATE_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir).join("kbunified"),
+            _ => {
+                let home = std::env::var_os("HOME").unwrap_or_default();
+                PathBuf::from(home).join(".local/state/kbunified")
+            }
+        }
+    }
+
+    /// The configured export directory, or `exports` in the state directory.
+    pub fn export_dir(&self) -> PathBuf {
+        match &self.export_dir {
+            Some(dir) => PathBuf::from(dir),
+            None => self.state_dir().join("exports"),
+        }
+    }
+}
+
+/// The `[daemon.events]` table.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EventQueueConfig {
+    /// How many events may wait for a frontend before `overflow` applies.
+    #[serde(default = "default_queue_capacity")]
+    pub queue_capacity: usize,
+    /// `"drop_oldest"`, `"drop_non_critical"` or `"disconnect"`.
+    #[serde(default)]
+    pub overflow: OverflowStrategy,
+}
+
+fn default_queue_capacity() -> usize {
+    DEFAULT_QUEUE_CAPACITY
+}
+
+impl Default for EventQueueConfig {
+    fn default() -> Self {
+        Self { queue_capacity: DEFAULT_QUEUE_CAPACITY, overflow: OverflowStrategy::default() }
+    }
+}
+
+/// The `[daemon.remote]` table. At least one of `tcp` and `websocket` should
+/// be set; TLS is enabled when both `tls_cert` and `tls_key` are given.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "RemoteTable")]
+pub struct RemoteConfig {
+    /// Address for the plain TCP listener, e.g. `"127.0.0.1:7878"`.
+    pub tcp: Option<String>,
+    /// Address for the WebSocket listener, e.g. `"127.0.0.1:7879"`.
+    pub websocket: Option<String>,
+    /// Where to read the token that remote frontends must send in their
+    /// `auth` command, from `token_command`, `token_env` or `token_file`.
+    pub token: SecretSource,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-struct ServiceConfig {
-    backend: String,
-    // Options for Rocket.Chat; for other backends these can be omitted.
+/// `[daemon.remote]` as written in the file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RemoteTable {
     #[serde(default)]
-    server_url: Option<String>,
+    tcp: Option<String>,
+    #[serde(default)]
+    websocket: Option<String>,
+    #[serde(default)]
+    token: Option<Secret>,
+    #[serde(default)]
+    token_command: Option<String>,
+    #[serde(default)]
+    token_env: Option<String>,
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+    #[serde(default)]
+    tls_cert: Option<String>,
+    #[serde(default)]
+    tls_key: Option<String>,
+}
+
+impl TryFrom<RemoteTable> for RemoteConfig {
+    type Error = String;
+
+    fn try_from(table: RemoteTable) -> Result<Self, Self::Error> {
+        let mut sources = secret_sources("token", &table.token, &table.token_command, &table.token_env, &table.token_file)?;
+        if sources.len() != 1 {
+            return Err("exactly one of `token_command`, `token_env` and `token_file` must be set".to_string());
+        }
+        let token = sources.remove(0);
+        if token.is_empty() {
+            return Err("the remote token source must not be empty".to_string());
+        }
+        Ok(RemoteConfig { tcp: table.tcp, websocket: table.websocket, token, tls_cert: table.tls_cert, tls_key: table.tls_key })
+    }
+}
+
+/// One service table: the keys shared by every backend, plus the backend's
+/// own options, which are checked against its `BackendConfig` type. Two
+/// configs compare equal when every option has the same value, which is how
+/// a reload decides whether to restart a service.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ServiceConfig {
+    backend: Spanned<String>,
+    /// Disabled services stay in the file but are not started.
+    #[serde(default = "enabled_by_default")]
+    enabled: bool,
+    // Credentials: at most one password or token source may be given.
     #[serde(default)]
     username: Option<String>,
     #[serde(default)]
-    password: Option<String>,
+    password: Option<Secret>,
+    #[serde(default)]
+    password_command: Option<String>,
+    #[serde(default)]
+    password_env: Option<String>,
+    #[serde(default)]
+    password_file: Option<PathBuf>,
+    #[serde(default)]
+    token: Option<Secret>,
+    #[serde(default)]
+    token_command: Option<String>,
+    #[serde(default)]
+    token_env: Option<String>,
+    #[serde(default)]
+    token_file: Option<PathBuf>,
+    /// How long the store keeps the service's history.
+    #[serde(default)]
+    retention: RetentionConfig,
+    /// Which of the service's messages go to the inbox.
+    #[serde(default)]
+    inbox: InboxConfig,
+    /// The backend-specific keys of the table.
+    #[serde(skip)]
+    options: toml::Table,
+}
+
+/// Collects the secret sources set among one family of options, e.g.
+/// `password_*`. The secret itself may not be written in the file.
+fn secret_sources(
+    name: &str,
+    literal: &Option<Secret>,
+    command: &Option<String>,
+    env: &Option<String>,
+    file: &Option<PathBuf>,
+) -> Result<Vec<SecretSource>, String> {
+    if literal.is_some() {
+        return Err(format!(
+            "`{0}` must not be written in the configuration file; use `{0}_command`, `{0}_env` or `{0}_file`",
+            name
+        ));
+    }
+    let mut sources = Vec::new();
+    sources.extend(command.clone().map(SecretSource::Command));
+    sources.extend(env.clone().map(SecretSource::Env));
+    sources.extend(file.clone().map(SecretSource::File));
+    Ok(sources)
+}
+
+fn enabled_by_default() -> bool {
+    true
+}
+
+impl ServiceConfig {
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+    }
+
+    /// The `backend` key of the service table.
+    pub fn backend(&self) -> &str {
+        self.backend.get_ref()
+    }
+
+    /// The backend-specific keys of the service table.
+    pub fn options(&self) -> &toml::Table {
+        &self.options
+    }
+
+    pub fn retention(&self) -> &RetentionConfig {
+        &self.retention
+    }
+
+    /// The inbox rules, watching for mentions of `username` unless other
+    /// identities are given.
+    pub fn inbox(&self) -> InboxConfig {
+        self.inbox.clone().with_default_identity(self.username.as_deref())
+    }
+
+    /// Works out where the service's credentials come from.
+    fn credentials(&self) -> Result<Option<CredentialSource>, String> {
+        let mut passwords =
+            secret_sources("password", &self.password, &self.password_command, &self.password_env, &self.password_file)?;
+        let mut tokens = secret_sources("token", &self.token, &self.token_command, &self.token_env, &self.token_file)?;
+        match (passwords.len(), tokens.len()) {
+            (0, 0) => Ok(None),
+            (1, 0) => {
+                let username = self.username.clone().ok_or("a password needs a `username`")?;
+                Ok(Some(CredentialSource::Password { username, source: passwords.remove(0) }))
+            }
+            (0, 1) => Ok(Some(CredentialSource::Token { username: self.username.clone(), source: tokens.remove(0) })),
+            _ => Err("only one of `password_command`, `password_env`, `password_file`, \
+                      `token_command`, `token_env` and `token_file` may be set"
+                .to_string()),
+        }
+    }
+}
+
+/// Deserializes only the top-level table `name` of a TOML document. Going
+/// through the document deserializer, rather than an intermediate
+/// `toml::Value`, keeps the location of any error inside that table.
+struct TableSeed<'a, T> {
+    name: &'a str,
+    marker: PhantomData<T>,
+}
+
+impl<'de, T: Deserialize<'de>> DeserializeSeed<'de> for TableSeed<'_, T> {
+    type Value = Option<T>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Option<T>, D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for TableSeed<'_, T> {
+    type Value = Option<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a table")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Option<T>, A::Error> {
+        let mut found = None;
+        while let Some(key) = map.next_key::<String>()? {
+            if key == self.name {
+                found = Some(map.next_value()?);
+            } else {
+                map.next_value::<IgnoredAny>()?;
+            }
+        }
+        Ok(found)
+    }
 }
 
-/// Reads the configuration file at `config_path` and instantiates all backend
-/// instances defined in the file. Each service is specified as its own table.
-/// For example:
+fn deserialize_table<T: DeserializeOwned>(source: &str, name: &str) -> Result<Option<T>, toml::de::Error> {
+    TableSeed { name, marker: PhantomData }.deserialize(toml::Deserializer::new(source))
+}
+
+/// Deserializes a map while hiding the keys in `COMMON_KEYS`, so a backend
+/// config type can reject unknown keys without listing the shared ones.
+struct BackendOptions<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for BackendOptions<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(BackendOptionsVisitor(PhantomData))
+    }
+}
+
+struct BackendOptionsVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for BackendOptionsVisitor<T> {
+    type Value = BackendOptions<T>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a table")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Self::Value, A::Error> {
+        T::deserialize(MapAccessDeserializer::new(WithoutCommonKeys(map)))?;
+        Ok(BackendOptions(PhantomData))
+    }
+}
+
+struct WithoutCommonKeys<A>(A);
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for WithoutCommonKeys<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, A::Error> {
+        let mut seed = Some(seed);
+        loop {
+            // Filtering inside the inner map's key seed keeps the key's location on errors.
+            match self.0.next_key_seed(SkipCommonKey(&mut seed))? {
+                None => return Ok(None),
+                Some(Some(key)) => return Ok(Some(key)),
+                Some(None) => {
+                    self.0.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, A::Error> {
+        self.0.next_value_seed(seed)
+    }
+}
+
+struct SkipCommonKey<'a, K>(&'a mut Option<K>);
+
+impl<'de, K: DeserializeSeed<'de>> DeserializeSeed<'de> for SkipCommonKey<'_, K> {
+    type Value = Option<K::Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        let key = String::deserialize(deserializer)?;
+        if COMMON_KEYS.contains(&key.as_str()) {
+            return Ok(None);
+        }
+        let seed = self.0.take().expect("a key seed is only used once");
+        seed.deserialize(key.into_deserializer()).map(Some)
+    }
+}
+
+/// Checks the backend-specific options of service table `name` against the
+/// backend's config type `T`, keeping the location of any error.
+pub fn deserialize_backend_options<T: DeserializeOwned>(source: &str, name: &str) -> Result<(), toml::de::Error> {
+    deserialize_table::<BackendOptions<T>>(source, name).map(|_| ())
+}
+
+/// Converts a byte offset into a 1-based line and column.
+fn position_of(source: &str, offset: usize) -> (usize, usize) {
+    let before = &source[..offset.min(source.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.rsplit('\n').next().map_or(0, |text| text.chars().count()) + 1;
+    (line, column)
+}
+
+/// Reads and validates the configuration file at `config_path` without
+/// instantiating anything. Each service is specified as its own table,
+/// except for the reserved `[daemon]` table. For example:
 ///
 /// ```toml
+/// [daemon]
+/// socket_path = "/run/user/1000/kbunified/commands.sock"
+///
+/// [daemon.remote]
+/// websocket = "127.0.0.1:7879"
+/// token_file = "/run/user/1000/kbunified/remote-token"
+///
 /// [some_dummy_service]
 /// backend = "dummy"
-///
-/// [some_rocketchat_service]
-/// backend = "rocketchat"
-/// server_url = "ws://chat.example.com/websocket"
 /// username = "my_username"
-/// password = "my_password"
+/// password_command = "pass show chat/dummy"
+///
+/// [some_archive_service]
+/// backend = "archive"
+/// path = "/home/me/slack-export.zip"
 /// ```
 ///
-/// Returns a mapping from service names to the corresponding backend instances.
-pub async fn load_config_and_instantiate_backend(
-    config_path: &str,
-) -> HashMap<String, Arc<Mutex<Box<dyn ChatBackend + Send + Sync>>>> {
-    let config_str = fs::read_to_string(config_path)
-        .expect("Failed to read configuration file");
-    let config: Config =
-        toml::from_str(&config_str).expect("Failed to parse configuration file");
-
-    let mut backends = HashMap::new();
-    for (service_name, service_config) in config.services.into_iter() {
-        match service_config.backend.as_str() {
-            "dummy" => {
-                // Cast DummyBackend into a trait object.
-                let backend: Box<dyn ChatBackend + Send + Sync> =
-                    Box::new(dummy_backend::DummyBackend::new())
-                        as Box<dyn ChatBackend + Send + Sync>;
-                backends.insert(service_name, Arc::new(Mutex::new(backend)));
+/// Instead of `password_command`, the password may come from `password_env`
+/// (an environment variable) or `password_file` (the first line of a file).
+/// Token based services and `[daemon.remote]` use `token_command`,
+/// `token_env` or `token_file` the same way. Secrets themselves may not be
+/// written in the file.
+///
+/// Returns the daemon settings and service configs, or every problem found
+/// in the file, not just the first.
+pub fn load_config(config_path: &str) -> Result<Config, ConfigErrors> {
+    let source = fs::read_to_string(config_path).map_err(|e| {
+        ConfigErrors(vec![ConfigError {
+            file: config_path.to_string(),
+            position: None,
+            message: format!("Failed to read configuration file: {}", e),
+        }])
+    })?;
+    parse_config(&source, config_path)
+}
+
+/// Validates configuration `source`, reporting problems against `file_name`.
+pub fn parse_config(source: &str, file_name: &str) -> Result<Config, ConfigErrors> {
+    let error_at = |position: Option<(usize, usize)>, message: String| ConfigError {
+        file: file_name.to_string(),
+        position,
+        message,
+    };
+    let toml_error = |context: &str, e: toml::de::Error| {
+        let position = e.span().map(|span| position_of(source, span.start));
+        error_at(position, format!("{}{}", context, e.message()))
+    };
+    let tables: BTreeMap<Spanned<String>, toml::Value> =
+        toml::from_str(source).map_err(|e| ConfigErrors(vec![toml_error("", e)]))?;
+
+    let mut errors = Vec::new();
+    let mut daemon = DaemonConfig::default();
+    let mut services = HashMap::new();
+    for (name, value) in &tables {
+        let name_position = Some(position_of(source, name.span().start));
+        if name.get_ref() == DAEMON_TABLE {
+            match deserialize_table(source, DAEMON_TABLE) {
+                Ok(config) => daemon = config.unwrap_or_default(),
+                Err(e) => errors.push(toml_error("[daemon]: ", e)),
+            }
+            continue;
+        }
+        let Some(table) = value.as_table() else {
+            errors.push(error_at(name_position, format!("Service '{}' must be a table", name.get_ref())));
+            continue;
+        };
+        let mut service: ServiceConfig = match deserialize_table(source, name.get_ref()) {
+            Ok(service) => service.expect("the service table was found above"),
+            Err(e) => {
+                errors.push(toml_error(&format!("Service '{}': ", name.get_ref()), e));
+                continue;
             }
-            // "rocketchat" => {
-            //     let server_url = service_config.server_url.clone()
-            //         .expect("Missing server_url for rocketchat");
-            //     let username = service_config.username.clone()
-            //         .expect("Missing username for rocketchat");
-            //     let password = service_config.password.clone()
-            //         .expect("Missing password for rocketchat");
-            //
-            //     let mut rc_backend = chat_backend::rocket_backend::RocketChatBackend::new(&server_url);
-            //     // Login asynchronously; _token is ignored here.
-            //     let _token = rc_backend.login(&username, &password).await
-            //         .expect("RocketChat login failed");
-            //     let backend: Box<dyn ChatBackend + Send + Sync> =
-            //         Box::new(rc_backend) as Box<dyn ChatBackend + Send + Sync>;
-            //     backends.insert(service_name, Arc::new(Mutex::new(backend)));
-            // }
-            other => panic!("Unsupported backend: {}", other),
-        }
-    }
-    backends
+        };
+        let backend_position = Some(position_of(source, service.backend.span().start));
+        match find_backend(service.backend.get_ref()) {
+            None => errors.push(error_at(
+                backend_position,
+                format!("Service '{}' uses unsupported backend '{}'", name.get_ref(), service.backend.get_ref()),
+            )),
+            Some(kind) => {
+                if let Err(e) = kind.validate(source, name.get_ref()) {
+                    errors.push(toml_error(&format!("Service '{}': ", name.get_ref()), e));
+                }
+            }
+        }
+        if let Err(e) = service.credentials() {
+            errors.push(error_at(name_position, format!("Service '{}': {}", name.get_ref(), e)));
+        }
+        service.options = table
+            .iter()
+            .filter(|(key, _)| !COMMON_KEYS.contains(&key.as_str()))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        services.insert(name.get_ref().clone(), service);
+    }
+
+    if errors.is_empty() {
+        Ok(Config { daemon, services })
+    } else {
+        Err(ConfigErrors(errors))
+    }
+}
+
+/// Validates a single service table given as `options`, e.g. by an
+/// `add_service` command, exactly as if it were read from a file.
+pub fn parse_service(name: &str, options: &toml::Table) -> Result<ServiceConfig, ConfigErrors> {
+    let error = |message: String| ConfigError { file: format!("service '{}'", name), position: None, message };
+    if name == DAEMON_TABLE {
+        let message = format!("'{}' is reserved for the daemon's own settings", DAEMON_TABLE);
+        return Err(ConfigErrors(vec![error(message)]));
+    }
+    let mut document = toml::Table::new();
+    document.insert(name.to_string(), toml::Value::Table(options.clone()));
+    let source = toml::to_string(&document).map_err(|e| ConfigErrors(vec![error(e.to_string())]))?;
+    let mut config = parse_config(&source, name).map_err(|errors| {
+        // Positions in the generated source would mean nothing to the sender.
+        ConfigErrors(errors.0.into_iter().map(|e| error(e.message)).collect())
+    })?;
+    Ok(config.services.remove(name).expect("the service table was generated above"))
+}
+
+/// Edits the configuration file at `path` in place, keeping its comments and
+/// formatting. The file is replaced atomically and keeps its permissions,
+/// since it may hold secrets.
+pub fn update_config_file(path: &str, edit: impl FnOnce(&mut toml_edit::DocumentMut)) -> io::Result<()> {
+    let path = Path::new(path);
+    let mut document: toml_edit::DocumentMut = fs::read_to_string(path)?
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    edit(&mut document);
+
+    let directory = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let mut replacement = tempfile::NamedTempFile::new_in(directory)?;
+    io::Write::write_all(&mut replacement, document.to_string().as_bytes())?;
+    replacement.as_file().set_permissions(fs::metadata(path)?.permissions())?;
+    replacement.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+/// Appends the service table `name` to a configuration document.
+pub fn insert_service_table(document: &mut toml_edit::DocumentMut, name: &str, options: &toml::Table) {
+    let parsed: toml_edit::DocumentMut =
+        toml::to_string(options).expect("options were validated").parse().expect("toml writes valid TOML");
+    let mut table = toml_edit::Table::new();
+    for (key, item) in parsed.iter() {
+        table.insert(key, item.clone());
+    }
+    document.insert(name, toml_edit::Item::Table(table));
+}
+
+/// Sets `enabled` in the service table `name`, leaving it out when true.
+pub fn set_service_table_enabled(document: &mut toml_edit::DocumentMut, name: &str, enabled: bool) {
+    if let Some(table) = document.get_mut(name).and_then(|item| item.as_table_like_mut()) {
+        if enabled {
+            table.remove("enabled");
+        } else {
+            table.insert("enabled", toml_edit::value(false));
+        }
+    }
+}
+
+/// Creates the backend for a validated service config. Logging in is left
+/// to whoever starts the service, so credentials are resolved freshly.
+pub fn instantiate_service(config: &ServiceConfig) -> ConfiguredService {
+    let credentials = config.credentials().expect("credentials passed validation");
+    let kind = find_backend(config.backend.get_ref()).expect("backend passed validation");
+    let backend = kind.create(config.options.clone()).expect("backend options passed validation");
+    ConfiguredService::new(kind.name, backend).with_credentials(credentials)
 }
 
 #[cfg(test)]
@@ -88,11 +629,10 @@ mod tests {
     use super::*;
     use std::io::Write;
     use tempfile::NamedTempFile;
-    use tokio::sync::Mutex;
-    use crate::chat_backend::{ChatBackend, BackendEvent};
+    use crate::chat_backend::BackendEvent;
 
     #[tokio::test]
-    async fn test_load_config_and_instantiate_backend() {
+    async fn test_load_config_and_instantiate_service() {
         // Create a temporary TOML config file with one service table for a dummy backend.
         let config_content = r#"
 [some_dummy_service]
@@ -104,15 +644,16 @@ backend = "dummy"
         write!(temp_file, "{}", config_content).expect("Failed to write config content");
         let config_path = temp_file.path().to_str().unwrap();
 
-        // Load the backend(s) from the configuration file.
-        let backends = load_config_and_instantiate_backend(config_path).await;
+        // Load the service config(s) from the configuration file.
+        let services = load_config(config_path).unwrap().services;
         // Verify that we have an entry for "some_dummy_service".
-        assert!(backends.contains_key("some_dummy_service"),
+        assert!(services.contains_key("some_dummy_service"),
             "Expected service 'some_dummy_service' to be loaded");
 
-        // Retrieve the backend and check that list_channels returns a ChannelList event.
-        let backend = backends.get("some_dummy_service").unwrap();
-        let event = backend.lock().await.list_channels();
+        // Instantiate the backend and check that list_channels returns a ChannelList event.
+        let service = instantiate_service(&services["some_dummy_service"]);
+        assert_eq!(service.backend_kind, "dummy");
+        let event = service.backend.list_channels();
         match event {
             BackendEvent::ChannelList { ref channels } => {
                 assert!(!channels.is_empty(),
@@ -121,4 +662,205 @@ backend = "dummy"
             _ => panic!("Expected a ChannelList event from the dummy backend"),
         }
     }
+
+    #[test]
+    fn test_daemon_table_is_not_a_service() {
+        let config_content = r#"
+[daemon]
+socket_path = "/tmp/test.sock"
+
+[daemon.remote]
+tcp = "127.0.0.1:7878"
+token_file = "/run/secrets/remote"
+
+[daemon.events]
+overflow = "disconnect"
+
+[some_dummy_service]
+backend = "dummy"
+        "#;
+
+        let mut temp_file =
+            NamedTempFile::new().expect("Failed to create temporary config file");
+        write!(temp_file, "{}", config_content).expect("Failed to write config content");
+        let config_path = temp_file.path().to_str().unwrap();
+
+        let config = load_config(config_path).unwrap();
+        assert_eq!(config.services.len(), 1);
+        assert_eq!(config.daemon.socket_path.as_deref(), Some("/tmp/test.sock"));
+        let remote = config.daemon.remote.expect("Expected a remote listener config");
+        assert_eq!(remote.tcp.as_deref(), Some("127.0.0.1:7878"));
+        assert_eq!(remote.websocket, None);
+        assert_eq!(remote.token, SecretSource::File(PathBuf::from("/run/secrets/remote")));
+        assert_eq!(config.daemon.events.overflow, OverflowStrategy::Disconnect);
+        assert_eq!(config.daemon.events.queue_capacity, DEFAULT_QUEUE_CAPACITY);
+    }
+
+    #[test]
+    fn test_daemon_table_rejects_unknown_keys() {
+        for source in ["[daemon]\nsocket_pth = \"/tmp/test.sock\"\n", "[daemon.events]\noverflw = \"disconnect\"\n"] {
+            let errors = parse_config(source, "test.toml").unwrap_err();
+            assert!(errors.to_string().contains("unknown field"), "{}", errors);
+        }
+    }
+
+    #[test]
+    fn test_load_config_reports_every_problem() {
+        let config_content = "\
+[good]
+backend = \"dummy\"
+
+[typo]
+backend = \"dumy\"
+
+[wrong_type]
+backend = 5
+
+[no_backend]
+username = \"me\"
+";
+
+        let mut temp_file =
+            NamedTempFile::new().expect("Failed to create temporary config file");
+        write!(temp_file, "{}", config_content).expect("Failed to write config content");
+        let config_path = temp_file.path().to_str().unwrap();
+
+        let errors = load_config(config_path).unwrap_err().0;
+        assert_eq!(errors.len(), 3, "Expected one error per broken service: {:?}", errors);
+        let mut positions: Vec<_> = errors.iter().map(|e| e.position).collect();
+        positions.sort();
+        assert_eq!(positions[0], Some((5, 11)), "unknown backend points at its value");
+        assert_eq!(positions[1].map(|(line, _)| line), Some(8), "wrong type points at the value");
+        assert!(positions[2].is_some_and(|(line, _)| line >= 10), "missing backend points into the table");
+        assert!(errors.iter().all(|e| e.file == config_path));
+        assert!(errors.iter().any(|e| e.message.contains("dumy")));
+    }
+
+    #[test]
+    fn test_load_config_reports_syntax_error_position() {
+        let mut temp_file =
+            NamedTempFile::new().expect("Failed to create temporary config file");
+        write!(temp_file, "[a]\nbackend = \"dummy\"\nbroken =\n").expect("Failed to write config content");
+        let config_path = temp_file.path().to_str().unwrap();
+
+        let errors = load_config(config_path).unwrap_err().0;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].position.map(|(line, _)| line), Some(3));
+        assert!(errors[0].to_string().starts_with(&format!("{}:3:", config_path)));
+    }
+
+    #[test]
+    fn test_load_config_rejects_bad_remote_tokens() {
+        for (remote, problem) in [
+            ("token = \"secret\"", "must not be written"),
+            ("token_env = \"\"", "must not be empty"),
+            ("", "exactly one of"),
+            ("token_env = \"A\"\ntoken_file = \"b\"", "exactly one of"),
+        ] {
+            let source = format!("[daemon.remote]\ntcp = \"127.0.0.1:7878\"\n{}\n", remote);
+            let errors = parse_config(&source, "test.toml").unwrap_err().0;
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].message.contains(problem), "{}", errors[0]);
+        }
+    }
+
+    #[test]
+    fn test_load_config_missing_file() {
+        let errors = load_config("/nonexistent/kbunified.toml").unwrap_err().0;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].position, None);
+    }
+
+    #[test]
+    fn test_load_config_secret_sources() {
+        let config_content = r#"
+[from_command]
+backend = "dummy"
+username = "me"
+password_command = "pass show chat/rc"
+
+[from_token_file]
+backend = "dummy"
+token_file = "/run/secrets/chat"
+        "#;
+
+        let mut temp_file =
+            NamedTempFile::new().expect("Failed to create temporary config file");
+        write!(temp_file, "{}", config_content).expect("Failed to write config content");
+        let config = load_config(temp_file.path().to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            config.services["from_command"].credentials(),
+            Ok(Some(CredentialSource::Password {
+                username: "me".to_string(),
+                source: SecretSource::Command("pass show chat/rc".to_string()),
+            }))
+        );
+        assert_eq!(
+            config.services["from_token_file"].credentials(),
+            Ok(Some(CredentialSource::Token {
+                username: None,
+                source: SecretSource::File(PathBuf::from("/run/secrets/chat")),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_load_config_rejects_ambiguous_secrets() {
+        let config_content = r#"
+[both]
+backend = "dummy"
+username = "me"
+password_env = "CHAT_PASSWORD"
+token_env = "CHAT_TOKEN"
+
+[no_username]
+backend = "dummy"
+password_file = "/run/secrets/chat"
+
+[plain]
+backend = "dummy"
+username = "me"
+password = "hunter2"
+        "#;
+
+        let mut temp_file =
+            NamedTempFile::new().expect("Failed to create temporary config file");
+        write!(temp_file, "{}", config_content).expect("Failed to write config content");
+        let errors = load_config(temp_file.path().to_str().unwrap()).unwrap_err().0;
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors.iter().any(|e| e.message.contains("only one of")));
+        assert!(errors.iter().any(|e| e.message.contains("`username`")));
+        assert!(errors.iter().any(|e| e.message.contains("`password` must not be written")));
+        assert!(!format!("{:?}", errors).contains("hunter2"), "Secrets must not appear in errors");
+    }
+
+    #[test]
+    fn test_backend_options_are_typed() {
+        let config_content = "\
+[fast]
+backend = \"dummy\"
+username = \"me\"
+interval_ms = 10
+
+[typo]
+backend = \"dummy\"
+intervall_ms = 10
+
+[wrong_type]
+backend = \"dummy\"
+interval_ms = \"soon\"
+";
+
+        let errors = parse_config(config_content, "test.toml").unwrap_err().0;
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_eq!(errors[0].position, Some((8, 1)), "unknown key points at the key");
+        assert!(errors[0].message.contains("unknown field `intervall_ms`"), "{}", errors[0]);
+        assert_eq!(errors[1].position.map(|(line, _)| line), Some(12));
+
+        let config = parse_config("[fast]\nbackend = \"dummy\"\ninterval_ms = 10\n", "test.toml").unwrap();
+        let slow = parse_config("[fast]\nbackend = \"dummy\"\ninterval_ms = 20\n", "test.toml").unwrap();
+        assert_ne!(config.services["fast"], slow.services["fast"], "Backend options take part in comparisons");
+        assert_eq!(instantiate_service(&config.services["fast"]).backend_kind, "dummy");
+    }
 }
@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+
+use futures::StreamExt;
+use zbus::zvariant::Value;
+use zbus::Connection;
+
+use crate::chat_backend::{BackendEvent, Channel};
+use crate::command_processor::{execute_command, CommandContext};
+use crate::event_bus::Subscription;
+use crate::notifications::NotificationLevel;
+use crate::protocol::{DaemonEvent, FrontendCommand, FrontendEvent, ServiceEvent};
+
+/// The action that marks the message's channel as read up to it.
+const MARK_READ_ACTION: &str = "mark-read";
+/// The action of notification servers that can reply from the notification,
+/// which then emit `NotificationReplied`.
+const REPLY_ACTION: &str = "inline-reply";
+
+#[zbus::proxy(
+    interface = "org.freedesktop.Notifications",
+    default_service = "org.freedesktop.Notifications",
+    default_path = "/org/freedesktop/Notifications"
+)]
+trait Notifications {
+    #[allow(clippy::too_many_arguments)]
+    fn notify(
+        &self,
+        app_name: &str,
+        replaces_id: u32,
+        app_icon: &str,
+        summary: &str,
+        body: &str,
+        actions: &[&str],
+        hints: HashMap<&str, Value<'_>>,
+        expire_timeout: i32,
+    ) -> zbus::Result<u32>;
+
+    #[zbus(signal)]
+    fn action_invoked(&self, id: u32, action_key: String) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_replied(&self, id: u32, text: String) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn notification_closed(&self, id: u32, reason: u32) -> zbus::Result<()>;
+}
+
+/// The message a desktop notification is about.
+struct Shown {
+    service: String,
+    channel_id: String,
+    message_id: u64,
+}
+
+/// Shows a desktop notification for every `notification` event of level
+/// `notify`, through the `org.freedesktop.Notifications` server on
+/// `connection`. Replies are posted like `post_message` commands, and the
+/// "Mark read" action runs `mark_read`.
+pub async fn run_desktop_notifier(connection: Connection, context: CommandContext, mut subscription: Subscription) {
+    let signals = async {
+        let proxy = NotificationsProxy::new(&connection).await?;
+        let actions = proxy.receive_action_invoked().await?;
+        let replies = proxy.receive_notification_replied().await?;
+        let closed = proxy.receive_notification_closed().await?;
+        zbus::Result::Ok((proxy, actions, replies, closed))
+    };
+    let (proxy, mut actions, mut replies, mut closed) = match signals.await {
+        Ok(signals) => signals,
+        Err(e) => {
+            eprintln!("Desktop notifications are unavailable: {}", e);
+            return;
+        }
+    };
+    let mut shown: HashMap<u32, Shown> = HashMap::new();
+    let mut channels: HashMap<(String, String), Channel> = HashMap::new();
+    loop {
+        let command = tokio::select! {
+            event = subscription.next_event() => {
+                if let FrontendEvent::Service(ServiceEvent { service, event }) = event {
+                    match event {
+                        BackendEvent::ChannelList { channels: listed } => {
+                            for channel in listed {
+                                channels.insert((service.clone(), channel.id.clone()), channel);
+                            }
+                        }
+                        BackendEvent::Notification { channel_id, message_id, author, body, level: NotificationLevel::Notify, .. } => {
+                            let channel = channels.get(&(service.clone(), channel_id.clone()));
+                            let summary = format!("{} in {}", author, channel.map_or(&channel_id, |channel| &channel.name));
+                            let actions = [MARK_READ_ACTION, "Mark read", REPLY_ACTION, "Reply"];
+                            let hints = HashMap::from([("category", Value::from("im.received"))]);
+                            match proxy.notify("kbunified", 0, "", &summary, &body, &actions, hints, -1).await {
+                                Ok(id) => {
+                                    shown.insert(id, Shown { service, channel_id, message_id });
+                                }
+                                Err(e) => eprintln!("Failed to show a desktop notification: {}", e),
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                None
+            }
+            Some(signal) = actions.next() => {
+                let Ok(args) = signal.args() else { continue };
+                match shown.get(&args.id) {
+                    Some(message) if args.action_key == MARK_READ_ACTION => Some(FrontendCommand::MarkRead {
+                        service: message.service.clone(),
+                        channel_id: message.channel_id.clone(),
+                        message_id: message.message_id,
+                    }),
+                    _ => None,
+                }
+            }
+            Some(signal) = replies.next() => {
+                let Ok(args) = signal.args() else { continue };
+                shown.get(&args.id).map(|message| FrontendCommand::PostMessage {
+                    service: message.service.clone(),
+                    channel_id: message.channel_id.clone(),
+                    body: args.text,
+                    local_id: None,
+                })
+            }
+            Some(signal) = closed.next() => {
+                if let Ok(args) = signal.args() {
+                    shown.remove(&args.id);
+                }
+                None
+            }
+        };
+        if let Some(command) = command {
+            let context = context.clone();
+            tokio::spawn(async move {
+                if let Err(DaemonEvent::CommandError { reason, .. }) = execute_command(command, &context).await {
+                    eprintln!("Desktop notification action failed: {}", reason);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UnixStream;
+    use tokio::sync::{mpsc, Mutex};
+    use zbus::object_server::SignalEmitter;
+
+    use crate::chat_backend::ConfiguredService;
+    use crate::dummy_backend::DummyBackend;
+    use crate::event_bus::EventBus;
+    use crate::outbox::DeliveryState;
+    use crate::service_manager::ServiceManager;
+
+    const PATH: &str = "/org/freedesktop/Notifications";
+
+    /// A notification server that reports the summary and body it is asked to show.
+    struct StubServer {
+        shown: mpsc::UnboundedSender<(String, String, Vec<String>)>,
+    }
+
+    #[zbus::interface(name = "org.freedesktop.Notifications")]
+    impl StubServer {
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: &str,
+            _replaces_id: u32,
+            _app_icon: &str,
+            summary: &str,
+            body: &str,
+            actions: Vec<String>,
+            _hints: HashMap<String, zbus::zvariant::OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            self.shown.send((summary.to_string(), body.to_string(), actions)).unwrap();
+            7
+        }
+
+        #[zbus(signal)]
+        async fn action_invoked(emitter: &SignalEmitter<'_>, id: u32, action_key: &str) -> zbus::Result<()>;
+
+        #[zbus(signal)]
+        async fn notification_replied(emitter: &SignalEmitter<'_>, id: u32, text: &str) -> zbus::Result<()>;
+    }
+
+    #[tokio::test]
+    async fn test_reply_and_mark_read_actions() {
+        let (server_socket, client_socket) = UnixStream::pair().unwrap();
+        let (shown_tx, mut shown_rx) = mpsc::unbounded_channel();
+        let server = async {
+            zbus::connection::Builder::unix_stream(server_socket)
+                .server(zbus::Guid::generate())?
+                .p2p()
+                .serve_at(PATH, StubServer { shown: shown_tx })?
+                .build()
+                .await
+        };
+        let client = zbus::connection::Builder::unix_stream(client_socket).p2p().build();
+        let (server, client) = tokio::try_join!(server, client).unwrap();
+
+        let backends = HashMap::from([("chat".to_string(), ConfiguredService::new("dummy", Box::new(DummyBackend::new())))]);
+        let context = CommandContext::new(ServiceManager::new(Arc::new(Mutex::new(backends)), EventBus::new()));
+        let mut events = context.events.subscribe("test");
+        let subscription = context.events.subscribe_lossless("desktop_notifications");
+        tokio::spawn(run_desktop_notifier(client, context.clone(), subscription));
+
+        let channels = vec![Channel { id: "D1".to_string(), name: "bob".to_string(), direct: true }];
+        context.events.publish(ServiceEvent::new("chat", BackendEvent::ChannelList { channels }));
+        let notification = |message_id, level| BackendEvent::Notification {
+            channel_id: "D1".to_string(),
+            message_id,
+            author: "bob".to_string(),
+            body: format!("message {}", message_id),
+            level,
+            reason: "rule 1".to_string(),
+        };
+        context.events.publish(ServiceEvent::new("chat", notification(1, NotificationLevel::Highlight)));
+        context.events.publish(ServiceEvent::new("chat", notification(2, NotificationLevel::Notify)));
+        let (summary, body, actions) = shown_rx.recv().await.unwrap();
+        assert_eq!((summary.as_str(), body.as_str()), ("bob in bob", "message 2"), "Highlights are not shown");
+        assert!(actions.contains(&REPLY_ACTION.to_string()) && actions.contains(&MARK_READ_ACTION.to_string()));
+
+        let emitter = SignalEmitter::new(&server, PATH).unwrap();
+        StubServer::notification_replied(&emitter, 7, "on my way").await.unwrap();
+        StubServer::action_invoked(&emitter, 7, MARK_READ_ACTION).await.unwrap();
+
+        let (mut sent, mut read) = (false, false);
+        while !(sent && read) {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap();
+            match event {
+                FrontendEvent::Daemon(DaemonEvent::MessageStatus { channel_id, status: DeliveryState::Sent, .. }) => {
+                    sent = channel_id == "D1";
+                }
+                FrontendEvent::Daemon(DaemonEvent::InboxRead { service, message_id, .. }) => {
+                    read = (service.as_str(), message_id) == ("chat", 2);
+                }
+                _ => {}
+            }
+        }
+    }
+}
@@ -1,29 +1,88 @@
-use rand::Rng;
-use crate::chat_backend::{Message, Channel, LoginError, PostError, BackendEvent}; // adjust the path based on your project structure
+use crate::chat_backend::{Capabilities, Channel, Credentials, HistoryError, LoginError, Message, PostError, BackendEvent}; // adjust the path based on your project structure
 use crate::chat_backend::ChatBackend;
+use crate::backend_registry::BackendConfig;
 use async_stream::stream;
 use futures::Stream;
 use tokio::time::{sleep, Duration};
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
 use std::pin::Pin;
 use async_trait::async_trait;
+use serde::Deserialize;
+
+/// How often the dummy backend makes up messages, unless configured otherwise.
+const DEFAULT_INTERVAL_MS: u64 = 500;
+
+/// How many made-up past messages each dummy channel has.
+const HISTORY_LENGTH: u64 = 100;
+
+/// Options of `backend = "dummy"` services.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DummyConfig {
+    /// Milliseconds between two bursts of made-up messages.
+    #[serde(default = "default_interval_ms")]
+    interval_ms: u64,
+}
+
+fn default_interval_ms() -> u64 {
+    DEFAULT_INTERVAL_MS
+}
+
+impl BackendConfig for DummyConfig {
+    const KIND: &'static str = "dummy";
+
+    fn instantiate(self) -> Box<dyn ChatBackend + Send + Sync> {
+        Box::new(DummyBackend::new().with_interval(Duration::from_millis(self.interval_ms)))
+    }
+}
+
+fn first_live_id() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_millis() as u64);
+    now.max(HISTORY_LENGTH + 1)
+}
 
 pub struct DummyBackend {
     posted_messages: Arc<Mutex<Vec<BackendEvent>>>,
+    /// Idempotency keys of the posts seen so far, so that retries are not echoed twice.
+    posted_keys: Mutex<HashSet<String>>,
+    /// The id of the next live or posted message. Ids keep increasing across
+    /// reconnections, and start from the current time in milliseconds so
+    /// that they do not repeat those of earlier runs or of the history.
+    next_id: Arc<AtomicU64>,
+    interval: Duration,
 }
 
 impl DummyBackend {
     pub fn new() -> Self {
         DummyBackend {
             posted_messages: Arc::new(Mutex::new(Vec::new())),
+            posted_keys: Mutex::new(HashSet::new()),
+            next_id: Arc::new(AtomicU64::new(first_live_id())),
+            interval: Duration::from_millis(DEFAULT_INTERVAL_MS),
         }
     }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
 }
 
 #[async_trait]
 impl ChatBackend for DummyBackend {
     // Implement the trait methods here.
-    async fn login(&self, username: &str, password: &str) -> Result<String, LoginError> {
+    async fn login(&self, credentials: &Credentials) -> Result<String, LoginError> {
+        // Accept any non-empty secret, so that secret sources can be tried out.
+        let secret = match credentials {
+            Credentials::Password { password, .. } => password,
+            Credentials::Token { token, .. } => token,
+        };
+        if secret.expose().is_empty() {
+            return Err(LoginError::InvalidCredentials);
+        }
         Ok("dummy_session_token".to_string())
     }
     fn list_channels(&self) -> BackendEvent {
@@ -32,10 +91,12 @@ impl ChatBackend for DummyBackend {
                 Channel {
                     id: "dummy_channel1".to_string(),
                     name: "Dummy Channel1".to_string(),
+                    direct: false,
                 },
                 Channel {
                     id: "dummy_channel2".to_string(),
                     name: "Dummy Channel2".to_string(),
+                    direct: false,
                 }
             ]
         }
@@ -43,8 +104,9 @@ impl ChatBackend for DummyBackend {
 
     fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
         let extra_messages = self.posted_messages.clone();
+        let interval = self.interval;
+        let next_id = self.next_id.clone();
         let s = stream! {
-            let mut message_id = 1u64;
             loop {
                 // First, yield any messages that were posted (and clear the table)
                 // Extract posted messages from the table without holding the lock across an await.
@@ -56,39 +118,79 @@ impl ChatBackend for DummyBackend {
                 for msg in posted_msgs {
                     yield msg;
                 }
+                let message_id = next_id.fetch_add(1, Ordering::Relaxed);
                 let msg1 = BackendEvent::Message {
-                    message_id: message_id,
+                    message_id,
                     channel_id: "dummy_channel1".to_string(),
                     author: "Dummy Author".to_string(),
                     body: format!("Random message: {}", message_id),
+                    attachments: Vec::new(),
+                    idempotency_key: None,
                 };
                 yield msg1;
-                message_id += 1;
+                let message_id = next_id.fetch_add(1, Ordering::Relaxed);
                 let msg2 = BackendEvent::Message {
-                    message_id: message_id,
+                    message_id,
                     channel_id: "dummy_channel2".to_string(),
                     author: "Another Dummy Author".to_string(),
                     body: format!("Random message: {}", message_id),
+                    attachments: Vec::new(),
+                    idempotency_key: None,
                 };
                 yield msg2;
-                message_id += 1;
-                sleep(Duration::from_millis(500)).await;
+                sleep(interval).await;
             }
         };
 
         Box::pin(s)
     }
 
-    async fn post_message(&self, channel_id: &str, content: &str) -> Result<(), PostError> {
+    async fn post_message(&self, channel_id: &str, content: &str, idempotency_key: &str) -> Result<(), PostError> {
+        if !self.posted_keys.lock().unwrap().insert(idempotency_key.to_string()) {
+            return Ok(());
+        }
         let message = BackendEvent::Message {
-            message_id: 0u64,
+            message_id: self.next_id.fetch_add(1, Ordering::Relaxed),
             channel_id: channel_id.to_string(),
             author: "Good old me".to_string(),
             body: content.to_string(),
+            attachments: Vec::new(),
+            idempotency_key: Some(idempotency_key.to_string()),
         };
         let mut table = self.posted_messages.lock().unwrap();
-        println!("pushing message");
         table.push(message);
         Ok(())
     }
+
+    async fn fetch_history(
+        &self,
+        channel_id: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<Message>, HistoryError> {
+        if channel_id != "dummy_channel1" && channel_id != "dummy_channel2" {
+            return Err(HistoryError::ChannelNotFound);
+        }
+        let end = before.unwrap_or(HISTORY_LENGTH + 1).min(HISTORY_LENGTH + 1);
+        let start = end.saturating_sub(limit as u64).max(1);
+        Ok((start..end)
+            .map(|id| Message {
+                id,
+                channel_id: channel_id.to_string(),
+                author: "Dummy Historian".to_string(),
+                content: format!("Old message: {}", id),
+                timestamp: None,
+                attachments: Vec::new(),
+            })
+            .collect())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        // The dummy backend can only list channels and post plain messages.
+        Capabilities::default()
+    }
+
+    fn echoes_idempotency_keys(&self) -> bool {
+        true
+    }
 }
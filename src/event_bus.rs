@@ -0,0 +1,427 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+
+use crate::chat_backend::BackendEvent;
+use crate::protocol::{DaemonEvent, FrontendEvent, ServiceEvent};
+
+/// How many events a subscriber may fall behind before its overflow strategy applies.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 1024;
+
+/// What happens when a subscriber's queue is full and another event arrives.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowStrategy {
+    /// Discard the oldest queued event to make room. Dropped streamed
+    /// messages are reported with a `messages_dropped` event.
+    #[default]
+    DropOldest,
+    /// Discard the oldest event the subscriber can do without: a
+    /// `service_status` superseded by a later one, or a streamed message,
+    /// which a `messages_dropped` event reports. When only critical events
+    /// are queued, the subscriber is disconnected instead, since it could
+    /// not catch up.
+    DropNonCritical,
+    /// Disconnect the subscriber, telling it why.
+    Disconnect,
+}
+
+/// Why a subscriber was disconnected by the bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Overflow {
+    pub reason: String,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reason)
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+/// How far behind one subscriber is, as reported by `subscriber_stats`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct SubscriberStats {
+    pub id: u64,
+    /// What the subscriber is, e.g. `"stdout"` or `"tcp 192.0.2.1:5000"`.
+    pub label: String,
+    /// Events waiting to be delivered right now.
+    pub queued: usize,
+    /// The most events that were ever waiting at once.
+    pub peak_queued: usize,
+    pub delivered: u64,
+    pub dropped: u64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<FrontendEvent>,
+    /// How many streamed messages of each channel were dropped and not yet
+    /// reported, in the order the channels first lost one.
+    dropped_messages: Vec<((String, String), u64)>,
+    closed: Option<Overflow>,
+    peak_queued: usize,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl QueueState {
+    /// Counts an event dropped to make room, remembering which channel a
+    /// dropped streamed message belonged to.
+    fn drop_event(&mut self, event: Option<FrontendEvent>) {
+        self.dropped += 1;
+        let Some(FrontendEvent::Service(ServiceEvent { service, event: BackendEvent::Message { channel_id, .. } })) = event else {
+            return;
+        };
+        let channel = (service, channel_id);
+        match self.dropped_messages.iter_mut().find(|(dropped, _)| *dropped == channel) {
+            Some((_, count)) => *count += 1,
+            None => self.dropped_messages.push((channel, 1)),
+        }
+    }
+}
+
+struct SubscriberQueue {
+    id: u64,
+    label: String,
+    /// `None` for lossless subscribers, whose queue is unbounded.
+    strategy: Option<OverflowStrategy>,
+    state: Mutex<QueueState>,
+    ready: Notify,
+}
+
+impl SubscriberQueue {
+    /// Queues an event, applying the subscriber's strategy when full.
+    /// Returns false once the subscriber has been disconnected.
+    fn push(&self, event: FrontendEvent, capacity: usize) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed.is_some() {
+            return false;
+        }
+        if let Some(strategy) = self.strategy.filter(|_| state.events.len() >= capacity) {
+            let disconnect = match strategy {
+                OverflowStrategy::DropOldest => {
+                    let dropped = state.events.pop_front();
+                    state.drop_event(dropped);
+                    false
+                }
+                OverflowStrategy::DropNonCritical => match state.events.iter().position(|queued| !queued.is_critical()) {
+                    Some(index) => {
+                        let dropped = state.events.remove(index);
+                        state.drop_event(dropped);
+                        false
+                    }
+                    // Nothing queued may be dropped: drop the incoming event if it may be.
+                    None if !event.is_critical() => {
+                        state.drop_event(Some(event));
+                        drop(state);
+                        self.ready.notify_one();
+                        return true;
+                    }
+                    None => true,
+                },
+                OverflowStrategy::Disconnect => true,
+            };
+            if disconnect {
+                let reason = format!("Fell behind by more than {} events", capacity);
+                state.dropped += state.events.len() as u64 + 1;
+                state.events.clear();
+                state.dropped_messages.clear();
+                state.closed = Some(Overflow { reason });
+                drop(state);
+                self.ready.notify_one();
+                return false;
+            }
+        }
+        state.events.push_back(event);
+        state.peak_queued = state.peak_queued.max(state.events.len());
+        drop(state);
+        self.ready.notify_one();
+        true
+    }
+
+    fn pop(&self) -> Result<Option<FrontendEvent>, Overflow> {
+        let mut state = self.state.lock().unwrap();
+        // Reported first, so that the frontend fetches what it missed as soon as possible.
+        if !state.dropped_messages.is_empty() {
+            let ((service, channel_id), count) = state.dropped_messages.remove(0);
+            return Ok(Some(DaemonEvent::MessagesDropped { service, channel_id, count }.into()));
+        }
+        match state.events.pop_front() {
+            Some(event) => {
+                state.delivered += 1;
+                Ok(Some(event))
+            }
+            None => match &state.closed {
+                Some(overflow) => Err(overflow.clone()),
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn stats(&self) -> SubscriberStats {
+        let state = self.state.lock().unwrap();
+        SubscriberStats {
+            id: self.id,
+            label: self.label.clone(),
+            queued: state.events.len(),
+            peak_queued: state.peak_queued,
+            delivered: state.delivered,
+            dropped: state.dropped,
+        }
+    }
+}
+
+struct BusState {
+    capacity: usize,
+    strategy: OverflowStrategy,
+    next_id: AtomicU64,
+    subscribers: Mutex<Vec<Arc<SubscriberQueue>>>,
+}
+
+/// Fans every event out to all connected frontends. Each subscriber has its
+/// own bounded queue, so a slow one never holds up the others.
+#[derive(Clone)]
+pub struct EventBus {
+    state: Arc<BusState>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::with_queue(DEFAULT_QUEUE_CAPACITY, OverflowStrategy::default())
+    }
+
+    /// Creates a bus whose subscribers queue up to `capacity` events each.
+    pub fn with_queue(capacity: usize, strategy: OverflowStrategy) -> Self {
+        Self {
+            state: Arc::new(BusState {
+                capacity: capacity.max(1),
+                strategy,
+                next_id: AtomicU64::new(1),
+                subscribers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Queues an event for every current subscriber. Events published while
+    /// nobody is subscribed are dropped.
+    pub fn publish(&self, event: impl Into<FrontendEvent>) {
+        let event = event.into();
+        let mut subscribers = self.state.subscribers.lock().unwrap();
+        subscribers.retain(|queue| queue.push(event.clone(), self.state.capacity));
+    }
+
+    /// Starts queueing events for a new subscriber, described by `label` in
+    /// the statistics.
+    pub fn subscribe(&self, label: impl Into<String>) -> Subscription {
+        self.subscribe_with(label, self.state.strategy)
+    }
+
+    /// Like `subscribe`, with an overflow strategy of its own.
+    pub fn subscribe_with(&self, label: impl Into<String>, strategy: OverflowStrategy) -> Subscription {
+        self.add_subscriber(label.into(), Some(strategy))
+    }
+
+    /// Starts queueing events for one of the daemon's own consumers, which
+    /// keep state that missed events could not restore. Its queue is never
+    /// trimmed, so it must keep up on average; read it with `next_event`.
+    pub fn subscribe_lossless(&self, label: impl Into<String>) -> Subscription {
+        self.add_subscriber(label.into(), None)
+    }
+
+    fn add_subscriber(&self, label: String, strategy: Option<OverflowStrategy>) -> Subscription {
+        let queue = Arc::new(SubscriberQueue {
+            id: self.state.next_id.fetch_add(1, Ordering::Relaxed),
+            label,
+            strategy,
+            state: Mutex::new(QueueState::default()),
+            ready: Notify::new(),
+        });
+        self.state.subscribers.lock().unwrap().push(queue.clone());
+        Subscription { queue, bus: self.state.clone() }
+    }
+
+    /// Reports how far behind every subscriber is, in subscription order.
+    pub fn stats(&self) -> Vec<SubscriberStats> {
+        self.state.subscribers.lock().unwrap().iter().map(|queue| queue.stats()).collect()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The receiving end of one subscriber's queue. Dropping it unsubscribes.
+pub struct Subscription {
+    queue: Arc<SubscriberQueue>,
+    bus: Arc<BusState>,
+}
+
+impl Subscription {
+    /// Waits for the next event. Fails once the bus has disconnected this
+    /// subscriber for falling behind.
+    pub async fn recv(&mut self) -> Result<FrontendEvent, Overflow> {
+        loop {
+            if let Some(event) = self.queue.pop()? {
+                return Ok(event);
+            }
+            self.queue.ready.notified().await;
+        }
+    }
+
+    /// Waits for the next event of a lossless subscription.
+    pub async fn next_event(&mut self) -> FrontendEvent {
+        self.recv().await.expect("lossless subscribers are never disconnected")
+    }
+
+    /// Returns the next queued event without waiting, if there is one.
+    pub fn try_recv(&mut self) -> Option<FrontendEvent> {
+        self.queue.pop().ok().flatten()
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.bus.subscribers.lock().unwrap().retain(|queue| !Arc::ptr_eq(queue, &self.queue));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::supervisor::ServiceStatus;
+
+    fn status(service: &str) -> DaemonEvent {
+        DaemonEvent::ServiceStatus {
+            service: service.to_string(),
+            status: ServiceStatus::Connected,
+            reason: None,
+            retry_in_ms: None,
+        }
+    }
+
+    fn reason(event: FrontendEvent) -> String {
+        match event {
+            FrontendEvent::Daemon(DaemonEvent::CommandError { reason, .. }) => reason,
+            FrontendEvent::Daemon(DaemonEvent::ServiceStatus { service, .. }) => service,
+            other => panic!("Unexpected event {:?}", other),
+        }
+    }
+
+    fn critical(reason: &str) -> DaemonEvent {
+        DaemonEvent::command_error(reason, None)
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let bus = EventBus::with_queue(2, OverflowStrategy::DropOldest);
+        let mut slow = bus.subscribe("slow");
+        for name in ["1", "2", "3"] {
+            bus.publish(critical(name));
+        }
+        assert_eq!(reason(slow.recv().await.unwrap()), "2");
+        assert_eq!(reason(slow.recv().await.unwrap()), "3");
+
+        let stats = &bus.stats()[0];
+        assert_eq!((stats.label.as_str(), stats.queued, stats.peak_queued), ("slow", 0, 2));
+        assert_eq!((stats.delivered, stats.dropped), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn test_drop_non_critical() {
+        let bus = EventBus::with_queue(2, OverflowStrategy::DropNonCritical);
+        let mut slow = bus.subscribe("slow");
+        bus.publish(status("a"));
+        bus.publish(critical("1"));
+        bus.publish(critical("2"));
+        // A full queue of critical events drops the incoming non-critical one.
+        bus.publish(status("b"));
+        assert_eq!(reason(slow.recv().await.unwrap()), "1");
+        assert_eq!(reason(slow.recv().await.unwrap()), "2");
+        assert_eq!(bus.stats()[0].dropped, 2);
+
+        bus.publish(critical("3"));
+        bus.publish(critical("4"));
+        bus.publish(critical("5"));
+        assert_eq!(slow.recv().await.unwrap_err().reason, "Fell behind by more than 2 events");
+        assert!(bus.stats().is_empty(), "A disconnected subscriber is forgotten");
+    }
+
+    #[tokio::test]
+    async fn test_messages_make_room_for_replies() {
+        let bus = EventBus::with_queue(2, OverflowStrategy::DropNonCritical);
+        let mut slow = bus.subscribe("slow");
+        for id in 1..=2 {
+            let message = BackendEvent::Message {
+                channel_id: "general".to_string(),
+                message_id: id,
+                body: "bulk".to_string(),
+                author: "someone".to_string(),
+                attachments: Vec::new(),
+                idempotency_key: None,
+            };
+            bus.publish(ServiceEvent::new("chat", message));
+        }
+        bus.publish(critical("reply"));
+        // The frontend learns which channel to fetch again before anything else.
+        match slow.recv().await.unwrap() {
+            FrontendEvent::Daemon(DaemonEvent::MessagesDropped { service, channel_id, count }) => {
+                assert_eq!((service.as_str(), channel_id.as_str(), count), ("chat", "general", 1));
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
+        assert!(matches!(slow.recv().await.unwrap(), FrontendEvent::Service(_)));
+        assert_eq!(reason(slow.recv().await.unwrap()), "reply");
+        assert_eq!(bus.stats()[0].dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_only_affects_slow_subscriber() {
+        let bus = EventBus::with_queue(1, OverflowStrategy::Disconnect);
+        let mut slow = bus.subscribe("slow");
+        let mut fast = bus.subscribe("fast");
+        bus.publish(critical("1"));
+        assert_eq!(reason(fast.recv().await.unwrap()), "1");
+        bus.publish(critical("2"));
+        assert_eq!(reason(fast.recv().await.unwrap()), "2");
+
+        assert!(slow.recv().await.is_err());
+        let stats = bus.stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].label, "fast");
+        drop(fast);
+        assert!(bus.stats().is_empty(), "Dropping a subscription unsubscribes");
+    }
+
+    #[tokio::test]
+    async fn test_subscriber_strategy_overrides_the_bus() {
+        let bus = EventBus::with_queue(1, OverflowStrategy::DropOldest);
+        let mut frontend = bus.subscribe("frontend");
+        let mut internal = bus.subscribe_with("internal", OverflowStrategy::Disconnect);
+        bus.publish(critical("1"));
+        bus.publish(critical("2"));
+        assert_eq!(reason(frontend.recv().await.unwrap()), "2");
+        assert!(internal.recv().await.is_err(), "Missed events are reported");
+    }
+
+    #[tokio::test]
+    async fn test_lossless_subscribers_keep_every_event() {
+        let bus = EventBus::with_queue(1, OverflowStrategy::Disconnect);
+        let mut internal = bus.subscribe_lossless("internal");
+        for name in ["1", "2", "3"] {
+            bus.publish(critical(name));
+        }
+        for name in ["1", "2", "3"] {
+            assert_eq!(reason(internal.next_event().await), name);
+        }
+        assert_eq!((bus.stats()[0].peak_queued, bus.stats()[0].dropped), (3, 0));
+    }
+}
@@ -0,0 +1,381 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use schemars::JsonSchema;
+use serde::Deserialize;
+
+use crate::chat_backend::{Attachment, Channel, Message};
+use crate::store::{Store, StoreError};
+use crate::time::Utc;
+
+/// What `export` writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// One JSON `Message` per line.
+    Ndjson,
+    /// A transcript, with attachments copied alongside.
+    Markdown,
+    /// A standalone page, with attachments copied alongside.
+    Html,
+    /// One mail per message, for mail-backed services.
+    Mbox,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            "markdown" => Ok(ExportFormat::Markdown),
+            "html" => Ok(ExportFormat::Html),
+            "mbox" => Ok(ExportFormat::Mbox),
+            other => Err(format!("Unknown export format '{}', expected 'ndjson', 'markdown', 'html' or 'mbox'", other)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExportError {
+    Io(io::Error),
+    Store(StoreError),
+    UnknownChannel(String),
+    /// Nothing was ever stored for the service.
+    NothingStored(String),
+}
+
+impl fmt::Display for ExportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExportError::Io(e) => write!(f, "Failed to write the export: {}", e),
+            ExportError::Store(e) => write!(f, "{}", e),
+            ExportError::UnknownChannel(channel) => write!(f, "No stored channel '{}'", channel),
+            ExportError::NothingStored(service) => write!(f, "Nothing is stored for service '{}'", service),
+        }
+    }
+}
+
+impl std::error::Error for ExportError {}
+
+impl From<io::Error> for ExportError {
+    fn from(error: io::Error) -> Self {
+        ExportError::Io(error)
+    }
+}
+
+impl From<StoreError> for ExportError {
+    fn from(error: StoreError) -> Self {
+        ExportError::Store(error)
+    }
+}
+
+/// What an export wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    pub messages: usize,
+    pub attachments: usize,
+}
+
+/// Writes the stored history of one channel, or of every channel of a
+/// service, to `output`. Markdown and HTML transcripts get their attachments
+/// copied to a `<name>_files` directory next to them. The file is replaced
+/// only once the export is complete.
+pub fn export(
+    store: &Store,
+    service: &str,
+    channel_id: Option<&str>,
+    format: ExportFormat,
+    output: &Path,
+) -> Result<ExportSummary, ExportError> {
+    let mut channels = store.channels(service)?;
+    if let Some(channel_id) = channel_id {
+        channels.retain(|channel| channel.id == channel_id);
+        if channels.is_empty() {
+            return Err(ExportError::UnknownChannel(channel_id.to_string()));
+        }
+    } else if channels.is_empty() {
+        return Err(ExportError::NothingStored(service.to_string()));
+    }
+
+    let directory = match output.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let stem = output.file_stem().unwrap_or(output.as_os_str()).to_string_lossy();
+    let mut exporter = Exporter {
+        service,
+        files: AttachmentCopier { directory: directory.join(format!("{}_files", stem)), link_prefix: format!("{}_files", stem) },
+        summary: ExportSummary::default(),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(directory)?;
+    {
+        let mut writer = BufWriter::new(file.as_file_mut());
+        if format == ExportFormat::Html {
+            write!(
+                writer,
+                "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n",
+                escape_html(service)
+            )?;
+        }
+        for channel in &channels {
+            let messages = store.messages(service, &channel.id)?;
+            exporter.summary.messages += messages.len();
+            match format {
+                ExportFormat::Ndjson => exporter.ndjson(&mut writer, &messages)?,
+                ExportFormat::Markdown => exporter.markdown(&mut writer, channel, &messages)?,
+                ExportFormat::Html => exporter.html(&mut writer, channel, &messages)?,
+                ExportFormat::Mbox => exporter.mbox(&mut writer, channel, &messages)?,
+            }
+        }
+        if format == ExportFormat::Html {
+            writeln!(writer, "</body>\n</html>")?;
+        }
+        writer.flush()?;
+    }
+    file.persist(output).map_err(|e| e.error)?;
+    Ok(exporter.summary)
+}
+
+/// Resolves the `path` of an `export` command within `directory`, the only
+/// place frontends may have exports written to.
+pub fn export_path(directory: &Path, path: &str) -> Result<PathBuf, String> {
+    let relative = Path::new(path);
+    if path.is_empty() || !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(format!("'{}' is not a relative path within the export directory", path));
+    }
+    Ok(directory.join(relative))
+}
+
+struct Exporter<'a> {
+    service: &'a str,
+    files: AttachmentCopier,
+    summary: ExportSummary,
+}
+
+impl Exporter<'_> {
+    fn ndjson(&mut self, writer: &mut impl Write, messages: &[Message]) -> io::Result<()> {
+        for message in messages {
+            writeln!(writer, "{}", serde_json::to_string(message).unwrap())?;
+        }
+        Ok(())
+    }
+
+    fn markdown(&mut self, writer: &mut impl Write, channel: &Channel, messages: &[Message]) -> io::Result<()> {
+        writeln!(writer, "# {} / {}\n", escape_markdown(self.service), escape_markdown(&channel.name))?;
+        for message in messages {
+            let author = escape_markdown(&message.author);
+            match message.timestamp {
+                Some(timestamp) => writeln!(writer, "**{}** · {}\n", author, Utc::new(timestamp).readable())?,
+                None => writeln!(writer, "**{}**\n", author)?,
+            }
+            writeln!(writer, "{}\n", escape_markdown(&message.content))?;
+            for attachment in &message.attachments {
+                let name = escape_markdown(&attachment.name);
+                match self.copy(message, attachment) {
+                    Some(link) => writeln!(writer, "- [{}](<{}>)", name, escape_markdown(&link))?,
+                    None => writeln!(writer, "- {} (missing)", name)?,
+                }
+            }
+            if !message.attachments.is_empty() {
+                writeln!(writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn html(&mut self, writer: &mut impl Write, channel: &Channel, messages: &[Message]) -> io::Result<()> {
+        writeln!(writer, "<h1>{} / {}</h1>", escape_html(self.service), escape_html(&channel.name))?;
+        for message in messages {
+            write!(writer, "<article id=\"m{}\">\n<header><strong>{}</strong>", message.id, escape_html(&message.author))?;
+            if let Some(timestamp) = message.timestamp {
+                write!(writer, " <time>{}</time>", Utc::new(timestamp).readable())?;
+            }
+            writeln!(writer, "</header>\n<p>{}</p>", escape_html(&message.content).replace('\n', "<br>\n"))?;
+            if !message.attachments.is_empty() {
+                writeln!(writer, "<ul>")?;
+                for attachment in &message.attachments {
+                    match self.copy(message, attachment) {
+                        Some(link) => writeln!(
+                            writer,
+                            "<li><a href=\"{}\">{}</a></li>",
+                            escape_html(&link),
+                            escape_html(&attachment.name)
+                        )?,
+                        None => writeln!(writer, "<li>{} (missing)</li>", escape_html(&attachment.name))?,
+                    }
+                }
+                writeln!(writer, "</ul>")?;
+            }
+            writeln!(writer, "</article>")?;
+        }
+        Ok(())
+    }
+
+    /// Writes mboxrd: lines of the body that look like a `From ` separator,
+    /// even behind `>` quotes, get one more `>`.
+    fn mbox(&mut self, writer: &mut impl Write, channel: &Channel, messages: &[Message]) -> io::Result<()> {
+        for message in messages {
+            let date = Utc::new(message.timestamp.unwrap_or(0));
+            let sender: String =
+                message.author.chars().map(|c| if c.is_whitespace() { '_' } else { c }).collect();
+            writeln!(writer, "From {} {}", sender, date.asctime())?;
+            writeln!(writer, "From: {}", header_value(&message.author))?;
+            writeln!(writer, "Date: {}", date.rfc2822())?;
+            writeln!(writer, "Subject: {}", header_value(&channel.name))?;
+            writeln!(writer, "Message-ID: <{}.{}.{}@kbunified>", message.id, header_value(&message.channel_id), header_value(self.service))?;
+            writeln!(writer, "MIME-Version: 1.0")?;
+            writeln!(writer, "Content-Type: text/plain; charset=utf-8")?;
+            writeln!(writer, "Content-Transfer-Encoding: 8bit\n")?;
+            for line in message.content.lines() {
+                if line.trim_start_matches('>').starts_with("From ") {
+                    write!(writer, ">")?;
+                }
+                writeln!(writer, "{}", line)?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Copies an attachment next to the transcript, returning its relative link.
+    fn copy(&mut self, message: &Message, attachment: &Attachment) -> Option<String> {
+        let link = self.files.copy(message, attachment)?;
+        self.summary.attachments += 1;
+        Some(link)
+    }
+}
+
+struct AttachmentCopier {
+    directory: PathBuf,
+    /// How the transcript refers to `directory`.
+    link_prefix: String,
+}
+
+impl AttachmentCopier {
+    fn copy(&self, message: &Message, attachment: &Attachment) -> Option<String> {
+        let name: String = attachment.name.chars().map(|c| if c == '/' || c == '\\' { '_' } else { c }).collect();
+        let file_name = format!("{}-{}-{}", message.channel_id.replace('/', "_"), message.id, name);
+        let copied = fs::create_dir_all(&self.directory)
+            .and_then(|_| fs::copy(&attachment.path, self.directory.join(&file_name)));
+        match copied {
+            Ok(_) => Some(format!("{}/{}", self.link_prefix, file_name)),
+            Err(e) => {
+                eprintln!("Failed to copy attachment {}: {}", attachment.path, e);
+                None
+            }
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Backslash-escapes the characters that would make text render as markup,
+/// raw HTML or links, and the `#`, `-` and `+` that would start a block at
+/// the beginning of a line.
+fn escape_markdown(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    let mut line_start = true;
+    for c in text.chars() {
+        let block = line_start && matches!(c, '#' | '-' | '+');
+        if block || matches!(c, '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '&' | '!' | '|' | '~') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        line_start = c == '\n' || (line_start && c == ' ');
+    }
+    escaped
+}
+
+/// Keeps a value on its header line.
+fn header_value(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat_backend::BackendEvent;
+
+    fn store_with_messages(attachment: &Path) -> Store {
+        let store = Store::in_memory().unwrap();
+        let channels = vec![Channel { id: "c1".to_string(), name: "Dev <Team>".to_string(), direct: false }];
+        store.record("work", &BackendEvent::ChannelList { channels }).unwrap();
+        let message = |id: u64, body: &str, attachments: Vec<Attachment>| BackendEvent::Message {
+            channel_id: "c1".to_string(),
+            message_id: id,
+            body: body.to_string(),
+            author: "Alice Doe".to_string(),
+            attachments,
+            idempotency_key: None,
+        };
+        let attachment = Attachment { name: "notes.txt".to_string(), path: attachment.to_string_lossy().into_owned() };
+        store.record("work", &message(1, "Hello\nFrom here on, <b>bold</b>", vec![attachment])).unwrap();
+        store.record("work", &message(2, "Bye", vec![])).unwrap();
+        store
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let directory = tempfile::tempdir().unwrap();
+        let attachment = directory.path().join("original.txt");
+        fs::write(&attachment, "attached").unwrap();
+        let store = store_with_messages(&attachment);
+
+        let output = directory.path().join("work.ndjson");
+        let summary = export(&store, "work", None, ExportFormat::Ndjson, &output).unwrap();
+        assert_eq!(summary, ExportSummary { messages: 2, attachments: 0 });
+        let lines: Vec<Message> =
+            fs::read_to_string(&output).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!((lines[0].id, lines[0].attachments.len(), lines[1].content.as_str()), (1, 1, "Bye"));
+
+        let output = directory.path().join("dev.html");
+        let summary = export(&store, "work", Some("c1"), ExportFormat::Html, &output).unwrap();
+        assert_eq!(summary, ExportSummary { messages: 2, attachments: 1 });
+        let html = fs::read_to_string(&output).unwrap();
+        assert!(html.contains("<h1>work / Dev &lt;Team&gt;</h1>"), "{}", html);
+        assert!(html.contains("<p>Hello<br>\nFrom here on, &lt;b&gt;bold&lt;/b&gt;</p>"), "{}", html);
+        assert!(html.contains("<a href=\"dev_files/c1-1-notes.txt\">notes.txt</a>"), "{}", html);
+        assert_eq!(fs::read_to_string(directory.path().join("dev_files/c1-1-notes.txt")).unwrap(), "attached");
+
+        let output = directory.path().join("dev.md");
+        export(&store, "work", Some("c1"), ExportFormat::Markdown, &output).unwrap();
+        let markdown = fs::read_to_string(&output).unwrap();
+        assert!(markdown.starts_with("# work / Dev \\<Team\\>\n"), "{}", markdown);
+        assert!(markdown.contains("Hello\nFrom here on, \\<b\\>bold\\</b\\>\n"), "{}", markdown);
+        assert!(markdown.contains("- [notes.txt](<dev\\_files/c1-1-notes.txt>)"), "{}", markdown);
+
+        let output = directory.path().join("dev.mbox");
+        export(&store, "work", Some("c1"), ExportFormat::Mbox, &output).unwrap();
+        let mbox = fs::read_to_string(&output).unwrap();
+        assert!(mbox.starts_with("From Alice_Doe "), "{}", mbox);
+        assert!(mbox.contains("\n>From here on"), "Body lines starting with 'From ' are quoted");
+        assert_eq!(mbox.matches("\nSubject: Dev <Team>\n").count(), 2);
+
+        assert!(matches!(
+            export(&store, "work", Some("c2"), ExportFormat::Markdown, &output),
+            Err(ExportError::UnknownChannel(_))
+        ));
+        assert!(matches!(export(&store, "home", None, ExportFormat::Markdown, &output), Err(ExportError::NothingStored(_))));
+    }
+
+    #[test]
+    fn test_markdown_escapes_markup() {
+        assert_eq!(escape_markdown("**bold** [link](x) <img src=x>"), "\\*\\*bold\\*\\* \\[link\\](x) \\<img src=x\\>");
+        assert_eq!(escape_markdown("# title\n - item\nnot-a-list"), "\\# title\n \\- item\nnot-a-list");
+    }
+
+    #[test]
+    fn test_export_path_stays_in_the_export_directory() {
+        let directory = Path::new("/exports");
+        assert_eq!(export_path(directory, "work/dev.html").unwrap(), Path::new("/exports/work/dev.html"));
+        for path in ["", "/etc/passwd", "../dev.html", "work/../../dev.html", "./dev.html"] {
+            assert!(export_path(directory, path).is_err(), "{}", path);
+        }
+    }
+}
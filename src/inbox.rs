@@ -0,0 +1,195 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::chat_backend::{BackendEvent, Channel, Message};
+use crate::event_bus::{EventBus, Subscription};
+use crate::protocol::{DaemonEvent, FrontendEvent, ServiceEvent};
+use crate::service_manager::ServiceManager;
+use crate::store::Store;
+
+/// Why a message was put in the inbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum InboxReason {
+    DirectMessage,
+    Mention,
+    /// It arrived in one of the service's inbox `folders`.
+    Folder,
+    /// A notification rule routed it there.
+    Rule,
+}
+
+impl InboxReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            InboxReason::DirectMessage => "direct_message",
+            InboxReason::Mention => "mention",
+            InboxReason::Folder => "folder",
+            InboxReason::Rule => "rule",
+        }
+    }
+
+    pub fn parse(reason: &str) -> Option<Self> {
+        [InboxReason::DirectMessage, InboxReason::Mention, InboxReason::Folder, InboxReason::Rule]
+            .into_iter()
+            .find(|known| known.as_str() == reason)
+    }
+}
+
+/// A message gathered in the inbox, with its read state.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct InboxItem {
+    /// Orders the inbox, and pages it with `fetch_inbox`'s `before`.
+    pub id: u64,
+    pub service: String,
+    pub channel_id: String,
+    pub channel_name: String,
+    pub message_id: u64,
+    pub author: String,
+    pub body: String,
+    /// When the message was sent or, if the backend does not say, received.
+    pub timestamp: u64,
+    pub reason: InboxReason,
+    pub read: bool,
+}
+
+/// The `inbox` table of a service: which of its messages go to the inbox.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InboxConfig {
+    /// Whether messages of direct conversations go to the inbox.
+    #[serde(default = "direct_messages_by_default")]
+    pub direct_messages: bool,
+    /// Names whose `@` mentions go to the inbox. The service's `username`
+    /// when empty. Messages by these names never do.
+    #[serde(default)]
+    pub identities: Vec<String>,
+    /// Channels, by id or name, whose every message goes to the inbox, such
+    /// as mail folders.
+    #[serde(default)]
+    pub folders: Vec<String>,
+}
+
+fn direct_messages_by_default() -> bool {
+    true
+}
+
+impl Default for InboxConfig {
+    fn default() -> Self {
+        Self { direct_messages: true, identities: Vec::new(), folders: Vec::new() }
+    }
+}
+
+impl InboxConfig {
+    /// Uses `username` as the identity when none is configured.
+    pub fn with_default_identity(mut self, username: Option<&str>) -> Self {
+        if self.identities.is_empty() {
+            self.identities.extend(username.map(str::to_string));
+        }
+        self
+    }
+
+    /// Why a message of `author` in a channel belongs in the inbox, if it does.
+    /// `channel` is what the service listed about the channel, if anything.
+    pub fn reason(&self, channel_id: &str, channel: Option<&Channel>, author: &str, body: &str) -> Option<InboxReason> {
+        if self.identities.iter().any(|identity| identity.eq_ignore_ascii_case(author)) {
+            return None;
+        }
+        if self.direct_messages && channel.is_some_and(|channel| channel.direct) {
+            return Some(InboxReason::DirectMessage);
+        }
+        let in_folder = |folder: &String| folder == channel_id || channel.is_some_and(|channel| &channel.name == folder);
+        if self.folders.iter().any(in_folder) {
+            return Some(InboxReason::Folder);
+        }
+        if self.identities.iter().any(|identity| mentions(body, identity)) {
+            return Some(InboxReason::Mention);
+        }
+        None
+    }
+}
+
+/// Whether `body` has `@identity` as a word of its own, ignoring case.
+pub fn mentions(body: &str, identity: &str) -> bool {
+    let (body, mention) = (body.to_lowercase(), format!("@{}", identity.to_lowercase()));
+    body.match_indices(&mention).any(|(start, _)| {
+        let before = body[..start].chars().next_back();
+        let after = body[start + mention.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(|c| c.is_alphanumeric() || c == '_')
+    })
+}
+
+/// Gathers direct messages, mentions and mails from the folders of every
+/// service into the inbox, publishing an `inbox_item` event for each. The
+/// rules are read again whenever services are added or removed.
+pub async fn collect_inbox(
+    store: Arc<Store>,
+    services: Arc<Mutex<ServiceManager>>,
+    events: EventBus,
+    mut subscription: Subscription,
+) {
+    let mut rules = services.lock().await.inbox();
+    let mut channels: HashMap<(String, String), Channel> = HashMap::new();
+    loop {
+        match subscription.next_event().await {
+            FrontendEvent::Service(ServiceEvent { service, event }) => match event {
+                BackendEvent::ChannelList { channels: listed } => {
+                    for channel in listed {
+                        channels.insert((service.clone(), channel.id.clone()), channel);
+                    }
+                }
+                BackendEvent::Message { channel_id, message_id, body, author, .. } => {
+                    let Some(config) = rules.get(&service) else {
+                        continue;
+                    };
+                    let channel = channels.get(&(service.clone(), channel_id.clone()));
+                    let Some(reason) = config.reason(&channel_id, channel, &author, &body) else {
+                        continue;
+                    };
+                    let channel_name = channel.map_or(&channel_id, |channel| &channel.name).clone();
+                    let message = Message { id: message_id, channel_id, author, content: body, timestamp: None, attachments: Vec::new() };
+                    let inboxed_service = service.clone();
+                    let added = store.blocking(move |store| store.add_to_inbox(&inboxed_service, &channel_name, &message, reason));
+                    match added.await {
+                        Ok(Some(item)) => events.publish(DaemonEvent::InboxItem { item }),
+                        Ok(None) => {}
+                        Err(e) => eprintln!("Failed to add a message of {} to the inbox: {}", service, e),
+                    }
+                }
+                _ => {}
+            },
+            FrontendEvent::Daemon(DaemonEvent::ServiceAdded { .. })
+            | FrontendEvent::Daemon(DaemonEvent::ServiceRemoved { .. }) => rules = services.lock().await.inbox(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inbox_reasons() {
+        let config: InboxConfig = toml::from_str("folders = [\"INBOX\"]").unwrap();
+        let config = config.with_default_identity(Some("alice"));
+        let room = Channel { id: "C1".to_string(), name: "general".to_string(), direct: false };
+        let dm = Channel { id: "D1".to_string(), name: "bob".to_string(), direct: true };
+        let folder = Channel { id: "f1".to_string(), name: "INBOX".to_string(), direct: false };
+
+        assert_eq!(config.reason("D1", Some(&dm), "bob", "hi"), Some(InboxReason::DirectMessage));
+        assert_eq!(config.reason("f1", Some(&folder), "bob", "hi"), Some(InboxReason::Folder));
+        assert_eq!(config.reason("C1", Some(&room), "bob", "ping @Alice, see this"), Some(InboxReason::Mention));
+        assert_eq!(config.reason("C2", None, "bob", "@alice"), Some(InboxReason::Mention));
+        assert_eq!(config.reason("C1", Some(&room), "bob", "@alicebob and mail@alice.org"), None);
+        assert_eq!(config.reason("D1", Some(&dm), "Alice", "my own message"), None);
+
+        let config = InboxConfig { direct_messages: false, ..config };
+        assert_eq!(config.reason("D1", Some(&dm), "bob", "hi"), None);
+        assert!(toml::from_str::<InboxConfig>("folder = [\"INBOX\"]").is_err());
+    }
+}
@@ -1,65 +1,385 @@
 use std::env;
-use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::collections::HashMap;
 
-use futures::StreamExt;
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
+use tokio::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 mod chat_backend;
 mod dummy_backend;
-mod config_loader; // Contains load_config_and_instantiate_backend
+mod config_loader; // Contains load_config and instantiate_service
 mod command_processor; // Contains process_command and run_command_socket
+mod protocol; // Contains FrontendCommand, DaemonEvent and the JSON schemas
+mod event_bus; // Fans events out to stdout and connected frontends
+mod remote_listener; // Serves the protocol over TCP and WebSocket
+mod cli; // Command-line argument parsing
+mod secrets; // Resolves passwords and tokens from commands, env vars and files
+mod service_manager; // Starts, stops and hot-reloads services
+mod backend_registry; // Maps `backend` keys to backend config types
+mod supervisor; // Reconnects services with exponential backoff
+mod outbox; // Queues posted messages and retries them after reconnecting
+mod store; // Records channels and messages in a local SQLite database
+mod search; // Parses search_local queries
+mod export; // Writes stored history as NDJSON, Markdown, HTML or mbox
+mod archive; // Reads Slack exports and mbox files, and serves them as a service
+mod retention; // Prunes stored history by age and count
+mod inbox; // Gathers direct messages, mentions and mail folders into one inbox
+mod notifications; // Applies notification rules and quiet hours to incoming messages
+mod desktop_notifications; // Shows notifications through org.freedesktop.Notifications
+mod bridges; // Relays messages between channels of different services
+mod scheduler; // Sends scheduled messages when they are due
+mod time; // Converts between Unix timestamps and UTC dates
 
-use chat_backend::{ChatBackend, BackendEvent};
-use config_loader::load_config_and_instantiate_backend;
-use command_processor::run_command_socket;
+use chat_backend::BackendMap;
+use event_bus::{EventBus, Subscription};
+use protocol::DaemonEvent;
+use archive::{archive_service_options, check_import_target, Archive};
+use config_loader::{insert_service_table, load_config, update_config_file};
+use export::ExportFormat;
+use cli::CliCommand;
+use command_processor::{bind_command_socket, default_socket_path, run_command_socket, CommandContext};
+use outbox::{retry_pending, Outbox};
+use remote_listener::run_remote_listener;
+use retention::run_pruner;
+use inbox::collect_inbox;
+use notifications::run_notifier;
+use desktop_notifications::run_desktop_notifier;
+use bridges::run_bridges;
+use scheduler::run_scheduler;
+use store::{record_events, Store};
+use service_manager::{spawn_config_watcher, ServiceManager};
 
-/// Streams events for a single backend instance.
-async fn stream_events(backend: Arc<Mutex<Box<dyn ChatBackend + Send + Sync>>>) {
-    // Send the initial channel list event.
-    {
-        let event = backend.lock().await.list_channels();
-        println!("{}", serde_json::to_string(&event).unwrap());
-    }
+/// How long shutdown waits for frontend sessions to finish their commands.
+const SESSION_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
 
-    let mut stream = backend.lock().await.get_messages();
-    while let Some(event) = stream.next().await {
+/// Prints every published event to stdout as a single line of JSON, until
+/// `done` is cancelled and the events published so far have been printed.
+/// Falling behind only loses the events missed meanwhile.
+async fn print_events(events: EventBus, mut subscription: Subscription, done: CancellationToken) {
+    loop {
+        let received = tokio::select! {
+            received = subscription.recv() => received,
+            _ = done.cancelled() => break,
+        };
+        match received {
+            Ok(event) => println!("{}", serde_json::to_string(&event).unwrap()),
+            Err(overflow) => {
+                eprintln!("Missed events on stdout: {}", overflow);
+                subscription = events.subscribe("stdout");
+            }
+        }
+    }
+    while let Some(event) = subscription.try_recv() {
         println!("{}", serde_json::to_string(&event).unwrap());
     }
 }
 
 #[tokio::main]
-async fn main() {
-    // --- Read configuration file path from command-line arguments ---
+async fn main() -> ExitCode {
+    // --- Parse the command line ---
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <config_file_path>", args[0]);
-        return;
+    let (config_path, socket_override) = match cli::parse_args(&args[1..]) {
+        Ok(CliCommand::Run { config_path, socket_path }) => (config_path, socket_path),
+        Ok(CliCommand::CheckConfig { config_path }) => {
+            return match config_loader::load_config(&config_path) {
+                Ok(_) => {
+                    eprintln!("{}: configuration is valid", config_path);
+                    ExitCode::SUCCESS
+                }
+                Err(errors) => {
+                    eprintln!("{}", errors);
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Ok(CliCommand::Export { config_path, format, service, channel_id, output }) => {
+            return export_from_cli(&config_path, &format, &service, channel_id.as_deref(), &output);
+        }
+        Ok(CliCommand::Import { config_path, archive, service }) => {
+            return import_from_cli(&config_path, &archive, &service);
+        }
+        Ok(CliCommand::PrintSchema(which)) => {
+            let schema = match which.as_str() {
+                "commands" => protocol::command_schema(),
+                "events" => protocol::event_schema(),
+                other => {
+                    eprintln!("Unknown schema '{}', expected 'commands' or 'events'", other);
+                    return ExitCode::FAILURE;
+                }
+            };
+            println!("{}", serde_json::to_string_pretty(&schema).unwrap());
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("{}", cli::usage(&args[0]));
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // --- Load and validate the configuration ---
+    let config = match load_config(&config_path) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let state_dir = config.daemon.state_dir();
+    let export_dir = config.daemon.export_dir();
+
+    // --- Create the command socket before connecting to anything ---
+    let socket_path = socket_override
+        .or(config.daemon.socket_path)
+        .map(PathBuf::from)
+        .unwrap_or_else(default_socket_path);
+    let (listener, socket_guard) = match bind_command_socket(&socket_path) {
+        Ok(bound) => bound,
+        Err(e) => {
+            eprintln!("Failed to create command socket {}: {}", socket_path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    eprintln!("Listening for commands on {}", socket_path.display());
+
+    // --- Shut down cleanly on SIGINT and SIGTERM rather than being killed ---
+    let (mut interrupt, mut terminate) = match (signal(SignalKind::interrupt()), signal(SignalKind::terminate())) {
+        (Ok(interrupt), Ok(terminate)) => (interrupt, terminate),
+        (Err(e), _) | (_, Err(e)) => {
+            eprintln!("Failed to install signal handlers: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    // --- Print every event on stdout ---
+    let events = EventBus::with_queue(config.daemon.events.queue_capacity, config.daemon.events.overflow);
+    let stdout_done = CancellationToken::new();
+    let printer = tokio::spawn(print_events(events.clone(), events.subscribe("stdout"), stdout_done.clone()));
+    // The daemon's own consumers keep every event, since they could not get
+    // back the ones they missed.
+    let internal = |label| events.subscribe_lossless(label);
+
+    // --- Open the outbox, retrying its messages as their services connect ---
+    let backends: BackendMap = Arc::new(Mutex::new(HashMap::new()));
+    let outbox_path = state_dir.join("outbox.json");
+    let outbox = match Outbox::open(outbox_path.clone(), backends.clone(), events.clone()) {
+        Ok(outbox) => Arc::new(outbox),
+        Err(e) => {
+            eprintln!("Failed to open the outbox {}: {}", outbox_path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    tokio::spawn(retry_pending(outbox.clone(), internal("outbox")));
+
+    // --- Record every channel and message in the local store ---
+    let store_path = state_dir.join("messages.sqlite");
+    let store = match Store::open(&store_path) {
+        Ok(store) => Arc::new(store),
+        Err(e) => {
+            eprintln!("Failed to open the message store {}: {}", store_path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    tokio::spawn(record_events(store.clone(), internal("store")));
+    // Subscribed before the services start, so that no early message is missed.
+    let inbox_events = internal("inbox");
+    let notification_events = internal("notifications");
+    let bridge_events = (!config.daemon.bridges.is_empty()).then(|| internal("bridges"));
+    let desktop_events = config.daemon.notifications.desktop.then(|| internal("desktop_notifications"));
+
+    // --- Start every enabled service, logging in and streaming events in the background ---
+    let mut manager = ServiceManager::new(backends, events.clone()).with_config_file(config_path.clone());
+    let services = manager.start_all(config.services).await;
+    events.publish(DaemonEvent::ServiceList { services });
+    let context = CommandContext::new(manager)
+        .with_outbox(outbox)
+        .with_store(store)
+        .with_export_dir(export_dir);
+    tokio::spawn(run_pruner(context.store.clone(), context.services.clone(), context.events.clone()));
+    tokio::spawn(collect_inbox(context.store.clone(), context.services.clone(), context.events.clone(), inbox_events));
+    tokio::spawn(run_notifier(
+        config.daemon.notifications,
+        context.store.clone(),
+        context.services.clone(),
+        context.events.clone(),
+        notification_events,
+    ));
+    tokio::spawn(run_scheduler(context.clone(), internal("scheduler")));
+    if let Some(subscription) = bridge_events {
+        tokio::spawn(run_bridges(config.daemon.bridges, context.clone(), subscription));
+    }
+    if let Some(subscription) = desktop_events {
+        match zbus::Connection::session().await {
+            Ok(connection) => {
+                tokio::spawn(run_desktop_notifier(connection, context.clone(), subscription));
+            }
+            Err(e) => eprintln!("Desktop notifications are unavailable: {}", e),
+        }
+    }
+
+    // --- Reload services when the configuration file changes or on SIGHUP ---
+    if let Err(e) = spawn_config_watcher(config_path, context.services.clone()) {
+        eprintln!("Failed to watch the configuration for changes: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    // --- Serve remote frontends, if configured ---
+    if let Some(remote) = config.daemon.remote {
+        if let Err(e) = run_remote_listener(remote, context.clone()).await {
+            eprintln!("Failed to start the remote listener: {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    // --- Run the Unix socket command processor until asked to stop ---
+    let mut command_socket = tokio::spawn(run_command_socket(listener, context.clone()));
+    let mut exit_code = ExitCode::SUCCESS;
+    tokio::select! {
+        _ = interrupt.recv() => eprintln!("Received SIGINT, shutting down"),
+        _ = terminate.recv() => eprintln!("Received SIGTERM, shutting down"),
+        result = &mut command_socket => {
+            if let Ok(Err(e)) = result {
+                eprintln!("Command socket {} failed: {}", socket_path.display(), e);
+            }
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+
+    shutdown(&context).await;
+    stdout_done.cancel();
+    let _ = printer.await;
+    drop(socket_guard);
+    exit_code
+}
+
+/// Exports the history stored by the daemon configured in `config_path`,
+/// without fetching what is missing from the backends.
+fn export_from_cli(config_path: &str, format: &str, service: &str, channel_id: Option<&str>, output: &str) -> ExitCode {
+    let format: ExportFormat = match format.parse() {
+        Ok(format) => format,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = match load_config(config_path) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            return ExitCode::FAILURE;
+        }
+    };
+    let store_path = config.daemon.state_dir().join("messages.sqlite");
+    let store = match Store::open(&store_path) {
+        Ok(store) => store,
+        Err(e) => {
+            eprintln!("Failed to open the message store {}: {}", store_path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match export::export(&store, service, channel_id, format, Path::new(output)) {
+        Ok(summary) => {
+            eprintln!("Exported {} messages and {} attachments to {}", summary.messages, summary.attachments, output);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Imports an archive into the store of the daemon configured in
+/// `config_path`, and adds an `archive` service reading it to the
+/// configuration file unless `service` is already configured.
+fn import_from_cli(config_path: &str, archive_path: &str, service: &str) -> ExitCode {
+    let config = match load_config(config_path) {
+        Ok(config) => config,
+        Err(errors) => {
+            eprintln!("{}", errors);
+            return ExitCode::FAILURE;
+        }
+    };
+    let archive_path = match std::fs::canonicalize(archive_path) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", archive_path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let exists = match check_import_target(service, config.services.get(service), &archive_path) {
+        Ok(exists) => exists,
+        Err(reason) => {
+            eprintln!("{}", reason);
+            return ExitCode::FAILURE;
+        }
+    };
+    let archive = match Archive::open(&archive_path) {
+        Ok(archive) => archive,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", archive_path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let store_path = config.daemon.state_dir().join("messages.sqlite");
+    if let Err(e) = Store::open(&store_path).and_then(|store| store.import(service, &archive)) {
+        eprintln!("Failed to import into the message store {}: {}", store_path.display(), e);
+        return ExitCode::FAILURE;
     }
-    let config_path = &args[1];
-
-    // --- Load configuration and instantiate all backend instances ---
-    let backend_map: HashMap<String, Arc<Mutex<Box<dyn ChatBackend + Send + Sync>>>> =
-        load_config_and_instantiate_backend(config_path).await;
-    // Wrap the map in an Arc<Mutex<>> so it can be shared across tasks.
-    let backends = Arc::new(Mutex::new(backend_map));
-
-    // --- Spawn a task to stream events for each backend ---
-    {
-        let backends_guard = backends.lock().await;
-        for (service, backend_instance) in backends_guard.iter() {
-            let service_clone = service.clone();
-            let backend_clone = backend_instance.clone();
-            tokio::spawn(async move {
-                println!("Spawning event stream for service: {}", service_clone);
-                stream_events(backend_clone).await;
-            });
+    eprintln!(
+        "Imported {} messages in {} channels into {}",
+        archive.messages.len(),
+        archive.channels.len(),
+        service
+    );
+    if !exists {
+        let options = archive_service_options(&archive_path);
+        if let Err(e) = update_config_file(config_path, |document| insert_service_table(document, service, &options)) {
+            eprintln!("Failed to add service {} to {}: {}", service, config_path, e);
+            return ExitCode::FAILURE;
         }
+        eprintln!("Added the archive service {} to {}", service, config_path);
+    }
+    ExitCode::SUCCESS
+}
+
+/// Stops the daemon in order: frontends are told with a `shutting_down`
+/// event, listeners stop accepting, sessions finish the commands they are
+/// running and flush their queued events, then every service logs out.
+async fn shutdown(context: &CommandContext) {
+    context.events.publish(DaemonEvent::ShuttingDown);
+    context.shutdown.cancel();
+    context.sessions.close();
+    if tokio::time::timeout(SESSION_DRAIN_TIMEOUT, context.sessions.wait()).await.is_err() {
+        eprintln!("Some frontend sessions did not finish in time");
     }
+    context.services.lock().await.stop_all().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    // --- Run the Unix socket command processor ---
-    let socket_path = "/tmp/chat_commands.sock";
-    run_command_socket(socket_path, backends.clone()).await;
+    #[test]
+    fn test_cli_import_only_into_archive_services() {
+        let directory = tempfile::tempdir().unwrap();
+        let config_path = directory.path().join("config.toml");
+        let state_dir = directory.path().join("state");
+        let config = format!("[daemon]\nstate_dir = {:?}\n\n[work]\nbackend = \"dummy\"\n", state_dir.to_str().unwrap());
+        std::fs::write(&config_path, config).unwrap();
+        let mbox = directory.path().join("old.mbox");
+        std::fs::write(&mbox, "From a@b Fri Mar  1 12:00:00 2024\nFrom: a@b\nSubject: hi\n\nHello\n").unwrap();
+        let (config_path, mbox) = (config_path.to_str().unwrap(), mbox.to_str().unwrap());
+
+        assert_eq!(import_from_cli(config_path, mbox, "work"), ExitCode::FAILURE);
+        assert!(!state_dir.join("messages.sqlite").exists(), "Nothing was imported");
+
+        assert_eq!(import_from_cli(config_path, mbox, "old"), ExitCode::SUCCESS);
+        assert_eq!(import_from_cli(config_path, mbox, "old"), ExitCode::SUCCESS, "The archive service may be updated");
+    }
 }
@@ -0,0 +1,329 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer, Serialize};
+use tokio::sync::Mutex;
+
+use crate::chat_backend::{BackendEvent, Channel, Message};
+use crate::event_bus::{EventBus, Subscription};
+use crate::inbox::{mentions, InboxConfig, InboxReason};
+use crate::protocol::{DaemonEvent, FrontendEvent, ServiceEvent};
+use crate::service_manager::ServiceManager;
+use crate::store::Store;
+
+/// How a frontend should bring a message to the user's attention.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationLevel {
+    /// Make the message stand out where it is shown.
+    Highlight,
+    /// Alert the user, e.g. with a desktop notification.
+    Notify,
+}
+
+/// What a rule does with the messages it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationAction {
+    Highlight,
+    Notify,
+    /// No notification at all, whatever later rules say.
+    Mute,
+    /// Add the message to the inbox.
+    Inbox,
+}
+
+/// A `[[daemon.notifications.rules]]` entry. Every condition given must
+/// hold for the rule to match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationRule {
+    /// Reported as the reason of the notifications; `rule <n>` by default.
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    service: Option<String>,
+    /// A channel id or name.
+    #[serde(default)]
+    channel: Option<String>,
+    #[serde(default)]
+    author: Option<String>,
+    /// A regular expression searched for in the body.
+    #[serde(default, deserialize_with = "deserialize_regex")]
+    body: Option<Regex>,
+    /// Whether the body must mention one of the service's inbox identities.
+    #[serde(default)]
+    mentions_me: bool,
+    actions: Vec<NotificationAction>,
+    /// Notify even during quiet hours.
+    #[serde(default)]
+    urgent: bool,
+}
+
+fn deserialize_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|pattern| Regex::new(&pattern).map_err(D::Error::custom))
+        .transpose()
+}
+
+/// A day of the week, as written in `quiet_hours`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+const WEEK: [Weekday; 7] =
+    [Weekday::Sun, Weekday::Mon, Weekday::Tue, Weekday::Wed, Weekday::Thu, Weekday::Fri, Weekday::Sat];
+
+impl Weekday {
+    fn previous(self) -> Weekday {
+        WEEK[(self as usize + 6) % 7]
+    }
+}
+
+/// A `[[daemon.notifications.quiet_hours]]` entry: from `start` to `end`,
+/// in local time, notifications only highlight. Periods may run past
+/// midnight and start on the given `days`, every day by default.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHours {
+    #[serde(deserialize_with = "deserialize_time")]
+    start: u32,
+    #[serde(deserialize_with = "deserialize_time")]
+    end: u32,
+    #[serde(default = "every_day")]
+    days: Vec<Weekday>,
+}
+
+fn every_day() -> Vec<Weekday> {
+    WEEK.to_vec()
+}
+
+/// Reads an `HH:MM` time as minutes since midnight.
+fn deserialize_time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    let parsed = time.split_once(':').and_then(|(hours, minutes)| {
+        let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+        (hours < 24 && minutes < 60 && time.len() == 5).then_some(hours * 60 + minutes)
+    });
+    parsed.ok_or_else(|| D::Error::custom(format!("invalid time '{}', expected HH:MM", time)))
+}
+
+impl QuietHours {
+    /// Whether `minute` past midnight on `day` falls in the period.
+    fn contains(&self, day: Weekday, minute: u32) -> bool {
+        if self.start <= self.end {
+            self.days.contains(&day) && (self.start..self.end).contains(&minute)
+        } else {
+            (self.days.contains(&day) && minute >= self.start)
+                || (self.days.contains(&day.previous()) && minute < self.end)
+        }
+    }
+}
+
+/// The `[daemon.notifications]` table.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NotificationConfig {
+    /// Tried in order; the first one matching a message decides.
+    #[serde(default)]
+    rules: Vec<NotificationRule>,
+    #[serde(default)]
+    quiet_hours: Vec<QuietHours>,
+    /// Whether the daemon itself shows a desktop notification for each
+    /// message to notify, even when no frontend is attached.
+    #[serde(default)]
+    pub desktop: bool,
+}
+
+/// A message streamed by a service, as the rules see it.
+pub struct Incoming<'a> {
+    pub service: &'a str,
+    pub channel_id: &'a str,
+    pub channel: Option<&'a Channel>,
+    pub author: &'a str,
+    pub body: &'a str,
+}
+
+/// What the first matching rule decided for a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    /// `None` when the message was muted or only routed to the inbox.
+    pub level: Option<NotificationLevel>,
+    pub inbox: bool,
+    pub reason: String,
+}
+
+impl NotificationConfig {
+    /// Applies the first rule matching `message`, sent at `minute` past
+    /// midnight on `day`, local time. `identities` are those of the
+    /// service's inbox, whose own messages never match.
+    pub fn decide(&self, message: &Incoming, identities: &[String], day: Weekday, minute: u32) -> Option<Decision> {
+        if identities.iter().any(|identity| identity.eq_ignore_ascii_case(message.author)) {
+            return None;
+        }
+        let (index, rule) = self.rules.iter().enumerate().find(|(_, rule)| rule.matches(message, identities))?;
+        let has = |action| rule.actions.contains(&action);
+        let level = if has(NotificationAction::Mute) {
+            None
+        } else if has(NotificationAction::Notify) {
+            let quiet = !rule.urgent && self.quiet_hours.iter().any(|period| period.contains(day, minute));
+            Some(if quiet { NotificationLevel::Highlight } else { NotificationLevel::Notify })
+        } else if has(NotificationAction::Highlight) {
+            Some(NotificationLevel::Highlight)
+        } else {
+            None
+        };
+        let reason = rule.name.clone().unwrap_or_else(|| format!("rule {}", index + 1));
+        Some(Decision { level, inbox: has(NotificationAction::Inbox), reason })
+    }
+}
+
+impl NotificationRule {
+    fn matches(&self, message: &Incoming, identities: &[String]) -> bool {
+        let channel_matches = |channel: &String| {
+            channel == message.channel_id || message.channel.is_some_and(|listed| &listed.name == channel)
+        };
+        self.service.as_ref().is_none_or(|service| service == message.service)
+            && self.channel.as_ref().is_none_or(channel_matches)
+            && self.author.as_ref().is_none_or(|author| author.eq_ignore_ascii_case(message.author))
+            && self.body.as_ref().is_none_or(|body| body.is_match(message.body))
+            && (!self.mentions_me || identities.iter().any(|identity| mentions(message.body, identity)))
+    }
+}
+
+/// The day of the week and minutes past midnight at `time`, in local time.
+fn local_time(time: SystemTime) -> (Weekday, u32) {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()) as libc::time_t;
+    // SAFETY: localtime_r only writes to the `tm` it is given.
+    let tm = unsafe {
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&seconds, &mut tm);
+        tm
+    };
+    (WEEK[tm.tm_wday.rem_euclid(7) as usize], (tm.tm_hour * 60 + tm.tm_min) as u32)
+}
+
+/// Applies the notification rules to every message streamed by the
+/// services, publishing a `notification` event for those to highlight or
+/// notify, and adding those routed there to the inbox.
+pub async fn run_notifier(
+    config: NotificationConfig,
+    store: Arc<Store>,
+    services: Arc<Mutex<ServiceManager>>,
+    events: EventBus,
+    mut subscription: Subscription,
+) {
+    let mut inboxes: HashMap<String, InboxConfig> = services.lock().await.inbox();
+    let mut channels: HashMap<(String, String), Channel> = HashMap::new();
+    loop {
+        match subscription.next_event().await {
+            FrontendEvent::Service(ServiceEvent { service, event }) => match event {
+                BackendEvent::ChannelList { channels: listed } => {
+                    for channel in listed {
+                        channels.insert((service.clone(), channel.id.clone()), channel);
+                    }
+                }
+                BackendEvent::Message { channel_id, message_id, body, author, .. } => {
+                    let channel = channels.get(&(service.clone(), channel_id.clone()));
+                    let identities = inboxes.get(&service).map_or(&[][..], |inbox| &inbox.identities);
+                    let incoming = Incoming { service: &service, channel_id: &channel_id, channel, author: &author, body: &body };
+                    let (day, minute) = local_time(SystemTime::now());
+                    let Some(decision) = config.decide(&incoming, identities, day, minute) else {
+                        continue;
+                    };
+                    if decision.inbox {
+                        let channel_name = channel.map_or(&channel_id, |channel| &channel.name).clone();
+                        let message = Message {
+                            id: message_id,
+                            channel_id: channel_id.clone(),
+                            author: author.clone(),
+                            content: body.clone(),
+                            timestamp: None,
+                            attachments: Vec::new(),
+                        };
+                        let inboxed_service = service.clone();
+                        let added = store.blocking(move |store| {
+                            store.add_to_inbox(&inboxed_service, &channel_name, &message, InboxReason::Rule)
+                        });
+                        match added.await {
+                            Ok(Some(item)) => events.publish(DaemonEvent::InboxItem { item }),
+                            Ok(None) => {}
+                            Err(e) => eprintln!("Failed to add a message of {} to the inbox: {}", service, e),
+                        }
+                    }
+                    if let Some(level) = decision.level {
+                        let reason = decision.reason;
+                        let notification = BackendEvent::Notification { channel_id, message_id, author, body, level, reason };
+                        events.publish(ServiceEvent::new(service, notification));
+                    }
+                }
+                _ => {}
+            },
+            FrontendEvent::Daemon(DaemonEvent::ServiceAdded { .. })
+            | FrontendEvent::Daemon(DaemonEvent::ServiceRemoved { .. }) => inboxes = services.lock().await.inbox(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_matching_rule_decides() {
+        let config: NotificationConfig = toml::from_str(
+            "[[quiet_hours]]\n\
+             start = \"22:00\"\n\
+             end = \"07:00\"\n\
+             days = [\"fri\"]\n\
+             [[rules]]\n\
+             author = \"spam-bot\"\n\
+             actions = [\"mute\"]\n\
+             [[rules]]\n\
+             name = \"outages\"\n\
+             channel = \"alerts\"\n\
+             body = \"(?i)down|failed\"\n\
+             actions = [\"notify\", \"inbox\"]\n\
+             urgent = true\n\
+             [[rules]]\n\
+             mentions_me = true\n\
+             actions = [\"notify\"]\n",
+        )
+        .unwrap();
+        let identities = ["me".to_string()];
+        let alerts = &Channel { id: "C9".to_string(), name: "alerts".to_string(), direct: false };
+        fn message<'a>(channel: Option<&'a Channel>, author: &'a str, body: &'a str) -> Incoming<'a> {
+            Incoming { service: "work", channel_id: "C9", channel, author, body }
+        }
+        let decide = |message: &Incoming, day, minute| config.decide(message, &identities, day, minute);
+
+        let outage = decide(&message(Some(alerts), "ci", "Deploy FAILED"), Weekday::Sat, 60).unwrap();
+        assert_eq!(outage, Decision { level: Some(NotificationLevel::Notify), inbox: true, reason: "outages".to_string() });
+        assert_eq!(decide(&message(None, "ci", "Deploy failed"), Weekday::Mon, 600), None, "The channel is not known as alerts");
+
+        let mention = message(None, "bob", "@me lunch?");
+        assert_eq!(decide(&mention, Weekday::Fri, 23 * 60).unwrap().level, Some(NotificationLevel::Highlight));
+        assert_eq!(decide(&mention, Weekday::Sat, 6 * 60).unwrap().level, Some(NotificationLevel::Highlight));
+        let daytime = decide(&mention, Weekday::Sat, 7 * 60).unwrap();
+        assert_eq!((daytime.level, daytime.reason.as_str()), (Some(NotificationLevel::Notify), "rule 3"));
+
+        assert_eq!(decide(&message(Some(alerts), "spam-bot", "@me down"), Weekday::Mon, 0).unwrap().level, None);
+        assert_eq!(decide(&message(None, "me", "@me"), Weekday::Mon, 0), None);
+
+        assert!(toml::from_str::<NotificationConfig>("[[rules]]\nbody = \"(\"\nactions = []").is_err());
+        assert!(toml::from_str::<NotificationConfig>("[[quiet_hours]]\nstart = \"25:00\"\nend = \"07:00\"").is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::chat_backend::{BackendMap, PostError};
+use crate::event_bus::{EventBus, Subscription};
+use crate::protocol::{DaemonEvent, FrontendEvent};
+use crate::supervisor::ServiceStatus;
+
+/// How often pending messages are retried when no service reconnects.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A message that still fails to send after this many attempts is given up.
+const MAX_ATTEMPTS: u32 = 10;
+
+/// Where a posted message is, as reported in `message_status` events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    /// Queued, waiting to be sent or retried.
+    Pending,
+    Sent,
+    /// Rejected by the service, or still unsent after too many attempts.
+    Failed,
+}
+
+/// One message waiting in the outbox.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct QueuedMessage {
+    service: String,
+    channel_id: String,
+    body: String,
+    local_id: String,
+    /// Made up once when the message is queued and reused on every retry.
+    idempotency_key: String,
+    attempts: u32,
+    /// Whether a send is under way, so that a retry does not race it.
+    #[serde(skip)]
+    sending: bool,
+}
+
+/// Posts messages on behalf of frontends, keeping those that could not be
+/// sent yet so they can be retried once their service reconnects. With a
+/// file, the queue survives restarts.
+pub struct Outbox {
+    path: Option<PathBuf>,
+    messages: Mutex<Vec<QueuedMessage>>,
+    backends: BackendMap,
+    events: EventBus,
+}
+
+impl Outbox {
+    /// Creates an outbox that only lasts as long as the daemon.
+    pub fn new(backends: BackendMap, events: EventBus) -> Self {
+        Self { path: None, messages: Mutex::new(Vec::new()), backends, events }
+    }
+
+    /// Creates an outbox stored in `path`, picking up the messages that
+    /// were still pending when the daemon last stopped.
+    pub fn open(path: PathBuf, backends: BackendMap, events: EventBus) -> io::Result<Self> {
+        let messages = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).map_err(io::Error::other)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path: Some(path), messages: Mutex::new(messages), backends, events })
+    }
+
+    /// Queues a message behind those still pending for the same channel and
+    /// sends the channel's queue, so that messages arrive in the order they
+    /// were posted. Returns its local id: `local_id` when given, or a new
+    /// one. Posting a message that is still queued under the same id only
+    /// retries it.
+    pub async fn post(&self, service: &str, channel_id: &str, body: &str, local_id: Option<String>) -> String {
+        self.post_with_key(service, channel_id, body, local_id, random_id()).await
+    }
+
+    /// Like `post`, but sends the message under a given idempotency key,
+    /// for callers that recognize the message by it when it comes back.
+    pub async fn post_with_key(
+        &self,
+        service: &str,
+        channel_id: &str,
+        body: &str,
+        local_id: Option<String>,
+        idempotency_key: String,
+    ) -> String {
+        let local_id = self.queue(service, channel_id, body, local_id, idempotency_key);
+        self.deliver(service, channel_id).await;
+        local_id
+    }
+
+    /// Queues a message like `post`, without sending anything yet. Once this
+    /// returns, the message is saved and survives a restart; `deliver` sends it.
+    pub fn enqueue(&self, service: &str, channel_id: &str, body: &str, local_id: Option<String>) -> String {
+        self.queue(service, channel_id, body, local_id, random_id())
+    }
+
+    fn queue(
+        &self,
+        service: &str,
+        channel_id: &str,
+        body: &str,
+        local_id: Option<String>,
+        idempotency_key: String,
+    ) -> String {
+        let local_id = local_id.unwrap_or_else(random_id);
+        let queued = {
+            let mut messages = self.messages.lock().unwrap();
+            let queued = messages.iter().any(|message| message.service == service && message.local_id == local_id);
+            if !queued {
+                messages.push(QueuedMessage {
+                    service: service.to_string(),
+                    channel_id: channel_id.to_string(),
+                    body: body.to_string(),
+                    local_id: local_id.clone(),
+                    idempotency_key,
+                    attempts: 0,
+                    sending: false,
+                });
+            }
+            queued
+        };
+        if !queued {
+            self.save();
+            self.publish(service, channel_id, &local_id, DeliveryState::Pending, None);
+        }
+        local_id
+    }
+
+    /// Retries every pending message of a service, oldest first.
+    pub async fn flush(&self, service: &str) {
+        let mut channel_ids: Vec<String> = Vec::new();
+        for message in self.messages.lock().unwrap().iter().filter(|message| message.service == service) {
+            if !channel_ids.contains(&message.channel_id) {
+                channel_ids.push(message.channel_id.clone());
+            }
+        }
+        for channel_id in channel_ids {
+            self.deliver(service, &channel_id).await;
+        }
+    }
+
+    /// The services that have messages waiting, sorted by name.
+    pub fn pending_services(&self) -> Vec<String> {
+        let mut services: Vec<String> =
+            self.messages.lock().unwrap().iter().map(|message| message.service.clone()).collect();
+        services.sort();
+        services.dedup();
+        services
+    }
+
+    /// Sends the queued messages of a channel one at a time, oldest first,
+    /// until one has to wait for a retry. Nothing is sent while the service
+    /// is not running, or while another call is already sending the
+    /// channel's queue, since that call picks up messages queued meanwhile.
+    pub async fn deliver(&self, service: &str, channel_id: &str) {
+        let Some(backend) = self.backends.lock().await.get(service).map(|configured| configured.backend.clone()) else {
+            return;
+        };
+        let is_next = |message: &QueuedMessage| message.service == service && message.channel_id == channel_id;
+        loop {
+            let message = {
+                let mut messages = self.messages.lock().unwrap();
+                match messages.iter_mut().find(|message| is_next(message)) {
+                    Some(message) if !message.sending => {
+                        message.sending = true;
+                        message.attempts += 1;
+                        message.clone()
+                    }
+                    _ => return,
+                }
+            };
+
+            let result = backend.post_message(&message.channel_id, &message.body, &message.idempotency_key).await;
+            let (state, reason) = match result {
+                Ok(()) => (DeliveryState::Sent, None),
+                Err(PostError::ConnectionError(e)) if message.attempts < MAX_ATTEMPTS => {
+                    (DeliveryState::Pending, Some(format!("Connection error: {}", e)))
+                }
+                Err(e @ PostError::ConnectionError(_)) => {
+                    (DeliveryState::Failed, Some(format!("Gave up after {} attempts: {}", message.attempts, e)))
+                }
+                Err(e) => (DeliveryState::Failed, Some(e.to_string())),
+            };
+            {
+                let mut messages = self.messages.lock().unwrap();
+                let is_sent = |queued: &QueuedMessage| queued.service == service && queued.local_id == message.local_id;
+                if state == DeliveryState::Pending {
+                    if let Some(queued) = messages.iter_mut().find(|queued| is_sent(queued)) {
+                        queued.sending = false;
+                    }
+                } else {
+                    messages.retain(|queued| !is_sent(queued));
+                }
+            }
+            self.save();
+            self.publish(service, channel_id, &message.local_id, state, reason);
+            if state == DeliveryState::Pending {
+                // Later messages wait behind this one until it is retried.
+                return;
+            }
+        }
+    }
+
+    fn publish(&self, service: &str, channel_id: &str, local_id: &str, status: DeliveryState, reason: Option<String>) {
+        self.events.publish(DaemonEvent::MessageStatus {
+            service: service.to_string(),
+            channel_id: channel_id.to_string(),
+            local_id: local_id.to_string(),
+            status,
+            reason,
+        });
+    }
+
+    /// Writes the queue to the outbox file, if there is one, replacing it
+    /// atomically so that a crash never leaves half a queue behind.
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let contents = serde_json::to_string(&*self.messages.lock().unwrap()).unwrap();
+        let saved = (|| {
+            let directory = path.parent().unwrap_or(path);
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(directory)?;
+            let mut replacement = tempfile::NamedTempFile::new_in(directory)?;
+            io::Write::write_all(&mut replacement, contents.as_bytes())?;
+            replacement.persist(path).map_err(|e| e.error)?;
+            Ok::<(), io::Error>(())
+        })();
+        if let Err(e) = saved {
+            eprintln!("Failed to save the outbox to {}: {}", path.display(), e);
+        }
+    }
+}
+
+pub fn random_id() -> String {
+    format!("{:016x}", rand::rng().random::<u64>())
+}
+
+/// Retries pending messages whenever their service (re)connects, and every
+/// `RETRY_INTERVAL` in case a send failed while the service stayed up.
+pub async fn retry_pending(outbox: Arc<Outbox>, mut subscription: Subscription) {
+    let mut ticks = tokio::time::interval_at(tokio::time::Instant::now() + RETRY_INTERVAL, RETRY_INTERVAL);
+    loop {
+        let services = tokio::select! {
+            received = subscription.next_event() => match received {
+                FrontendEvent::Daemon(DaemonEvent::ServiceStatus { service, status: ServiceStatus::Connected, .. }) => {
+                    vec![service]
+                }
+                _ => continue,
+            },
+            _ = ticks.tick() => outbox.pending_services(),
+        };
+        for service in services {
+            let outbox = outbox.clone();
+            tokio::spawn(async move { outbox.flush(&service).await });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::pin::Pin;
+
+    use async_trait::async_trait;
+    use futures::Stream;
+    use tokio::sync::Mutex as AsyncMutex;
+
+    use crate::chat_backend::{BackendEvent, Capabilities, ChatBackend, ConfiguredService, Credentials, LoginError};
+
+    /// Fails to post until `online` is set, recording the keys of the posts that got through.
+    struct OfflineBackend {
+        online: Arc<Mutex<bool>>,
+        keys: Arc<Mutex<Vec<String>>>,
+    }
+
+    #[async_trait]
+    impl ChatBackend for OfflineBackend {
+        async fn login(&self, _credentials: &Credentials) -> Result<String, LoginError> {
+            Ok("session".to_string())
+        }
+
+        fn list_channels(&self) -> BackendEvent {
+            BackendEvent::ChannelList { channels: vec![] }
+        }
+
+        fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
+            Box::pin(futures::stream::pending())
+        }
+
+        async fn post_message(&self, channel_id: &str, _content: &str, idempotency_key: &str) -> Result<(), PostError> {
+            if channel_id == "forbidden" {
+                return Err(PostError::PermissionDenied);
+            }
+            if !*self.online.lock().unwrap() {
+                return Err(PostError::ConnectionError("offline".to_string()));
+            }
+            self.keys.lock().unwrap().push(idempotency_key.to_string());
+            Ok(())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+    }
+
+    fn backends(online: &Arc<Mutex<bool>>, keys: &Arc<Mutex<Vec<String>>>) -> BackendMap {
+        let backend = OfflineBackend { online: online.clone(), keys: keys.clone() };
+        let services = HashMap::from([("chat".to_string(), ConfiguredService::new("offline", Box::new(backend)))]);
+        Arc::new(AsyncMutex::new(services))
+    }
+
+    async fn next_status(subscription: &mut Subscription) -> (String, DeliveryState, Option<String>) {
+        loop {
+            if let FrontendEvent::Daemon(DaemonEvent::MessageStatus { local_id, status, reason, .. }) =
+                subscription.recv().await.unwrap()
+            {
+                return (local_id, status, reason);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retries_when_service_reconnects() {
+        let (online, keys) = (Arc::new(Mutex::new(false)), Arc::new(Mutex::new(Vec::new())));
+        let events = EventBus::new();
+        let mut statuses = events.subscribe("test");
+        let outbox = Arc::new(Outbox::new(backends(&online, &keys), events.clone()));
+        tokio::spawn(retry_pending(outbox.clone(), events.subscribe_lossless("outbox")));
+
+        let local_id = outbox.post("chat", "general", "hi", Some("m1".to_string())).await;
+        assert_eq!(local_id, "m1");
+        assert_eq!(next_status(&mut statuses).await, ("m1".to_string(), DeliveryState::Pending, None));
+        let (_, state, reason) = next_status(&mut statuses).await;
+        assert_eq!((state, reason.as_deref()), (DeliveryState::Pending, Some("Connection error: offline")));
+
+        *online.lock().unwrap() = true;
+        events.publish(DaemonEvent::ServiceStatus {
+            service: "chat".to_string(),
+            status: ServiceStatus::Connected,
+            reason: None,
+            retry_in_ms: None,
+        });
+        assert_eq!(next_status(&mut statuses).await, ("m1".to_string(), DeliveryState::Sent, None));
+        assert!(outbox.pending_services().is_empty());
+
+        outbox.post("chat", "forbidden", "hi", Some("m2".to_string())).await;
+        next_status(&mut statuses).await;
+        let (_, state, reason) = next_status(&mut statuses).await;
+        assert_eq!((state, reason.as_deref()), (DeliveryState::Failed, Some("Permission denied")));
+    }
+
+    #[tokio::test]
+    async fn test_messages_of_a_channel_are_sent_in_order() {
+        let (online, keys) = (Arc::new(Mutex::new(false)), Arc::new(Mutex::new(Vec::new())));
+        let outbox = Outbox::new(backends(&online, &keys), EventBus::new());
+        outbox.post("chat", "general", "first", Some("m1".to_string())).await;
+        outbox.post("chat", "general", "second", Some("m2".to_string())).await;
+        let key = |local_id: &str| {
+            let messages = outbox.messages.lock().unwrap();
+            messages.iter().find(|message| message.local_id == local_id).unwrap().idempotency_key.clone()
+        };
+        let (first, second) = (key("m1"), key("m2"));
+        assert_eq!(outbox.messages.lock().unwrap()[1].attempts, 0, "A message waits behind the pending one");
+
+        *online.lock().unwrap() = true;
+        outbox.post("chat", "general", "third", Some("m3".to_string())).await;
+        let sent = keys.lock().unwrap().clone();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[..2], [first, second]);
+        assert!(outbox.pending_services().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pending_messages_survive_restart() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("state/outbox.json");
+        let (online, keys) = (Arc::new(Mutex::new(false)), Arc::new(Mutex::new(Vec::new())));
+
+        let outbox = Outbox::open(path.clone(), backends(&online, &keys), EventBus::new()).unwrap();
+        outbox.post("chat", "general", "hi", None).await;
+        let key = outbox.messages.lock().unwrap()[0].idempotency_key.clone();
+        drop(outbox);
+
+        *online.lock().unwrap() = true;
+        let outbox = Outbox::open(path.clone(), backends(&online, &keys), EventBus::new()).unwrap();
+        assert_eq!(outbox.pending_services(), ["chat"]);
+        outbox.flush("chat").await;
+        assert!(outbox.pending_services().is_empty());
+        assert_eq!(*keys.lock().unwrap(), [key], "A retry reuses the message's idempotency key");
+
+        let reopened = Outbox::open(path, backends(&online, &keys), EventBus::new()).unwrap();
+        assert!(reopened.pending_services().is_empty());
+    }
+}
@@ -0,0 +1,567 @@
+use schemars::{schema_for, JsonSchema, Schema};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use std::collections::HashMap;
+
+use crate::backend_registry::BackendKind;
+use crate::chat_backend::{BackendEvent, Capabilities, ConfiguredService, Message};
+use crate::event_bus::SubscriberStats;
+use crate::export::ExportFormat;
+use crate::inbox::InboxItem;
+use crate::outbox::DeliveryState;
+use crate::retention::PrunedChannel;
+use crate::scheduler::{ScheduledMessage, SendAt};
+use crate::search::SearchHit;
+use crate::supervisor::ServiceStatus;
+
+/// Version of the command/event protocol, reported in the `hello` handshake.
+/// Bump it whenever a change would break existing frontends.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Commands that frontends send to the backend, deserialized from JSON.
+/// Mirrors `BackendEvent`: the `#[serde(tag = "command")]` attribute means
+/// that each command carries a `"command"` field naming the variant.
+#[derive(Debug, Clone, PartialEq, Deserialize, JsonSchema)]
+#[serde(tag = "command")]
+pub enum FrontendCommand {
+    /// Queues a message in the outbox and sends it. Its progress is reported
+    /// in `message_status` events carrying `local_id`, which the daemon
+    /// makes up when the frontend does not supply one.
+    #[serde(rename = "post_message")]
+    PostMessage {
+        service: String,
+        channel_id: String,
+        body: String,
+        #[serde(default)]
+        local_id: Option<String>,
+    },
+    /// Keeps a message to be posted later, even across restarts. It is
+    /// then sent like a `post_message` and reported with a
+    /// `scheduled_message_sent` or `scheduled_message_failed` event.
+    #[serde(rename = "schedule_message")]
+    ScheduleMessage { service: String, channel_id: String, body: String, send_at: SendAt },
+    /// Asks for every message waiting to be sent. Answered with a
+    /// `scheduled_messages` event sent to the requesting frontend only.
+    #[serde(rename = "list_scheduled_messages")]
+    ListScheduledMessages,
+    #[serde(rename = "cancel_scheduled_message")]
+    CancelScheduledMessage { id: u64 },
+    #[serde(rename = "leave_channel")]
+    LeaveChannel { service: String, channel_id: String },
+    /// Asks for up to `limit` messages of a channel older than message
+    /// `before`, or the latest ones. Answered with a `history` event sent
+    /// to the requesting frontend only.
+    #[serde(rename = "fetch_history")]
+    FetchHistory {
+        service: String,
+        channel_id: String,
+        #[serde(default)]
+        before: Option<u64>,
+        #[serde(default = "default_history_limit")]
+        limit: usize,
+    },
+    /// Authenticates a remote session; must be its first command.
+    #[serde(rename = "auth")]
+    Auth { token: String },
+    /// Searches the messages of every service in the local store. Answered
+    /// with a `search_results` event sent to the requesting frontend only.
+    /// Matched words are wrapped in `highlight_start` and `highlight_end`.
+    #[serde(rename = "search_local")]
+    SearchLocal {
+        query: String,
+        #[serde(default = "default_search_limit")]
+        limit: usize,
+        #[serde(default = "default_highlight_start")]
+        highlight_start: String,
+        #[serde(default = "default_highlight_end")]
+        highlight_end: String,
+    },
+    /// Writes the history of a channel, or of every channel of a service,
+    /// to `path`, relative to the daemon's export directory. When the service
+    /// is running, missing history is fetched first. Answered with an
+    /// `export_finished` event, giving the full path, sent to the requesting
+    /// frontend only.
+    #[serde(rename = "export")]
+    Export {
+        service: String,
+        #[serde(default)]
+        channel_id: Option<String>,
+        format: ExportFormat,
+        path: String,
+    },
+    /// Imports a Slack export (`.zip`) or mbox file at `path` on the daemon's
+    /// host into the store, under `service`. Unless that service exists, an
+    /// `archive` service reading the file is added, and written to the
+    /// configuration file with `persist`. Answered with an
+    /// `archive_imported` event sent to the requesting frontend only.
+    #[serde(rename = "import_archive")]
+    ImportArchive {
+        service: String,
+        path: String,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Stars or unstars a stored message. Starred messages are kept by
+    /// retention rules unless they set `keep_starred = false`.
+    #[serde(rename = "star_message")]
+    StarMessage {
+        service: String,
+        channel_id: String,
+        message_id: u64,
+        #[serde(default = "default_starred")]
+        starred: bool,
+    },
+    /// Applies the retention rules of every service now. With `dry_run`,
+    /// nothing is removed and the `storage_pruned` event listing what would
+    /// be is sent to the requesting frontend only.
+    #[serde(rename = "prune_storage")]
+    PruneStorage {
+        #[serde(default)]
+        dry_run: bool,
+    },
+    /// Asks for up to `limit` inbox items older than item `before`, or the
+    /// latest ones, and with `unread_only` only those not read yet. Answered
+    /// with an `inbox` event sent to the requesting frontend only.
+    #[serde(rename = "fetch_inbox")]
+    FetchInbox {
+        #[serde(default)]
+        before: Option<u64>,
+        #[serde(default = "default_history_limit")]
+        limit: usize,
+        #[serde(default)]
+        unread_only: bool,
+    },
+    /// Marks a channel as read up to message `message_id`, in the inbox and,
+    /// when the service is running, on its server.
+    #[serde(rename = "mark_read")]
+    MarkRead { service: String, channel_id: String, message_id: u64 },
+    /// Starts a new service. `config` holds the keys of its service table,
+    /// as in the configuration file. With `persist`, the table is also
+    /// written to the file; otherwise the change lasts until the next reload.
+    #[serde(rename = "add_service")]
+    AddService {
+        service: String,
+        config: serde_json::Map<String, serde_json::Value>,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Stops a service and forgets its configuration.
+    #[serde(rename = "remove_service")]
+    RemoveService {
+        service: String,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Starts a disabled service.
+    #[serde(rename = "enable_service")]
+    EnableService {
+        service: String,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Stops a service but keeps its configuration, so it can be enabled again.
+    #[serde(rename = "disable_service")]
+    DisableService {
+        service: String,
+        #[serde(default)]
+        persist: bool,
+    },
+    /// Asks for every configured service, enabled or not. Answered with a
+    /// `service_list` event sent to the requesting frontend only.
+    #[serde(rename = "list_services")]
+    ListServices,
+    /// Asks how far behind every event subscriber is. Answered with a
+    /// `subscriber_stats` event sent to the requesting frontend only.
+    #[serde(rename = "subscriber_stats")]
+    SubscriberStats,
+}
+
+/// How many messages `fetch_history` returns unless told otherwise.
+pub const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+fn default_history_limit() -> usize {
+    DEFAULT_HISTORY_LIMIT
+}
+
+/// How many hits `search_local` returns unless told otherwise.
+pub const DEFAULT_SEARCH_LIMIT: usize = 20;
+
+fn default_search_limit() -> usize {
+    DEFAULT_SEARCH_LIMIT
+}
+
+fn default_starred() -> bool {
+    true
+}
+
+fn default_highlight_start() -> String {
+    "<mark>".to_string()
+}
+
+fn default_highlight_end() -> String {
+    "</mark>".to_string()
+}
+
+impl FrontendCommand {
+    /// Whether the command waits on a backend, e.g. a network round trip,
+    /// rather than only touching the daemon's own state.
+    pub fn service_io(&self) -> bool {
+        matches!(
+            self,
+            FrontendCommand::PostMessage { .. }
+                | FrontendCommand::LeaveChannel { .. }
+                | FrontendCommand::FetchHistory { .. }
+                | FrontendCommand::Export { .. }
+                | FrontendCommand::MarkRead { .. }
+        )
+    }
+}
+
+/// An event emitted by a backend, tagged with the service it came from.
+/// The service name is assigned by the daemon when it forwards the backend's
+/// event stream, so backends cannot misreport it.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServiceEvent {
+    pub service: String,
+    #[serde(flatten)]
+    pub event: BackendEvent,
+}
+
+impl ServiceEvent {
+    pub fn new(service: impl Into<String>, event: BackendEvent) -> Self {
+        Self { service: service.into(), event }
+    }
+}
+
+/// Describes one configured service.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+pub struct ServiceInfo {
+    pub name: String,
+    /// The backend kind from the service's `backend` key, e.g. `"dummy"`.
+    pub backend: String,
+    pub capabilities: Capabilities,
+    /// Disabled services are configured but not running.
+    pub enabled: bool,
+}
+
+impl ServiceInfo {
+    /// Describes a running service.
+    pub fn new(name: &str, service: &ConfiguredService) -> Self {
+        ServiceInfo {
+            name: name.to_string(),
+            backend: service.backend_kind.clone(),
+            capabilities: service.capabilities,
+            enabled: true,
+        }
+    }
+
+    /// Describes a service that is configured but not running.
+    pub fn disabled(name: &str, backend: &BackendKind) -> Self {
+        ServiceInfo {
+            name: name.to_string(),
+            backend: backend.name.to_string(),
+            capabilities: backend.capabilities(),
+            enabled: false,
+        }
+    }
+}
+
+/// Describes every configured service, sorted by name.
+pub fn service_infos(services: &HashMap<String, ConfiguredService>) -> Vec<ServiceInfo> {
+    let mut infos: Vec<ServiceInfo> =
+        services.iter().map(|(name, service)| ServiceInfo::new(name, service)).collect();
+    infos.sort_by(|a, b| a.name.cmp(&b.name));
+    infos
+}
+
+/// Events emitted by the daemon itself rather than by a chat backend.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(tag = "event")]
+pub enum DaemonEvent {
+    /// Sent first on every new connection.
+    #[serde(rename = "hello")]
+    Hello { protocol_version: u32, services: Vec<ServiceInfo> },
+    /// A command could not be parsed or executed. `field` names the
+    /// offending command field when the failure can be pinned to one.
+    #[serde(rename = "command_error")]
+    CommandError {
+        reason: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        field: Option<String>,
+    },
+    /// The services configured in this daemon.
+    #[serde(rename = "service_list")]
+    ServiceList { services: Vec<ServiceInfo> },
+    /// A service was started, after a configuration reload or by a command
+    /// such as `add_service` or `enable_service`. A service whose options
+    /// changed is reported as removed, then added again.
+    #[serde(rename = "service_added")]
+    ServiceAdded { service: ServiceInfo },
+    /// A service was stopped, after a configuration reload or by a command
+    /// such as `remove_service` or `disable_service`.
+    #[serde(rename = "service_removed")]
+    ServiceRemoved { service: String },
+    /// The event queue of every frontend, and of stdout.
+    #[serde(rename = "subscriber_stats")]
+    SubscriberStats { subscribers: Vec<SubscriberStats> },
+    /// This frontend fell too far behind and is being disconnected.
+    #[serde(rename = "queue_overflow")]
+    QueueOverflow { reason: String },
+    /// `count` streamed messages of a channel were dropped because this
+    /// frontend fell behind. `fetch_history` returns them.
+    #[serde(rename = "messages_dropped")]
+    MessagesDropped { service: String, channel_id: String, count: u64 },
+    /// The daemon is shutting down. It is the last event sent on every
+    /// connection before it is closed.
+    #[serde(rename = "shutting_down")]
+    ShuttingDown,
+    /// The connection state of a service changed. `retry_in_ms` is set when
+    /// a reconnection attempt is scheduled.
+    #[serde(rename = "service_status")]
+    ServiceStatus {
+        service: String,
+        status: ServiceStatus,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        retry_in_ms: Option<u64>,
+    },
+    /// Messages of a channel, oldest first. `complete` is false when the
+    /// backend could not fill a gap in the local store, so some may be missing.
+    #[serde(rename = "history")]
+    History { service: String, channel_id: String, messages: Vec<Message>, complete: bool },
+    /// The hits of a `search_local` query, best first.
+    #[serde(rename = "search_results")]
+    SearchResults { query: String, hits: Vec<SearchHit> },
+    /// An `export` was written.
+    #[serde(rename = "export_finished")]
+    ExportFinished { service: String, path: String, messages: usize, attachments: usize },
+    /// An `import_archive` was stored.
+    #[serde(rename = "archive_imported")]
+    ArchiveImported { service: String, channels: usize, messages: usize },
+    /// Retention rules removed messages or attachments from the store, or
+    /// would have, for a `dry_run`. Only the channels affected are listed.
+    #[serde(rename = "storage_pruned")]
+    StoragePruned { dry_run: bool, channels: Vec<PrunedChannel> },
+    /// A page of the inbox, newest first, with the number of unread items
+    /// in the whole inbox.
+    #[serde(rename = "inbox")]
+    Inbox { items: Vec<InboxItem>, unread: usize },
+    /// A direct message, mention or mail was added to the inbox.
+    #[serde(rename = "inbox_item")]
+    InboxItem { item: InboxItem },
+    /// A channel was marked as read up to message `message_id`, and so were
+    /// its inbox items.
+    #[serde(rename = "inbox_read")]
+    InboxRead { service: String, channel_id: String, message_id: u64 },
+    /// A message was scheduled with `schedule_message`.
+    #[serde(rename = "message_scheduled")]
+    MessageScheduled { message: ScheduledMessage },
+    /// Every message waiting to be sent, soonest first.
+    #[serde(rename = "scheduled_messages")]
+    ScheduledMessages { messages: Vec<ScheduledMessage> },
+    #[serde(rename = "scheduled_message_cancelled")]
+    ScheduledMessageCancelled { id: u64 },
+    /// A scheduled message was posted. Its `message_status` events carry
+    /// the `local_id` `scheduled-<id>`.
+    #[serde(rename = "scheduled_message_sent")]
+    ScheduledMessageSent { id: u64, service: String, channel_id: String },
+    /// A scheduled message could not be posted.
+    #[serde(rename = "scheduled_message_failed")]
+    ScheduledMessageFailed { id: u64, service: String, channel_id: String, reason: String },
+    /// A posted message was queued, sent, or given up on. A message that
+    /// could not be sent yet is reported as `pending` again, with the
+    /// `reason`, and retried when its service reconnects.
+    #[serde(rename = "message_status")]
+    MessageStatus {
+        service: String,
+        channel_id: String,
+        local_id: String,
+        status: DeliveryState,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reason: Option<String>,
+    },
+}
+
+impl DaemonEvent {
+    pub fn command_error(reason: impl Into<String>, field: Option<String>) -> Self {
+        DaemonEvent::CommandError { reason: reason.into(), field }
+    }
+}
+
+/// Everything a frontend may receive, as a single schema-able type.
+#[derive(Debug, Clone, Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum FrontendEvent {
+    Service(ServiceEvent),
+    Daemon(DaemonEvent),
+}
+
+impl FrontendEvent {
+    /// Whether a slow frontend must receive this event, like replies,
+    /// errors and delivery statuses. Others may be dropped when the frontend
+    /// falls behind: `service_status` is superseded by later events of the
+    /// same kind, and streamed messages can be fetched again with
+    /// `fetch_history` once `messages_dropped` reports them.
+    pub fn is_critical(&self) -> bool {
+        !matches!(
+            self,
+            FrontendEvent::Daemon(DaemonEvent::ServiceStatus { .. })
+                | FrontendEvent::Service(ServiceEvent { event: BackendEvent::Message { .. }, .. })
+        )
+    }
+}
+
+impl From<ServiceEvent> for FrontendEvent {
+    fn from(event: ServiceEvent) -> Self {
+        FrontendEvent::Service(event)
+    }
+}
+
+impl From<DaemonEvent> for FrontendEvent {
+    fn from(event: DaemonEvent) -> Self {
+        FrontendEvent::Daemon(event)
+    }
+}
+
+/// A command that failed to deserialize, with the field it failed on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandParseError {
+    pub reason: String,
+    pub field: Option<String>,
+}
+
+impl From<CommandParseError> for DaemonEvent {
+    fn from(err: CommandParseError) -> Self {
+        DaemonEvent::command_error(err.reason, err.field)
+    }
+}
+
+/// Parses a single JSON command, reporting which field was at fault on failure.
+pub fn parse_command(input: &str) -> Result<FrontendCommand, CommandParseError> {
+    let value: Value = serde_json::from_str(input).map_err(|e| CommandParseError {
+        reason: format!("invalid JSON: {}", e),
+        field: None,
+    })?;
+    serde_json::from_value(value.clone()).map_err(|e| {
+        let reason = e.to_string();
+        let field = quoted_field(&reason).or_else(|| locate_offending_field(&value));
+        CommandParseError { reason, field }
+    })
+}
+
+/// Extracts the field name from serde's "missing field `x`" style messages.
+fn quoted_field(reason: &str) -> Option<String> {
+    if reason.starts_with("unknown variant") {
+        return Some("command".to_string());
+    }
+    if !reason.starts_with("missing field") && !reason.starts_with("unknown field") {
+        return None;
+    }
+    reason.split('`').nth(1).map(str::to_string)
+}
+
+/// Internally tagged enums buffer their input, so serde cannot tell us which
+/// field had the wrong type. Find it by dropping one field at a time: the
+/// offender is the one whose removal turns the error into "missing field".
+fn locate_offending_field(value: &Value) -> Option<String> {
+    let object = value.as_object()?;
+    object.keys().filter(|key| *key != "command").find_map(|key| {
+        let mut probe = object.clone();
+        probe.remove(key);
+        match serde_json::from_value::<FrontendCommand>(Value::Object(probe)) {
+            Err(e) if e.to_string() == format!("missing field `{}`", key) => Some(key.clone()),
+            _ => None,
+        }
+    })
+}
+
+/// JSON Schema describing every command a frontend may send.
+pub fn command_schema() -> Schema {
+    schema_for!(FrontendCommand)
+}
+
+/// JSON Schema describing every event a frontend may receive.
+pub fn event_schema() -> Schema {
+    schema_for!(FrontendEvent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_post_message() {
+        let cmd = parse_command(
+            r#"{"command": "post_message", "service": "s", "channel_id": "c", "body": "hi"}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            cmd,
+            FrontendCommand::PostMessage {
+                service: "s".to_string(),
+                channel_id: "c".to_string(),
+                body: "hi".to_string(),
+                local_id: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_reports_missing_field() {
+        let err = parse_command(r#"{"command": "post_message", "service": "s", "body": "hi"}"#)
+            .unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("channel_id"));
+    }
+
+    #[test]
+    fn test_parse_reports_wrongly_typed_field() {
+        let err = parse_command(
+            r#"{"command": "post_message", "service": "s", "channel_id": 5, "body": "hi"}"#,
+        )
+        .unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("channel_id"));
+    }
+
+    #[test]
+    fn test_parse_reports_unknown_command() {
+        let err = parse_command(r#"{"command": "dance", "service": "s"}"#).unwrap_err();
+        assert_eq!(err.field.as_deref(), Some("command"));
+    }
+
+    #[test]
+    fn test_service_event_carries_service_name() {
+        let event = ServiceEvent::new(
+            "work",
+            BackendEvent::Message {
+                channel_id: "c".to_string(),
+                message_id: 1,
+                body: "hi".to_string(),
+                author: "me".to_string(),
+                attachments: Vec::new(),
+                idempotency_key: None,
+            },
+        );
+        let json = serde_json::to_value(FrontendEvent::from(event)).unwrap();
+        assert_eq!(json["service"], "work");
+        assert_eq!(json["event"], "message");
+        assert_eq!(json["channel_id"], "c");
+    }
+
+    #[test]
+    fn test_published_schemas_are_up_to_date() {
+        let commands = serde_json::to_string_pretty(&command_schema()).unwrap() + "\n";
+        let events = serde_json::to_string_pretty(&event_schema()).unwrap() + "\n";
+        assert_eq!(
+            commands,
+            include_str!("../schema/commands.json"),
+            "schema/commands.json is stale, regenerate it with --print-schema commands"
+        );
+        assert_eq!(
+            events,
+            include_str!("../schema/events.json"),
+            "schema/events.json is stale, regenerate it with --print-schema events"
+        );
+    }
+}
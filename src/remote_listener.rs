@@ -0,0 +1,273 @@
+use std::future::ready;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message as WsMessage};
+
+use crate::command_processor::{line_transport, serve_session, CommandContext, MAX_COMMAND_LENGTH};
+use crate::config_loader::RemoteConfig;
+
+/// How long the TLS and WebSocket handshakes of a connection may take.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How frames are carried over an accepted TCP connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Newline-delimited JSON, exactly like the Unix socket.
+    Lines,
+    /// One JSON object per WebSocket text message.
+    WebSocket,
+}
+
+/// Binds the listeners configured in `[daemon.remote]` and serves remote
+/// frontends on them in the background. Every session must authenticate
+/// with the configured token, read once here, before it receives anything.
+pub async fn run_remote_listener(config: RemoteConfig, context: CommandContext) -> io::Result<()> {
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => Some(load_tls_acceptor(cert, key)?),
+        (None, None) => None,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tls_cert and tls_key must be given together",
+            ))
+        }
+    };
+    let token = config.token.resolve().await.map_err(|e| io::Error::other(format!("Remote token: {}", e)))?;
+    if token.expose().is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the remote token is empty"));
+    }
+    let token: Arc<str> = token.expose().into();
+
+    for (address, transport) in [(&config.tcp, Transport::Lines), (&config.websocket, Transport::WebSocket)] {
+        if let Some(address) = address {
+            let listener = TcpListener::bind(address).await?;
+            eprintln!("Listening for {:?} frontends on {} (TLS: {})", transport, address, tls.is_some());
+            tokio::spawn(accept_loop(listener, transport, tls.clone(), token.clone(), context.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Builds a TLS acceptor from PEM encoded certificate chain and private key files.
+pub fn load_tls_acceptor(cert_path: &str, key_path: &str) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", cert_path, e)))?;
+    let key = PrivateKeyDer::from_pem_file(key_path)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", key_path, e)))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts connections until the daemon shuts down, serving each one in its own task.
+pub async fn accept_loop(
+    listener: TcpListener,
+    transport: Transport,
+    tls: Option<TlsAcceptor>,
+    token: Arc<str>,
+    context: CommandContext,
+) {
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = context.shutdown.cancelled() => return,
+        };
+        let (stream, peer) = match accepted {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Failed to accept remote connection: {}", e);
+                continue;
+            }
+        };
+        let label = match transport {
+            Transport::Lines => format!("tcp {}", peer),
+            Transport::WebSocket => format!("websocket {}", peer),
+        };
+        let tls = tls.clone();
+        let token = token.clone();
+        let context = context.clone();
+        context.sessions.clone().spawn(async move {
+            match tls {
+                Some(acceptor) => match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => serve_connection(stream, transport, &token, context, &label).await,
+                    Ok(Err(e)) => eprintln!("TLS handshake with {} failed: {}", peer, e),
+                    Err(_) => eprintln!("TLS handshake with {} timed out", peer),
+                },
+                None => serve_connection(stream, transport, &token, context, &label).await,
+            }
+        });
+    }
+}
+
+/// Runs a frontend session over an established (and possibly encrypted) stream.
+async fn serve_connection<S>(stream: S, transport: Transport, token: &str, context: CommandContext, label: &str)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    match transport {
+        Transport::Lines => {
+            let (input, output) = line_transport(stream);
+            serve_session(input, output, context, Some(token), label).await;
+        }
+        Transport::WebSocket => {
+            let config = WebSocketConfig::default()
+                .max_message_size(Some(MAX_COMMAND_LENGTH))
+                .max_frame_size(Some(MAX_COMMAND_LENGTH));
+            let handshake = tokio_tungstenite::accept_async_with_config(stream, Some(config));
+            let websocket = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                Ok(Ok(websocket)) => websocket,
+                Ok(Err(e)) => {
+                    eprintln!("WebSocket handshake failed: {}", e);
+                    return;
+                }
+                Err(_) => {
+                    eprintln!("WebSocket handshake of {} timed out", label);
+                    return;
+                }
+            };
+            let (sink, stream) = websocket.split();
+            let input = stream
+                .take_while(|frame| ready(!matches!(frame, Ok(WsMessage::Close(_)))))
+                .filter_map(|frame| {
+                    ready(match frame {
+                        Ok(WsMessage::Text(text)) => Some(Ok(text.to_string())),
+                        Ok(WsMessage::Binary(data)) => Some(String::from_utf8(data.to_vec()).map_err(io::Error::other)),
+                        Ok(_) => None,
+                        Err(e) => Some(Err(io::Error::other(e))),
+                    })
+                });
+            let output = sink
+                .with(|line: String| ready(Ok::<_, WsError>(WsMessage::text(line))))
+                .sink_map_err(io::Error::other);
+            serve_session(input, output, context, Some(token), label).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::sync::Mutex;
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+
+    use crate::event_bus::EventBus;
+    use crate::secrets::SecretSource;
+    use crate::service_manager::ServiceManager;
+
+    fn empty_context() -> CommandContext {
+        CommandContext::new(ServiceManager::new(Arc::new(Mutex::new(HashMap::new())), EventBus::new()))
+    }
+
+    async fn spawn_listener(transport: Transport, tls: Option<TlsAcceptor>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(accept_loop(listener, transport, tls, "secret".into(), empty_context()));
+        address
+    }
+
+    #[tokio::test]
+    async fn test_tcp_session_requires_token() {
+        let address = spawn_listener(Transport::Lines, None).await;
+
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"{\"command\": \"auth\", \"token\": \"wrong\"}\n").await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let reply: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(reply["event"], "command_error");
+        assert_eq!(reply["field"], "token");
+        assert!(lines.next_line().await.unwrap().is_none(), "Connection should be closed");
+
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer.write_all(b"{\"command\": \"auth\", \"token\": \"secret\"}\n").await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let reply: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(reply["event"], "hello");
+    }
+
+    #[tokio::test]
+    async fn test_overlong_command_closes_the_connection() {
+        let address = spawn_listener(Transport::Lines, None).await;
+
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        // The daemon may hang up before reading it all.
+        tokio::spawn(async move { writer.write_all(&vec![b'a'; MAX_COMMAND_LENGTH + 2]).await });
+        let mut lines = BufReader::new(reader).lines();
+        let reply = tokio::time::timeout(Duration::from_secs(5), lines.next_line()).await.unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&reply.unwrap().unwrap()).unwrap();
+        assert_eq!(reply["event"], "command_error");
+        // Unread input makes the close a reset.
+        assert!(!matches!(lines.next_line().await, Ok(Some(_))), "Connection should be closed");
+    }
+
+    #[tokio::test]
+    async fn test_empty_token_is_refused() {
+        std::env::set_var("KBUNIFIED_TEST_EMPTY_REMOTE_TOKEN", "");
+        let config = RemoteConfig {
+            tcp: Some("127.0.0.1:0".to_string()),
+            websocket: None,
+            token: SecretSource::Env("KBUNIFIED_TEST_EMPTY_REMOTE_TOKEN".to_string()),
+            tls_cert: None,
+            tls_key: None,
+        };
+        assert!(run_remote_listener(config, empty_context()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_websocket_session() {
+        let address = spawn_listener(Transport::WebSocket, None).await;
+
+        let (mut websocket, _) = tokio_tungstenite::connect_async(format!("ws://{}", address)).await.unwrap();
+        websocket
+            .send(WsMessage::text(r#"{"command": "auth", "token": "secret"}"#))
+            .await
+            .unwrap();
+        let reply = websocket.next().await.unwrap().unwrap();
+        let reply: serde_json::Value = serde_json::from_str(reply.to_text().unwrap()).unwrap();
+        assert_eq!(reply["event"], "hello");
+    }
+
+    #[tokio::test]
+    async fn test_tls_session() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let cert_path = dir.path().join("cert.pem");
+        let key_path = dir.path().join("key.pem");
+        std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+        std::fs::write(&key_path, certified.signing_key.serialize_pem()).unwrap();
+        let acceptor = load_tls_acceptor(cert_path.to_str().unwrap(), key_path.to_str().unwrap()).unwrap();
+        let address = spawn_listener(Transport::Lines, Some(acceptor)).await;
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certified.cert.der().clone()).unwrap();
+        let client_config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let stream = TcpStream::connect(&address).await.unwrap();
+        let stream = connector.connect("localhost".try_into().unwrap(), stream).await.unwrap();
+
+        let (reader, mut writer) = tokio::io::split(stream);
+        writer.write_all(b"{\"command\": \"auth\", \"token\": \"secret\"}\n").await.unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let reply: serde_json::Value = serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(reply["event"], "hello");
+    }
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::chat_backend::Channel;
+use crate::event_bus::EventBus;
+use crate::protocol::DaemonEvent;
+use crate::service_manager::ServiceManager;
+use crate::store::{Store, StoreError};
+
+/// How often the retention rules are applied.
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Retention rules for one channel, or the defaults of a service.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionRules {
+    /// Messages older than this many days are removed.
+    #[serde(default)]
+    pub max_age_days: Option<u64>,
+    /// Only the newest messages, up to this many, are kept, besides the
+    /// starred messages that are spared.
+    #[serde(default)]
+    pub max_messages: Option<u64>,
+    /// Whether starred messages are spared; they are by default.
+    #[serde(default)]
+    pub keep_starred: Option<bool>,
+    /// Attachments of messages older than this many days are removed, along
+    /// with the files the backend downloaded.
+    #[serde(default)]
+    pub attachments_max_age_days: Option<u64>,
+}
+
+impl RetentionRules {
+    /// The rules of `self`, with those it leaves unset taken from `defaults`.
+    fn or(self, defaults: RetentionRules) -> RetentionRules {
+        RetentionRules {
+            max_age_days: self.max_age_days.or(defaults.max_age_days),
+            max_messages: self.max_messages.or(defaults.max_messages),
+            keep_starred: self.keep_starred.or(defaults.keep_starred),
+            attachments_max_age_days: self.attachments_max_age_days.or(defaults.attachments_max_age_days),
+        }
+    }
+
+    /// Whether the rules would remove anything at all.
+    fn prunes(&self) -> bool {
+        self.max_age_days.is_some() || self.max_messages.is_some() || self.attachments_max_age_days.is_some()
+    }
+}
+
+/// The `retention` table of a service: rules for all its channels, and
+/// `channels` tables, keyed by channel id or name, overriding some of them.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    max_age_days: Option<u64>,
+    #[serde(default)]
+    max_messages: Option<u64>,
+    #[serde(default)]
+    keep_starred: Option<bool>,
+    #[serde(default)]
+    attachments_max_age_days: Option<u64>,
+    #[serde(default)]
+    channels: HashMap<String, RetentionRules>,
+}
+
+impl RetentionConfig {
+    /// The rules that apply to a channel of the service.
+    pub fn rules_for(&self, channel: &Channel) -> RetentionRules {
+        let service = RetentionRules {
+            max_age_days: self.max_age_days,
+            max_messages: self.max_messages,
+            keep_starred: self.keep_starred,
+            attachments_max_age_days: self.attachments_max_age_days,
+        };
+        match self.channels.get(&channel.id).or_else(|| self.channels.get(&channel.name)) {
+            Some(rules) => rules.or(service),
+            None => service,
+        }
+    }
+}
+
+/// What pruning removed, or would remove, from one channel.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct PrunedChannel {
+    pub service: String,
+    pub channel_id: String,
+    pub messages: usize,
+    pub attachments: usize,
+}
+
+/// Applies the retention rules of every service to the store, or with
+/// `dry_run` only counts what they would remove. Channels left untouched
+/// are not listed.
+pub fn prune(
+    store: &Store,
+    retention: &HashMap<String, RetentionConfig>,
+    dry_run: bool,
+) -> Result<Vec<PrunedChannel>, StoreError> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let mut services: Vec<&String> = retention.keys().collect();
+    services.sort();
+    let mut pruned = Vec::new();
+    for service in services {
+        for channel in store.channels(service)? {
+            let rules = retention[service].rules_for(&channel);
+            if !rules.prunes() {
+                continue;
+            }
+            let (messages, attachments) = store.prune(service, &channel.id, &rules, now, dry_run)?;
+            if messages > 0 || attachments > 0 {
+                pruned.push(PrunedChannel { service: service.clone(), channel_id: channel.id, messages, attachments });
+            }
+        }
+    }
+    Ok(pruned)
+}
+
+/// Prunes the store when the daemon starts and every `PRUNE_INTERVAL`
+/// after that, with the rules of the services configured at the time.
+/// Each run that removed something is reported with a `storage_pruned` event.
+pub async fn run_pruner(store: Arc<Store>, services: Arc<Mutex<ServiceManager>>, events: EventBus) {
+    let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let retention = services.lock().await.retention();
+        let store = store.clone();
+        match tokio::task::spawn_blocking(move || prune(&store, &retention, false)).await.expect("pruning does not panic") {
+            Ok(channels) if channels.is_empty() => {}
+            Ok(channels) => events.publish(DaemonEvent::StoragePruned { dry_run: false, channels }),
+            Err(e) => eprintln!("Failed to prune the message store: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_rules_override_service_rules() {
+        let config: RetentionConfig = toml::from_str(
+            "max_age_days = 365\n\
+             keep_starred = false\n\
+             [channels.random]\n\
+             max_age_days = 30\n\
+             max_messages = 1000\n",
+        )
+        .unwrap();
+        let channel = |id: &str, name: &str| Channel { id: id.to_string(), name: name.to_string(), direct: false };
+
+        let random = config.rules_for(&channel("C2", "random"));
+        assert_eq!((random.max_age_days, random.max_messages, random.keep_starred), (Some(30), Some(1000), Some(false)));
+        let general = config.rules_for(&channel("C1", "general"));
+        assert_eq!((general.max_age_days, general.max_messages), (Some(365), None));
+
+        assert!(toml::from_str::<RetentionConfig>("max_age = 3").is_err());
+        assert!(toml::from_str::<RetentionConfig>("[channels.random]\nmax_mesages = 3").is_err());
+    }
+}
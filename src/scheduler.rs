@@ -0,0 +1,238 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::command_processor::CommandContext;
+use crate::event_bus::Subscription;
+use crate::outbox::DeliveryState;
+use crate::protocol::{DaemonEvent, FrontendEvent};
+use crate::time::timestamp;
+
+/// Prefix of the outbox `local_id` of scheduled messages, followed by their id.
+const LOCAL_ID_PREFIX: &str = "scheduled-";
+
+/// When to send a scheduled message: a Unix timestamp in seconds, a UTC time
+/// such as `"2026-10-19T09:00:00Z"`, or a delay such as `"+1h30m"` (with
+/// `s`, `m`, `h` and `d` units).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum SendAt {
+    Timestamp(u64),
+    Text(String),
+}
+
+impl SendAt {
+    /// The Unix timestamp to send at, for a command received at `now`.
+    /// Times the store cannot hold, past `i64::MAX`, are refused.
+    pub fn resolve(&self, now: u64) -> Result<u64, String> {
+        let send_at = match self {
+            SendAt::Timestamp(timestamp) => *timestamp,
+            SendAt::Text(text) => match text.strip_prefix('+') {
+                Some(delay) => parse_delay(delay).and_then(|delay| now.checked_add(delay).ok_or(())),
+                None => parse_utc_time(text),
+            }
+            .map_err(|_| format!("invalid send_at '{}', expected a Unix timestamp, YYYY-MM-DDTHH:MM[:SS]Z or +<delay>", text))?,
+        };
+        if send_at > i64::MAX as u64 {
+            return Err(format!("send_at {} is too far in the future", send_at));
+        }
+        Ok(send_at)
+    }
+}
+
+/// Parses a delay such as `1h30m` into seconds.
+fn parse_delay(delay: &str) -> Result<u64, ()> {
+    let mut seconds = 0u64;
+    let mut rest = delay;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).ok_or(())?;
+        let amount: u64 = rest[..digits].parse().map_err(|_| ())?;
+        let unit = rest[digits..].chars().next().ok_or(())?;
+        let scale = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            _ => return Err(()),
+        };
+        seconds = amount.checked_mul(scale).and_then(|amount| seconds.checked_add(amount)).ok_or(())?;
+        rest = &rest[digits + unit.len_utf8()..];
+    }
+    if delay.is_empty() {
+        return Err(());
+    }
+    Ok(seconds)
+}
+
+/// Parses a `YYYY-MM-DDTHH:MM[:SS]Z` time into a Unix timestamp.
+fn parse_utc_time(time: &str) -> Result<u64, ()> {
+    let (date, clock) = time.strip_suffix('Z').and_then(|time| time.split_once('T')).ok_or(())?;
+    let date: Vec<i64> = date.split('-').map(|part| part.parse().map_err(|_| ())).collect::<Result<_, _>>()?;
+    let clock: Vec<i64> = clock.split(':').map(|part| part.parse().map_err(|_| ())).collect::<Result<_, _>>()?;
+    let (&[year, month, day], &[hour, minute, ref second @ ..]) = (&date[..], &clock[..]) else {
+        return Err(());
+    };
+    let second = match second {
+        [] => 0,
+        [second] => *second,
+        _ => return Err(()),
+    };
+    let valid = (1..=12).contains(&month)
+        && (1..=31).contains(&day)
+        && (0..24).contains(&hour)
+        && (0..60).contains(&minute)
+        && (0..60).contains(&second);
+    if !valid {
+        return Err(());
+    }
+    let timestamp = timestamp(year, month, day, hour * 3600 + minute * 60 + second).ok_or(())?;
+    u64::try_from(timestamp).map_err(|_| ())
+}
+
+/// A message waiting to be sent at `send_at`, a Unix timestamp in seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, JsonSchema)]
+pub struct ScheduledMessage {
+    pub id: u64,
+    pub service: String,
+    pub channel_id: String,
+    pub body: String,
+    pub send_at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Hands scheduled messages to the outbox when they are due, including
+/// those that fell due while the daemon was down, and reports the outcome
+/// of their delivery with `scheduled_message_sent` and
+/// `scheduled_message_failed` events.
+pub async fn run_scheduler(context: CommandContext, mut subscription: Subscription) {
+    let store = &context.store;
+    let next_due = || {
+        store.blocking(|store| {
+            store.next_scheduled().unwrap_or_else(|e| {
+                eprintln!("Failed to read scheduled messages: {}", e);
+                None
+            })
+        })
+    };
+    let mut next = next_due().await;
+    loop {
+        let wait = next.map(|send_at| Duration::from_secs(send_at.saturating_sub(now())));
+        tokio::select! {
+            _ = tokio::time::sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                let due = store.blocking(|store| {
+                    store.due_scheduled(now()).unwrap_or_else(|e| {
+                        eprintln!("Failed to read scheduled messages: {}", e);
+                        Vec::new()
+                    })
+                });
+                for message in due.await {
+                    let running = context.backends.lock().await.contains_key(&message.service);
+                    if running {
+                        // Saved in the outbox before leaving the schedule, so that it is sent however the
+                        // daemon stops. Queueing it again under the same local id changes nothing.
+                        let local_id = format!("{}{}", LOCAL_ID_PREFIX, message.id);
+                        context.outbox.enqueue(&message.service, &message.channel_id, &message.body, Some(local_id));
+                    }
+                    let id = message.id;
+                    if let Err(e) = store.blocking(move |store| store.cancel_scheduled(id)).await {
+                        eprintln!("Failed to remove scheduled message {}: {}", message.id, e);
+                        continue;
+                    }
+                    if !running {
+                        context.events.publish(DaemonEvent::ScheduledMessageFailed {
+                            id: message.id,
+                            reason: format!("Service '{}' not found", message.service),
+                            service: message.service,
+                            channel_id: message.channel_id,
+                        });
+                        continue;
+                    }
+                    let outbox = context.outbox.clone();
+                    tokio::spawn(async move { outbox.deliver(&message.service, &message.channel_id).await });
+                }
+                next = next_due().await;
+            }
+            event = subscription.next_event() => match event {
+                FrontendEvent::Daemon(DaemonEvent::MessageStatus { service, channel_id, local_id, status, reason }) => {
+                    let Some(id) = local_id.strip_prefix(LOCAL_ID_PREFIX).and_then(|id| id.parse().ok()) else {
+                        continue;
+                    };
+                    match status {
+                        DeliveryState::Sent => {
+                            context.events.publish(DaemonEvent::ScheduledMessageSent { id, service, channel_id });
+                        }
+                        DeliveryState::Failed => {
+                            let reason = reason.unwrap_or_else(|| "Sending failed".to_string());
+                            context.events.publish(DaemonEvent::ScheduledMessageFailed { id, service, channel_id, reason });
+                        }
+                        DeliveryState::Pending => {}
+                    }
+                }
+                FrontendEvent::Daemon(DaemonEvent::MessageScheduled { .. })
+                | FrontendEvent::Daemon(DaemonEvent::ScheduledMessageCancelled { .. }) => next = next_due().await,
+                _ => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use tokio::sync::Mutex;
+
+    use crate::chat_backend::ConfiguredService;
+    use crate::dummy_backend::DummyBackend;
+    use crate::event_bus::EventBus;
+    use crate::service_manager::ServiceManager;
+
+    #[test]
+    fn test_resolve_send_at() {
+        let now = 1_000_000;
+        assert_eq!(SendAt::Timestamp(5).resolve(now), Ok(5));
+        assert_eq!(SendAt::Text("+1h30m".to_string()).resolve(now), Ok(now + 5400));
+        assert_eq!(SendAt::Text("+2d10s".to_string()).resolve(now), Ok(now + 172810));
+        assert_eq!(SendAt::Text("2024-03-01T09:30Z".to_string()).resolve(now), Ok(1709285400));
+        assert_eq!(SendAt::Text("2024-03-01T09:30:15Z".to_string()).resolve(now), Ok(1709285415));
+        assert!(SendAt::Timestamp(u64::MAX).resolve(now).is_err());
+        assert!(SendAt::Timestamp(i64::MAX as u64 + 1).resolve(now).is_err());
+        assert_eq!(SendAt::Timestamp(i64::MAX as u64).resolve(now), Ok(i64::MAX as u64));
+        assert!(SendAt::Text("+18446744073709551615s".to_string()).resolve(now).is_err());
+        assert!(SendAt::Text(format!("+{}s", i64::MAX)).resolve(now).is_err());
+        for invalid in ["+", "+5", "+5w", "2024-03-01T09:30", "2024-13-01T09:30Z", "2024-03-01T09:30:15:00Z", "soon"] {
+            assert!(SendAt::Text(invalid.to_string()).resolve(now).is_err(), "{} is not valid", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_due_messages_are_posted() {
+        let backends = HashMap::from([("chat".to_string(), ConfiguredService::new("dummy", Box::new(DummyBackend::new())))]);
+        let context = CommandContext::new(ServiceManager::new(Arc::new(Mutex::new(backends)), EventBus::new()));
+        let mut events = context.events.subscribe("test");
+        let sent = context.store.schedule_message("chat", "general", "hello", now()).unwrap();
+        let failed = context.store.schedule_message("gone", "general", "hello", 0).unwrap();
+        let later = context.store.schedule_message("chat", "general", "later", now() + 3600).unwrap();
+        assert_eq!(context.store.due_scheduled(now()).unwrap(), [failed.clone(), sent.clone()]);
+        assert_eq!(context.store.scheduled_messages().unwrap().len(), 3, "Due messages stay until they are queued");
+        let subscription = context.events.subscribe_lossless("scheduler");
+        tokio::spawn(run_scheduler(context.clone(), subscription));
+
+        let (mut was_sent, mut has_failed) = (false, false);
+        while !(was_sent && has_failed) {
+            match tokio::time::timeout(Duration::from_secs(5), events.recv()).await.unwrap().unwrap() {
+                FrontendEvent::Daemon(DaemonEvent::ScheduledMessageSent { id, .. }) => was_sent = id == sent.id,
+                FrontendEvent::Daemon(DaemonEvent::ScheduledMessageFailed { id, .. }) => has_failed = id == failed.id,
+                _ => {}
+            }
+        }
+        assert_eq!(context.store.scheduled_messages().unwrap(), [later]);
+    }
+}
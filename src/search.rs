@@ -0,0 +1,203 @@
+use std::fmt;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::time::timestamp;
+
+/// Operators that filter rather than search, e.g. `from:alice`.
+const OPERATORS: &[&str] = &["from", "in", "service", "before", "after"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchError {
+    UnterminatedQuote,
+    /// An operator such as `from:` was not followed by a value.
+    MissingValue(String),
+    /// `before:` and `after:` take a `YYYY-MM-DD` date.
+    InvalidDate(String),
+    /// Only filters were given, nothing to search for.
+    NoTerms,
+}
+
+impl fmt::Display for SearchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SearchError::UnterminatedQuote => write!(f, "Unterminated quote"),
+            SearchError::MissingValue(operator) => write!(f, "Missing value after '{}:'", operator),
+            SearchError::InvalidDate(date) => write!(f, "Invalid date '{}', expected YYYY-MM-DD", date),
+            SearchError::NoTerms => write!(f, "Nothing to search for"),
+        }
+    }
+}
+
+impl std::error::Error for SearchError {}
+
+/// A parsed `search_local` query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchQuery {
+    /// The FTS5 expression matching words, phrases, authors and channels.
+    pub expression: String,
+    pub service: Option<String>,
+    /// Only messages from before this time, in seconds since the Unix epoch.
+    pub before: Option<i64>,
+    /// Only messages from this time on.
+    pub after: Option<i64>,
+}
+
+/// One message matching a query.
+#[derive(Debug, Clone, PartialEq, Serialize, JsonSchema)]
+pub struct SearchHit {
+    pub service: String,
+    pub channel_id: String,
+    pub channel_name: String,
+    pub message_id: u64,
+    pub author: String,
+    /// An excerpt of the message, or of its author or channel when the match
+    /// is there, with the matched words between the highlight markers.
+    pub snippet: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+enum Token {
+    Word(String),
+    Phrase(String),
+    Operator(String, String),
+}
+
+impl SearchQuery {
+    /// Parses a query made of words (`deploy`), prefixes (`depl*`), phrases
+    /// (`"release notes"`) and the operators `from:`, `in:`, `service:`,
+    /// `before:` and `after:`, whose value may be quoted as well. Every
+    /// part must match.
+    pub fn parse(query: &str) -> Result<Self, SearchError> {
+        let mut parts = Vec::new();
+        let mut parsed = SearchQuery { expression: String::new(), service: None, before: None, after: None };
+        for token in tokenize(query)? {
+            match token {
+                Token::Word(word) => parts.extend(term(&word)),
+                Token::Phrase(phrase) => parts.push(quote(&phrase)),
+                Token::Operator(operator, value) => match operator.as_str() {
+                    "from" => parts.extend(term(&value).map(|term| format!("author : {}", term))),
+                    "in" => parts.extend(term(&value).map(|term| format!("channel : {}", term))),
+                    "service" => parsed.service = Some(value),
+                    "before" => parsed.before = Some(parse_date(&value)?),
+                    "after" => parsed.after = Some(parse_date(&value)?),
+                    _ => unreachable!("only known operators are tokenized"),
+                },
+            }
+        }
+        if parts.is_empty() {
+            return Err(SearchError::NoTerms);
+        }
+        parsed.expression = parts.join(" AND ");
+        Ok(parsed)
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, SearchError> {
+    let mut chars = query.chars().peekable();
+    let mut tokens = Vec::new();
+    loop {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+        if chars.next_if_eq(&'"').is_some() {
+            tokens.push(Token::Phrase(quoted(&mut chars)?));
+            continue;
+        }
+        let mut word = String::new();
+        while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+            word.push(c);
+            if c == ':' && OPERATORS.contains(&&word[..word.len() - 1]) && chars.next_if_eq(&'"').is_some() {
+                word.push_str(&quoted(&mut chars)?);
+                break;
+            }
+        }
+        if word.is_empty() {
+            return Ok(tokens);
+        }
+        tokens.push(match word.split_once(':') {
+            Some((operator, value)) if OPERATORS.contains(&operator) => {
+                if value.is_empty() {
+                    return Err(SearchError::MissingValue(operator.to_string()));
+                }
+                Token::Operator(operator.to_string(), value.to_string())
+            }
+            _ => Token::Word(word),
+        });
+    }
+}
+
+/// Reads up to the closing quote, the opening one having been consumed.
+fn quoted(chars: &mut Peekable<Chars>) -> Result<String, SearchError> {
+    let mut text = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            return Ok(text);
+        }
+        text.push(c);
+    }
+    Err(SearchError::UnterminatedQuote)
+}
+
+/// Quotes text as an FTS5 string, so that it is matched as words only.
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('"', "\"\""))
+}
+
+/// A word or `prefix*`, or nothing for words without any letter or digit,
+/// which the index does not hold.
+fn term(word: &str) -> Option<String> {
+    let (word, prefix) = match word.strip_suffix('*') {
+        Some(stem) => (stem, true),
+        None => (word, false),
+    };
+    if !word.chars().any(char::is_alphanumeric) {
+        return None;
+    }
+    Some(if prefix { format!("{} *", quote(word)) } else { quote(word) })
+}
+
+/// Midnight UTC at the start of a `YYYY-MM-DD` day, as a Unix timestamp.
+fn parse_date(date: &str) -> Result<i64, SearchError> {
+    let invalid = || SearchError::InvalidDate(date.to_string());
+    let fields: Vec<&str> = date.split('-').collect();
+    let [year, month, day] = fields[..] else {
+        return Err(invalid());
+    };
+    let year: i64 = year.parse().map_err(|_| invalid())?;
+    let month: i64 = month.parse().map_err(|_| invalid())?;
+    let day: i64 = day.parse().map_err(|_| invalid())?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid());
+    }
+    timestamp(year, month, day, 0).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_query() {
+        let query = SearchQuery::parse(r#"deploy* "release notes" from:alice in:"dev team" service:work after:2024-03-01"#).unwrap();
+        assert_eq!(
+            query.expression,
+            r#""deploy" * AND "release notes" AND author : "alice" AND channel : "dev team""#
+        );
+        assert_eq!(query.service.as_deref(), Some("work"));
+        assert_eq!((query.before, query.after), (None, Some(1709251200)));
+
+        let query = SearchQuery::parse(r#"http://example.org say"hi" - from:bob"#).unwrap();
+        assert_eq!(query.expression, r#""http://example.org" AND "say""hi""" AND author : "bob""#);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(SearchQuery::parse(r#"in:"dev"#), Err(SearchError::UnterminatedQuote));
+        assert_eq!(SearchQuery::parse("from: alice"), Err(SearchError::MissingValue("from".to_string())));
+        assert_eq!(SearchQuery::parse("x before:2024-13-01"), Err(SearchError::InvalidDate("2024-13-01".to_string())));
+        assert_eq!(SearchQuery::parse("service:work"), Err(SearchError::NoTerms));
+    }
+}
@@ -0,0 +1,182 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::process::Command;
+
+use crate::chat_backend::Credentials;
+
+/// How long a secret command, such as a password manager waiting for an
+/// unlock, may take before logging in fails.
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A secret value, such as a password or an access token. It is never
+/// shown in `Debug` output, so configs holding one can be logged safely.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Returns the secret itself, for handing over to a backend.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Secret(<redacted>)")
+    }
+}
+
+#[derive(Debug)]
+pub enum SecretError {
+    CommandFailed(String),
+    MissingEnvVar(String),
+    Unreadable(String),
+}
+
+impl fmt::Display for SecretError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretError::CommandFailed(msg) => write!(f, "Secret command failed: {}", msg),
+            SecretError::MissingEnvVar(name) => write!(f, "Environment variable {} is not set", name),
+            SecretError::Unreadable(msg) => write!(f, "Failed to read secret file: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for SecretError {}
+
+/// Where a secret is read from. Sources are resolved again before every
+/// login, so a rotated password is picked up without restarting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    /// The first line printed by a shell command, e.g. `pass show chat/rc`.
+    Command(String),
+    /// The value of an environment variable.
+    Env(String),
+    /// The first line of a file.
+    File(PathBuf),
+}
+
+impl SecretSource {
+    /// Whether the command, variable name or path is empty.
+    pub fn is_empty(&self) -> bool {
+        match self {
+            SecretSource::Command(command) => command.trim().is_empty(),
+            SecretSource::Env(name) => name.is_empty(),
+            SecretSource::File(path) => path.as_os_str().is_empty(),
+        }
+    }
+
+    pub async fn resolve(&self) -> Result<Secret, SecretError> {
+        match self {
+            SecretSource::Command(command) => run_command(command, COMMAND_TIMEOUT).await,
+            SecretSource::Env(name) => std::env::var(name)
+                .map(Secret)
+                .map_err(|_| SecretError::MissingEnvVar(name.clone())),
+            SecretSource::File(path) => tokio::fs::read_to_string(path)
+                .await
+                .map(|contents| first_line(&contents))
+                .map_err(|e| SecretError::Unreadable(format!("{}: {}", path.display(), e))),
+        }
+    }
+}
+
+/// Runs a secret command, killing it if it takes longer than `timeout`.
+async fn run_command(command: &str, timeout: Duration) -> Result<Secret, SecretError> {
+    let running = Command::new("sh").arg("-c").arg(command).kill_on_drop(true).output();
+    let output = tokio::time::timeout(timeout, running)
+        .await
+        .map_err(|_| SecretError::CommandFailed(format!("`{}` timed out after {:?}", command, timeout)))?
+        .map_err(|e| SecretError::CommandFailed(format!("`{}`: {}", command, e)))?;
+    if !output.status.success() {
+        return Err(SecretError::CommandFailed(format!("`{}` exited with {}", command, output.status)));
+    }
+    Ok(first_line(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn first_line(text: &str) -> Secret {
+    Secret(text.lines().next().unwrap_or_default().to_string())
+}
+
+/// How to obtain the credentials a service logs in with.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CredentialSource {
+    Password { username: String, source: SecretSource },
+    Token { username: Option<String>, source: SecretSource },
+}
+
+impl CredentialSource {
+    /// Resolves the secret, producing credentials ready for `ChatBackend::login`.
+    pub async fn resolve(&self) -> Result<Credentials, SecretError> {
+        Ok(match self {
+            CredentialSource::Password { username, source } => Credentials::Password {
+                username: username.clone(),
+                password: source.resolve().await?,
+            },
+            CredentialSource::Token { username, source } => Credentials::Token {
+                username: username.clone(),
+                token: source.resolve().await?,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret("hunter2".to_string());
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+        let credentials = Credentials::Token { username: None, token: secret };
+        assert!(!format!("{:?}", credentials).contains("hunter2"));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_command() {
+        let source = SecretSource::Command("printf 'hunter2\\nsecond line'".to_string());
+        assert_eq!(source.resolve().await.unwrap().expose(), "hunter2");
+
+        let failing = SecretSource::Command("exit 3".to_string());
+        assert!(matches!(failing.resolve().await, Err(SecretError::CommandFailed(_))));
+
+        let hung = run_command("sleep 10", Duration::from_millis(50)).await;
+        assert!(matches!(hung, Err(SecretError::CommandFailed(reason)) if reason.contains("timed out")));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_env() {
+        std::env::set_var("KBUNIFIED_TEST_SECRET", "hunter2");
+        let source = SecretSource::Env("KBUNIFIED_TEST_SECRET".to_string());
+        assert_eq!(source.resolve().await.unwrap().expose(), "hunter2");
+
+        let missing = SecretSource::Env("KBUNIFIED_TEST_SECRET_UNSET".to_string());
+        assert!(matches!(missing.resolve().await, Err(SecretError::MissingEnvVar(_))));
+    }
+
+    #[tokio::test]
+    async fn test_credentials_are_resolved_on_every_call() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("password");
+        std::fs::write(&path, "first\n").unwrap();
+        let credentials = CredentialSource::Password {
+            username: "me".to_string(),
+            source: SecretSource::File(path.clone()),
+        };
+
+        match credentials.resolve().await.unwrap() {
+            Credentials::Password { password, .. } => assert_eq!(password.expose(), "first"),
+            other => panic!("Expected password credentials, got {:?}", other),
+        }
+        std::fs::write(&path, "rotated\n").unwrap();
+        match credentials.resolve().await.unwrap() {
+            Credentials::Password { password, .. } => assert_eq!(password.expose(), "rotated"),
+            other => panic!("Expected password credentials, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,463 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+use crate::backend_registry::find_backend;
+use crate::chat_backend::BackendMap;
+use crate::config_loader::{
+    insert_service_table, instantiate_service, load_config, parse_service, set_service_table_enabled,
+    update_config_file, ConfigErrors, ServiceConfig,
+};
+use crate::event_bus::EventBus;
+use crate::protocol::{DaemonEvent, ServiceInfo};
+use crate::inbox::InboxConfig;
+use crate::retention::RetentionConfig;
+use crate::supervisor::{supervise, Backoff, ServiceStatus};
+
+/// How often the configuration file is checked for changes.
+const RELOAD_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How long a stopping service may take to log out.
+const LOGOUT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A started service, with the config it was started from.
+struct RunningService {
+    config: ServiceConfig,
+    info: ServiceInfo,
+    task: JoinHandle<()>,
+}
+
+/// Why a service could not be added, removed, enabled or disabled.
+#[derive(Debug)]
+pub enum ServiceError {
+    UnknownService(String),
+    AlreadyExists(String),
+    InvalidConfig(ConfigErrors),
+    /// A change should be persisted, but there is no file to write it to.
+    NoConfigFile,
+    WriteBack(io::Error),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::UnknownService(name) => write!(f, "Service '{}' not found", name),
+            ServiceError::AlreadyExists(name) => write!(f, "Service '{}' already exists", name),
+            ServiceError::InvalidConfig(errors) => {
+                let messages: Vec<&str> = errors.0.iter().map(|e| e.message.as_str()).collect();
+                write!(f, "Invalid service configuration: {}", messages.join("; "))
+            }
+            ServiceError::NoConfigFile => write!(f, "No configuration file to persist the change to"),
+            ServiceError::WriteBack(e) => write!(f, "Failed to update the configuration file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// Starts and stops services, keeping the shared `BackendMap` that commands
+/// are executed against in step with what is actually running.
+pub struct ServiceManager {
+    backends: BackendMap,
+    events: EventBus,
+    /// Every configured service, enabled or not.
+    configs: HashMap<String, ServiceConfig>,
+    running: HashMap<String, RunningService>,
+    /// Where changes made with `persist` are written. Its lock keeps
+    /// concurrent changes from overwriting each other.
+    config_file: Option<Arc<Mutex<String>>>,
+}
+
+impl ServiceManager {
+    pub fn new(backends: BackendMap, events: EventBus) -> Self {
+        Self { backends, events, configs: HashMap::new(), running: HashMap::new(), config_file: None }
+    }
+
+    /// Lets changes be persisted to the configuration file at `path`.
+    pub fn with_config_file(mut self, path: impl Into<String>) -> Self {
+        self.config_file = Some(Arc::new(Mutex::new(path.into())));
+        self
+    }
+
+    pub fn backends(&self) -> BackendMap {
+        self.backends.clone()
+    }
+
+    pub fn events(&self) -> EventBus {
+        self.events.clone()
+    }
+
+    /// Starts the enabled services among `services` without publishing
+    /// anything, and describes them. Used once, when the daemon starts.
+    pub async fn start_all(&mut self, services: HashMap<String, ServiceConfig>) -> Vec<ServiceInfo> {
+        self.configs = services;
+        let mut names: Vec<String> =
+            self.configs.iter().filter(|(_, config)| config.enabled()).map(|(name, _)| name.clone()).collect();
+        names.sort();
+        let mut infos = Vec::new();
+        for name in names {
+            let config = self.configs[&name].clone();
+            infos.push(self.start(&name, config).await);
+        }
+        infos
+    }
+
+    /// Instantiates a service and makes it available to commands, then logs
+    /// in and streams its events in the background. Nothing is published.
+    async fn start(&mut self, name: &str, config: ServiceConfig) -> ServiceInfo {
+        let service = instantiate_service(&config);
+        let info = ServiceInfo::new(name, &service);
+        self.backends.lock().await.insert(name.to_string(), service.clone());
+        eprintln!("Spawning supervisor for service: {}", name);
+        let task = tokio::spawn(supervise(name.to_string(), service, self.events.clone(), Backoff::default()));
+        self.running.insert(name.to_string(), RunningService { config, info: info.clone(), task });
+        info
+    }
+
+    /// Stops a service and forgets its backend. The backend logs out in a
+    /// task of its own, so a slow logout does not hold up the manager;
+    /// await the returned handle to wait for it.
+    async fn stop(&mut self, name: &str) -> Option<JoinHandle<()>> {
+        let service = self.backends.lock().await.remove(name);
+        let running = self.running.remove(name)?;
+        running.task.abort();
+        self.events.publish(DaemonEvent::ServiceStatus {
+            service: name.to_string(),
+            status: ServiceStatus::Disconnected,
+            reason: None,
+            retry_in_ms: None,
+        });
+        let (name, service) = (name.to_string(), service?);
+        Some(tokio::spawn(async move {
+            match tokio::time::timeout(LOGOUT_TIMEOUT, service.backend.logout()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("Failed to log out of service {}: {}", name, e),
+                Err(_) => eprintln!("Timed out logging out of service {}", name),
+            }
+        }))
+    }
+
+    /// Stops every running service and waits for them to log out, for shutdown.
+    pub async fn stop_all(&mut self) {
+        let mut names: Vec<String> = self.running.keys().cloned().collect();
+        names.sort();
+        let mut logouts = Vec::new();
+        for name in names {
+            eprintln!("Stopping service {}", name);
+            logouts.extend(self.stop(&name).await);
+        }
+        for logout in logouts {
+            let _ = logout.await;
+        }
+    }
+
+    /// Replaces the configured services with `services`: removed or disabled
+    /// services are stopped, new ones started, and those whose options
+    /// changed are restarted. Services left untouched keep their connection.
+    pub async fn apply(&mut self, services: HashMap<String, ServiceConfig>) {
+        self.configs = services;
+        self.sync().await;
+    }
+
+    /// Starts and stops services until exactly the enabled ones are running
+    /// with their current config, publishing what changed.
+    async fn sync(&mut self) {
+        let mut names: Vec<String> = self.running.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let wanted = self.configs.get(&name).filter(|config| config.enabled());
+            if wanted != self.running.get(&name).map(|running| &running.config) {
+                eprintln!("Stopping service {}", name);
+                self.stop(&name).await;
+                self.events.publish(DaemonEvent::ServiceRemoved { service: name });
+            }
+        }
+
+        let mut added: Vec<(String, ServiceConfig)> = self
+            .configs
+            .iter()
+            .filter(|(name, config)| config.enabled() && !self.running.contains_key(*name))
+            .map(|(name, config)| (name.clone(), config.clone()))
+            .collect();
+        added.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, config) in added {
+            eprintln!("Starting service {}", name);
+            let service = self.start(&name, config).await;
+            self.events.publish(DaemonEvent::ServiceAdded { service });
+        }
+    }
+
+    /// The retention rules of every configured service that has some.
+    pub fn retention(&self) -> HashMap<String, RetentionConfig> {
+        self.configs
+            .iter()
+            .filter(|(_, config)| *config.retention() != RetentionConfig::default())
+            .map(|(name, config)| (name.clone(), config.retention().clone()))
+            .collect()
+    }
+
+    /// The configuration of a service, enabled or not.
+    pub fn config(&self, name: &str) -> Option<&ServiceConfig> {
+        self.configs.get(name)
+    }
+
+    /// The inbox rules of every configured service.
+    pub fn inbox(&self) -> HashMap<String, InboxConfig> {
+        self.configs.iter().map(|(name, config)| (name.clone(), config.inbox())).collect()
+    }
+
+    /// Adds and starts a service from the keys of its service table.
+    pub async fn add(manager: &Mutex<Self>, name: &str, options: toml::Table, persist: bool) -> Result<(), ServiceError> {
+        let config = parse_service(name, &options).map_err(ServiceError::InvalidConfig)?;
+        if let Some(config_file) = manager.lock().await.prepare(name, false, persist)? {
+            let name = name.to_string();
+            write_back(config_file, move |document| insert_service_table(document, &name, &options)).await?;
+        }
+        let mut manager = manager.lock().await;
+        manager.prepare(name, false, false)?;
+        manager.configs.insert(name.to_string(), config);
+        manager.sync().await;
+        Ok(())
+    }
+
+    /// Stops a service and forgets its configuration.
+    pub async fn remove(manager: &Mutex<Self>, name: &str, persist: bool) -> Result<(), ServiceError> {
+        if let Some(config_file) = manager.lock().await.prepare(name, true, persist)? {
+            let name = name.to_string();
+            write_back(config_file, move |document| {
+                document.remove(&name);
+            })
+            .await?;
+        }
+        let mut manager = manager.lock().await;
+        manager.prepare(name, true, false)?;
+        manager.configs.remove(name);
+        manager.sync().await;
+        Ok(())
+    }
+
+    /// Starts or stops a service while keeping its configuration.
+    pub async fn set_enabled(manager: &Mutex<Self>, name: &str, enabled: bool, persist: bool) -> Result<(), ServiceError> {
+        if let Some(config_file) = manager.lock().await.prepare(name, true, persist)? {
+            let name = name.to_string();
+            write_back(config_file, move |document| set_service_table_enabled(document, &name, enabled)).await?;
+        }
+        let mut manager = manager.lock().await;
+        manager.prepare(name, true, false)?;
+        if let Some(config) = manager.configs.get_mut(name) {
+            config.set_enabled(enabled);
+        }
+        manager.sync().await;
+        Ok(())
+    }
+
+    /// Checks that the service `name` is configured, or not, before a change,
+    /// and returns the file to write the change to if it is persisted. The
+    /// file is written without holding the manager's lock, so the check is
+    /// repeated before the change is applied.
+    fn prepare(&self, name: &str, configured: bool, persist: bool) -> Result<Option<Arc<Mutex<String>>>, ServiceError> {
+        match (self.configs.contains_key(name), configured) {
+            (false, true) => return Err(ServiceError::UnknownService(name.to_string())),
+            (true, false) => return Err(ServiceError::AlreadyExists(name.to_string())),
+            _ => {}
+        }
+        match persist {
+            true => self.config_file.clone().map(Some).ok_or(ServiceError::NoConfigFile),
+            false => Ok(None),
+        }
+    }
+
+    /// Describes every configured service, enabled or not, sorted by name.
+    pub fn list(&self) -> Vec<ServiceInfo> {
+        let mut infos: Vec<ServiceInfo> = self
+            .configs
+            .iter()
+            .map(|(name, config)| match self.running.get(name) {
+                Some(running) => running.info.clone(),
+                None => {
+                    let backend = find_backend(config.backend()).expect("backend passed validation");
+                    ServiceInfo::disabled(name, backend)
+                }
+            })
+            .collect();
+        infos.sort_by(|a, b| a.name.cmp(&b.name));
+        infos
+    }
+}
+
+/// Edits the configuration file on the blocking thread pool.
+async fn write_back(
+    config_file: Arc<Mutex<String>>,
+    edit: impl FnOnce(&mut toml_edit::DocumentMut) + Send + 'static,
+) -> Result<(), ServiceError> {
+    let path = config_file.lock_owned().await;
+    tokio::task::spawn_blocking(move || update_config_file(&path, edit))
+        .await
+        .expect("configuration edits do not panic")
+        .map_err(ServiceError::WriteBack)
+}
+
+/// Reloads the configuration at `config_path` whenever the file is modified
+/// or the daemon receives SIGHUP. Only service tables are reloaded; changes
+/// to `[daemon]` take effect on the next restart.
+pub fn spawn_config_watcher(config_path: String, manager: Arc<Mutex<ServiceManager>>) -> io::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        let mut last_modified = modified_time(&config_path);
+        let mut poll = tokio::time::interval(RELOAD_POLL_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = poll.tick() => {
+                    if modified_time(&config_path) == last_modified {
+                        continue;
+                    }
+                    eprintln!("{} changed, reloading", config_path);
+                }
+                _ = hangup.recv() => eprintln!("Received SIGHUP, reloading {}", config_path),
+            }
+            last_modified = modified_time(&config_path);
+            reload(&config_path, &manager).await;
+        }
+    });
+    Ok(())
+}
+
+fn modified_time(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Applies the configuration at `config_path`. An invalid file is reported
+/// and ignored, leaving every running service as it was.
+pub async fn reload(config_path: &str, manager: &Mutex<ServiceManager>) {
+    match load_config(config_path) {
+        Ok(config) => manager.lock().await.apply(config.services).await,
+        Err(errors) => eprintln!("Not reloading, the configuration is invalid:\n{}", errors),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::pin::Pin;
+
+    use async_trait::async_trait;
+    use futures::Stream;
+
+    use crate::chat_backend::{BackendEvent, Capabilities, ChatBackend, ConfiguredService, Credentials, LoginError, PostError};
+    use crate::config_loader::parse_config;
+    use crate::event_bus::Subscription;
+    use crate::protocol::FrontendEvent;
+
+    fn services(source: &str) -> HashMap<String, ServiceConfig> {
+        parse_config(source, "test.toml").unwrap().services
+    }
+
+    /// Collects the service_added/service_removed events published so far.
+    fn lifecycle_events(receiver: &mut Subscription) -> Vec<String> {
+        let mut seen = Vec::new();
+        while let Some(event) = receiver.try_recv() {
+            match event {
+                FrontendEvent::Daemon(DaemonEvent::ServiceAdded { service }) => seen.push(format!("+{}", service.name)),
+                FrontendEvent::Daemon(DaemonEvent::ServiceRemoved { service }) => seen.push(format!("-{}", service)),
+                _ => {}
+            }
+        }
+        seen
+    }
+
+    #[tokio::test]
+    async fn test_apply_diffs_services() {
+        let backends: BackendMap = Arc::new(Mutex::new(HashMap::new()));
+        let events = EventBus::new();
+        let mut receiver = events.subscribe("test");
+        let mut manager = ServiceManager::new(backends.clone(), events);
+
+        manager.apply(services("[a]\nbackend = \"dummy\"\n[b]\nbackend = \"dummy\"\n")).await;
+        assert_eq!(lifecycle_events(&mut receiver), ["+a", "+b"]);
+
+        // Reordering and reformatting alone must not restart anything.
+        manager.apply(services("[b]\nbackend   = \"dummy\"\n\n[a]\nbackend = \"dummy\"\n")).await;
+        assert_eq!(lifecycle_events(&mut receiver), Vec::<String>::new());
+
+        manager
+            .apply(services("[a]\nbackend = \"dummy\"\ntoken_env = \"CHAT_TOKEN\"\n[c]\nbackend = \"dummy\"\n"))
+            .await;
+        assert_eq!(lifecycle_events(&mut receiver), ["-a", "-b", "+a", "+c"]);
+        let mut running: Vec<String> = backends.lock().await.keys().cloned().collect();
+        running.sort();
+        assert_eq!(running, ["a", "c"]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_reload_keeps_services() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "[a]\nbackend = \"dummy\"\n").unwrap();
+
+        let backends: BackendMap = Arc::new(Mutex::new(HashMap::new()));
+        let events = EventBus::new();
+        let mut receiver = events.subscribe("test");
+        let manager = Mutex::new(ServiceManager::new(backends.clone(), events));
+        reload(path, &manager).await;
+        assert_eq!(lifecycle_events(&mut receiver), ["+a"]);
+
+        std::fs::write(path, "[a]\nbackend = \"dumy\"\n").unwrap();
+        reload(path, &manager).await;
+        assert_eq!(lifecycle_events(&mut receiver), Vec::<String>::new());
+        assert!(backends.lock().await.contains_key("a"));
+    }
+
+    struct SlowLogoutBackend;
+
+    #[async_trait]
+    impl ChatBackend for SlowLogoutBackend {
+        async fn login(&self, _credentials: &Credentials) -> Result<String, LoginError> {
+            Ok("session".to_string())
+        }
+
+        fn list_channels(&self) -> BackendEvent {
+            BackendEvent::ChannelList { channels: vec![] }
+        }
+
+        fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
+            Box::pin(futures::stream::empty())
+        }
+
+        async fn post_message(&self, _channel_id: &str, _content: &str, _idempotency_key: &str) -> Result<(), PostError> {
+            Ok(())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+
+        async fn logout(&self) -> Result<(), LoginError> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_disabling_does_not_wait_for_logout() {
+        let backends: BackendMap = Arc::new(Mutex::new(HashMap::new()));
+        let manager = Mutex::new(ServiceManager::new(backends.clone(), EventBus::new()));
+        manager.lock().await.apply(services("[slow]\nbackend = \"dummy\"\n")).await;
+        let service = ConfiguredService::new("dummy", Box::new(SlowLogoutBackend));
+        backends.lock().await.insert("slow".to_string(), service);
+
+        tokio::time::timeout(Duration::from_secs(1), ServiceManager::set_enabled(&manager, "slow", false, false))
+            .await
+            .expect("Disabling waited for the backend to log out")
+            .unwrap();
+        assert!(backends.lock().await.is_empty());
+        let infos = manager.lock().await.list();
+        assert_eq!((infos[0].name.as_str(), infos[0].backend.as_str(), infos[0].enabled), ("slow", "dummy", false));
+    }
+}
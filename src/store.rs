@@ -0,0 +1,1292 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::os::unix::fs::DirBuilderExt;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::archive::Archive;
+use crate::chat_backend::{Attachment, BackendEvent, Channel, HistoryError, Message, SharedBackend};
+use crate::event_bus::Subscription;
+use crate::bridges::RelayedCopy;
+use crate::inbox::{InboxItem, InboxReason};
+use crate::protocol::{DaemonEvent, FrontendEvent, ServiceEvent};
+use crate::retention::RetentionRules;
+use crate::scheduler::ScheduledMessage;
+use crate::search::{SearchHit, SearchQuery};
+
+/// Schema changes, applied in order. `PRAGMA user_version` counts how many
+/// a database has already been through.
+const MIGRATIONS: &[&str] = &["
+    CREATE TABLE channels (
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        name TEXT NOT NULL,
+        PRIMARY KEY (service, channel_id)
+    );
+    CREATE TABLE messages (
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        author TEXT NOT NULL,
+        body TEXT NOT NULL,
+        deleted INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY (service, channel_id, message_id)
+    );
+    CREATE TABLE edits (
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        previous_body TEXT NOT NULL
+    );
+    CREATE TABLE reactions (
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        author TEXT NOT NULL,
+        reaction TEXT NOT NULL,
+        PRIMARY KEY (service, channel_id, message_id, author, reaction)
+    );
+    -- Ranges of message ids whose messages are all stored. A range starting
+    -- at 0 reaches back to the start of the channel.
+    CREATE TABLE coverage (
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        oldest INTEGER NOT NULL,
+        newest INTEGER NOT NULL
+    );
+", "
+    ALTER TABLE messages ADD COLUMN timestamp INTEGER;
+    -- Full-text index of the messages that were not deleted, kept up to date
+    -- by the triggers below. Its rowids are those of `messages`.
+    CREATE VIRTUAL TABLE message_search USING fts5(
+        body, author, channel,
+        service UNINDEXED, channel_id UNINDEXED, message_id UNINDEXED,
+        tokenize = 'unicode61 remove_diacritics 2'
+    );
+    INSERT INTO message_search (rowid, body, author, channel, service, channel_id, message_id)
+        SELECT m.rowid, m.body, m.author, coalesce(c.name, m.channel_id), m.service, m.channel_id, m.message_id
+        FROM messages m LEFT JOIN channels c USING (service, channel_id)
+        WHERE m.deleted = 0;
+    CREATE TRIGGER message_inserted AFTER INSERT ON messages WHEN new.deleted = 0 BEGIN
+        INSERT INTO message_search (rowid, body, author, channel, service, channel_id, message_id)
+            VALUES (new.rowid, new.body, new.author,
+                coalesce((SELECT name FROM channels WHERE service = new.service AND channel_id = new.channel_id), new.channel_id),
+                new.service, new.channel_id, new.message_id);
+    END;
+    CREATE TRIGGER message_updated AFTER UPDATE OF body, author, deleted ON messages BEGIN
+        DELETE FROM message_search WHERE rowid = old.rowid;
+        INSERT INTO message_search (rowid, body, author, channel, service, channel_id, message_id)
+            SELECT new.rowid, new.body, new.author,
+                coalesce((SELECT name FROM channels WHERE service = new.service AND channel_id = new.channel_id), new.channel_id),
+                new.service, new.channel_id, new.message_id
+            WHERE new.deleted = 0;
+    END;
+    CREATE TRIGGER message_deleted AFTER DELETE ON messages BEGIN
+        DELETE FROM message_search WHERE rowid = old.rowid;
+    END;
+    CREATE TRIGGER channel_inserted AFTER INSERT ON channels BEGIN
+        UPDATE message_search SET channel = new.name WHERE service = new.service AND channel_id = new.channel_id;
+    END;
+    CREATE TRIGGER channel_renamed AFTER UPDATE OF name ON channels WHEN old.name IS NOT new.name BEGIN
+        UPDATE message_search SET channel = new.name WHERE service = new.service AND channel_id = new.channel_id;
+    END;
+", "
+    CREATE TABLE attachments (
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        name TEXT NOT NULL,
+        path TEXT NOT NULL,
+        PRIMARY KEY (service, channel_id, message_id, path)
+    );
+", "
+    ALTER TABLE messages ADD COLUMN starred INTEGER NOT NULL DEFAULT 0;
+", "
+    ALTER TABLE channels ADD COLUMN direct INTEGER NOT NULL DEFAULT 0;
+    -- Direct messages, mentions and mails gathered from every service. Items
+    -- keep a copy of their message, so that pruning does not empty them.
+    CREATE TABLE inbox (
+        id INTEGER PRIMARY KEY,
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        channel_name TEXT NOT NULL,
+        message_id INTEGER NOT NULL,
+        author TEXT NOT NULL,
+        body TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        reason TEXT NOT NULL,
+        read INTEGER NOT NULL DEFAULT 0,
+        UNIQUE (service, channel_id, message_id)
+    );
+", "
+    -- Copies of messages relayed by bridges. `message_id` is set once the
+    -- copy comes back from its service, recognized by its body.
+    CREATE TABLE relays (
+        id INTEGER PRIMARY KEY,
+        origin_service TEXT NOT NULL,
+        origin_channel_id TEXT NOT NULL,
+        origin_message_id INTEGER NOT NULL,
+        author TEXT NOT NULL,
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        body TEXT NOT NULL,
+        message_id INTEGER,
+        created INTEGER NOT NULL DEFAULT (unixepoch())
+    );
+    CREATE INDEX relays_by_origin ON relays (origin_service, origin_channel_id, origin_message_id);
+    CREATE INDEX relays_by_copy ON relays (service, channel_id, message_id);
+", "
+    -- Ids are never reused, since they name the messages in the outbox.
+    CREATE TABLE scheduled_messages (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        service TEXT NOT NULL,
+        channel_id TEXT NOT NULL,
+        body TEXT NOT NULL,
+        send_at INTEGER NOT NULL
+    );
+", "
+    -- The idempotency key a relayed copy was posted with, which recognizes
+    -- it when its backend reports the key. Older copies only have their body.
+    ALTER TABLE relays ADD COLUMN idempotency_key TEXT;
+    CREATE INDEX relays_by_key ON relays (service, channel_id, idempotency_key);
+"];
+
+/// How long a relayed copy may take to come back from its service before
+/// it is no longer expected.
+const RELAY_TIMEOUT_SECS: i64 = 86400;
+
+/// How many messages `backfill` asks for at a time.
+const BACKFILL_PAGE: usize = 100;
+
+/// What recognizes a message coming back from a service as a relayed copy.
+#[derive(Debug, Clone, Copy)]
+pub enum RelayEcho<'a> {
+    /// The idempotency key the message reported, for backends that echo
+    /// keys. Messages without one are not copies.
+    Key(Option<&'a str>),
+    /// The body of the message, for backends that do not. The oldest
+    /// expected copy with the same body is claimed.
+    Body(&'a str),
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Database(rusqlite::Error),
+    /// History was missing from the store and the backend could not fetch it.
+    History(HistoryError),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StoreError::Io(e) => write!(f, "{}", e),
+            StoreError::Database(e) => write!(f, "Database error: {}", e),
+            StoreError::History(e) => write!(f, "Failed to fetch history: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<rusqlite::Error> for StoreError {
+    fn from(error: rusqlite::Error) -> Self {
+        StoreError::Database(error)
+    }
+}
+
+/// Message ids are stored in SQLite's signed integers.
+fn sql_id(id: u64) -> i64 {
+    id as i64
+}
+
+/// Messages of a channel, oldest first. `complete` is false when some may
+/// be missing because the backend could not fill a gap in the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History {
+    pub messages: Vec<Message>,
+    pub complete: bool,
+}
+
+/// Everything that passed through the event pipeline, keyed by service, in
+/// a SQLite database.
+pub struct Store {
+    connection: Mutex<Connection>,
+    /// The id of the last message streamed in each channel since its service
+    /// connected. Messages streamed in one session have no gaps between them.
+    live: Mutex<HashMap<(String, String), u64>>,
+}
+
+impl Store {
+    /// Opens the database at `path`, creating it and its directory if needed.
+    pub fn open(path: &Path) -> Result<Self, StoreError> {
+        if let Some(directory) = path.parent() {
+            std::fs::DirBuilder::new().recursive(true).mode(0o700).create(directory).map_err(StoreError::Io)?;
+        }
+        Self::with_connection(Connection::open(path)?)
+    }
+
+    /// Opens a database that only lasts as long as the daemon.
+    pub fn in_memory() -> Result<Self, StoreError> {
+        Self::with_connection(Connection::open_in_memory()?)
+    }
+
+    fn with_connection(mut connection: Connection) -> Result<Self, StoreError> {
+        let applied: i64 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", version as i64 + 1)?;
+            transaction.commit()?;
+        }
+        Ok(Self { connection: Mutex::new(connection), live: Mutex::new(HashMap::new()) })
+    }
+
+    /// Records an event streamed by a service.
+    pub fn record(&self, service: &str, event: &BackendEvent) -> Result<(), StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        match event {
+            BackendEvent::ChannelList { channels } => {
+                for channel in channels {
+                    insert_channel(&transaction, service, channel)?;
+                }
+            }
+            // Without an id, the message could only overwrite another one.
+            BackendEvent::Message { message_id: 0, .. } => {}
+            BackendEvent::Message { channel_id, message_id, body, author, attachments, .. } => {
+                let message = Message {
+                    id: *message_id,
+                    channel_id: channel_id.clone(),
+                    author: author.clone(),
+                    content: body.clone(),
+                    timestamp: None,
+                    attachments: attachments.clone(),
+                };
+                insert_message(&transaction, service, &message)?;
+                let key = (service.to_string(), channel_id.clone());
+                let previous = self.live.lock().unwrap().insert(key, *message_id);
+                let previous = previous.unwrap_or(*message_id);
+                cover(&transaction, service, channel_id, previous.min(*message_id), previous.max(*message_id))?;
+            }
+            BackendEvent::MessageEdited { channel_id, message_id, body } => {
+                transaction.execute(
+                    "INSERT INTO edits (service, channel_id, message_id, previous_body)
+                     SELECT service, channel_id, message_id, body FROM messages
+                     WHERE service = ?1 AND channel_id = ?2 AND message_id = ?3 AND deleted = 0",
+                    params![service, channel_id, sql_id(*message_id)],
+                )?;
+                transaction.execute(
+                    "UPDATE messages SET body = ?4
+                     WHERE service = ?1 AND channel_id = ?2 AND message_id = ?3 AND deleted = 0",
+                    params![service, channel_id, sql_id(*message_id), body],
+                )?;
+            }
+            BackendEvent::MessageDeleted { channel_id, message_id } => {
+                transaction.execute(
+                    "UPDATE messages SET body = '', deleted = 1
+                     WHERE service = ?1 AND channel_id = ?2 AND message_id = ?3",
+                    params![service, channel_id, sql_id(*message_id)],
+                )?;
+            }
+            BackendEvent::ReactionAdded { channel_id, message_id, author, reaction } => {
+                transaction.execute(
+                    "INSERT OR IGNORE INTO reactions (service, channel_id, message_id, author, reaction)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![service, channel_id, sql_id(*message_id), author, reaction],
+                )?;
+            }
+            BackendEvent::ReactionRemoved { channel_id, message_id, author, reaction } => {
+                transaction.execute(
+                    "DELETE FROM reactions
+                     WHERE service = ?1 AND channel_id = ?2 AND message_id = ?3 AND author = ?4 AND reaction = ?5",
+                    params![service, channel_id, sql_id(*message_id), author, reaction],
+                )?;
+            }
+            // Notifications repeat messages that were recorded already.
+            BackendEvent::Notification { .. } => {}
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Stores every channel and message of an archive under `service`.
+    /// Archives hold the whole history of their channels, so it is served
+    /// from the store from then on. Importing again updates the messages.
+    pub fn import(&self, service: &str, archive: &Archive) -> Result<(), StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for channel in &archive.channels {
+            insert_channel(&transaction, service, channel)?;
+        }
+        let mut newest: HashMap<&str, u64> = HashMap::new();
+        for message in &archive.messages {
+            insert_message(&transaction, service, message)?;
+            let id = newest.entry(&message.channel_id).or_default();
+            *id = message.id.max(*id);
+        }
+        for (channel_id, newest) in newest {
+            cover(&transaction, service, channel_id, 0, newest)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Stars or unstars a stored message, returning false when there is none.
+    pub fn set_starred(&self, service: &str, channel_id: &str, message_id: u64, starred: bool) -> Result<bool, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE messages SET starred = ?4 WHERE service = ?1 AND channel_id = ?2 AND message_id = ?3 AND deleted = 0",
+            params![service, channel_id, sql_id(message_id), starred],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Adds a message to the inbox, returning the new item, or `None` when
+    /// it is in the inbox already.
+    pub fn add_to_inbox(
+        &self,
+        service: &str,
+        channel_name: &str,
+        message: &Message,
+        reason: InboxReason,
+    ) -> Result<Option<InboxItem>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let timestamp = message.timestamp.map(|timestamp| timestamp as i64);
+        let item = connection
+            .query_row(
+                "INSERT OR IGNORE INTO inbox (service, channel_id, channel_name, message_id, author, body, timestamp, reason)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, coalesce(?7, unixepoch()), ?8)
+                 RETURNING id, service, channel_id, channel_name, message_id, author, body, timestamp, reason, read",
+                params![
+                    service,
+                    message.channel_id,
+                    channel_name,
+                    sql_id(message.id),
+                    message.author,
+                    message.content,
+                    timestamp,
+                    reason.as_str()
+                ],
+                read_inbox_item,
+            )
+            .optional()?;
+        Ok(item)
+    }
+
+    /// Up to `limit` inbox items older than item `before`, or the latest
+    /// ones, newest first, with the number of unread items in the inbox.
+    pub fn inbox(&self, before: Option<u64>, limit: usize, unread_only: bool) -> Result<(Vec<InboxItem>, usize), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, service, channel_id, channel_name, message_id, author, body, timestamp, reason, read FROM inbox
+             WHERE (?1 IS NULL OR id < ?1) AND (?2 = 0 OR read = 0)
+             ORDER BY id DESC LIMIT ?3",
+        )?;
+        let items = statement
+            .query_map(params![before.map(sql_id), unread_only, limit as i64], read_inbox_item)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let unread: i64 = connection.query_row("SELECT count(*) FROM inbox WHERE read = 0", [], |row| row.get(0))?;
+        Ok((items, unread as usize))
+    }
+
+    /// Marks the inbox items of a channel up to message `message_id` as
+    /// read, returning how many were unread.
+    pub fn mark_read(&self, service: &str, channel_id: &str, message_id: u64) -> Result<usize, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let updated = connection.execute(
+            "UPDATE inbox SET read = 1 WHERE service = ?1 AND channel_id = ?2 AND message_id <= ?3 AND read = 0",
+            params![service, channel_id, sql_id(message_id)],
+        )?;
+        Ok(updated)
+    }
+
+    /// Remembers that message `origin_message_id` of `author` was relayed to
+    /// a channel of `copy.service` as `copy.body`, until the copy comes back.
+    pub fn add_relay(
+        &self,
+        origin_service: &str,
+        origin_channel_id: &str,
+        origin_message_id: u64,
+        author: &str,
+        copy: &RelayedCopy,
+        idempotency_key: &str,
+    ) -> Result<(), StoreError> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "DELETE FROM relays WHERE message_id IS NULL AND created < unixepoch() - ?1",
+            params![RELAY_TIMEOUT_SECS],
+        )?;
+        connection.execute(
+            "INSERT INTO relays
+                 (origin_service, origin_channel_id, origin_message_id, author, service, channel_id, body, idempotency_key)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![
+                origin_service,
+                origin_channel_id,
+                sql_id(origin_message_id),
+                author,
+                copy.service,
+                copy.channel_id,
+                copy.body,
+                idempotency_key
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Whether a message is a copy relayed by a bridge that came back.
+    pub fn is_relayed_copy(&self, service: &str, channel_id: &str, message_id: u64) -> Result<bool, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let copy = connection
+            .query_row(
+                "SELECT 1 FROM relays WHERE service = ?1 AND channel_id = ?2 AND message_id = ?3",
+                params![service, channel_id, sql_id(message_id)],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(copy.is_some())
+    }
+
+    /// Whether a message is a copy relayed by a bridge: one seen before, or
+    /// the expected copy that `echo` identifies, which it then becomes.
+    pub fn claim_relay(&self, service: &str, channel_id: &str, message_id: u64, echo: RelayEcho) -> Result<bool, StoreError> {
+        if self.is_relayed_copy(service, channel_id, message_id)? {
+            return Ok(true);
+        }
+        let connection = self.connection.lock().unwrap();
+        let claimed = match echo {
+            RelayEcho::Key(None) => 0,
+            RelayEcho::Key(Some(key)) => connection.execute(
+                "UPDATE relays SET message_id = ?3
+                 WHERE service = ?1 AND channel_id = ?2 AND message_id IS NULL AND idempotency_key = ?4",
+                params![service, channel_id, sql_id(message_id), key],
+            )?,
+            RelayEcho::Body(body) => connection.execute(
+                "UPDATE relays SET message_id = ?3 WHERE id = (
+                     SELECT id FROM relays WHERE service = ?1 AND channel_id = ?2 AND message_id IS NULL AND body = ?4
+                     ORDER BY id LIMIT 1
+                 )",
+                params![service, channel_id, sql_id(message_id), body],
+            )?,
+        };
+        Ok(claimed > 0)
+    }
+
+    /// The copies of a message that came back from their services, with the
+    /// author of the original.
+    pub fn relayed_copies(
+        &self,
+        origin_service: &str,
+        origin_channel_id: &str,
+        origin_message_id: u64,
+    ) -> Result<Vec<(String, RelayedCopy)>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT author, service, channel_id, message_id, body FROM relays
+             WHERE origin_service = ?1 AND origin_channel_id = ?2 AND origin_message_id = ?3 AND message_id IS NOT NULL
+             ORDER BY id",
+        )?;
+        let copies = statement
+            .query_map(params![origin_service, origin_channel_id, sql_id(origin_message_id)], |row| {
+                let copy = RelayedCopy {
+                    service: row.get(1)?,
+                    channel_id: row.get(2)?,
+                    message_id: Some(row.get::<_, i64>(3)? as u64),
+                    body: row.get(4)?,
+                };
+                Ok((row.get(0)?, copy))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(copies)
+    }
+
+    /// Keeps a message to be sent at `send_at`, a Unix timestamp.
+    pub fn schedule_message(&self, service: &str, channel_id: &str, body: &str, send_at: u64) -> Result<ScheduledMessage, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let message = connection.query_row(
+            "INSERT INTO scheduled_messages (service, channel_id, body, send_at) VALUES (?1, ?2, ?3, ?4)
+             RETURNING id, service, channel_id, body, send_at",
+            params![service, channel_id, body, sql_id(send_at)],
+            read_scheduled_message,
+        )?;
+        Ok(message)
+    }
+
+    /// Every message waiting to be sent, soonest first.
+    pub fn scheduled_messages(&self) -> Result<Vec<ScheduledMessage>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare("SELECT id, service, channel_id, body, send_at FROM scheduled_messages ORDER BY send_at, id")?;
+        let messages = statement.query_map([], read_scheduled_message)?.collect::<Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    /// When the next scheduled message is due, if there is one.
+    pub fn next_scheduled(&self) -> Result<Option<u64>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let next: Option<i64> = connection.query_row("SELECT min(send_at) FROM scheduled_messages", [], |row| row.get(0))?;
+        Ok(next.map(|send_at| send_at as u64))
+    }
+
+    /// The scheduled messages due at `now`, soonest first. They stay
+    /// scheduled until `cancel_scheduled` removes them.
+    pub fn due_scheduled(&self, now: u64) -> Result<Vec<ScheduledMessage>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT id, service, channel_id, body, send_at FROM scheduled_messages WHERE send_at <= ?1
+             ORDER BY send_at, id",
+        )?;
+        let messages = statement.query_map(params![sql_id(now)], read_scheduled_message)?.collect::<Result<Vec<_>, _>>()?;
+        Ok(messages)
+    }
+
+    /// Cancels a scheduled message, returning false when there is none.
+    pub fn cancel_scheduled(&self, id: u64) -> Result<bool, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let removed = connection.execute("DELETE FROM scheduled_messages WHERE id = ?1", params![sql_id(id)])?;
+        Ok(removed > 0)
+    }
+
+    /// Removes the messages of a channel that `rules` do not keep at time
+    /// `now`, and the attachments they say are too old. With `dry_run`,
+    /// nothing is removed. Returns how many messages and attachments were,
+    /// or would be, removed. Pruned history is no longer taken as complete,
+    /// so it is fetched from the backend again when asked for.
+    pub fn prune(
+        &self,
+        service: &str,
+        channel_id: &str,
+        rules: &RetentionRules,
+        now: i64,
+        dry_run: bool,
+    ) -> Result<(usize, usize), StoreError> {
+        let days_ago = |days: u64| now.saturating_sub((days as i64).saturating_mul(86400));
+        let keep_starred = rules.keep_starred.unwrap_or(true);
+        let (max_age, max_messages) = (rules.max_age_days.map(days_ago), rules.max_messages.map(|count| count as i64));
+        let attachments_max_age = rules.attachments_max_age_days.map(days_ago);
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let pruned: Vec<i64> = transaction
+            .prepare(
+                "SELECT message_id FROM messages
+                 WHERE service = ?1 AND channel_id = ?2 AND (starred = 0 OR NOT ?3)
+                   AND (timestamp < ?4
+                        OR (?5 IS NOT NULL AND message_id <= (
+                            SELECT message_id FROM messages
+                            WHERE service = ?1 AND channel_id = ?2 AND (starred = 0 OR NOT ?3)
+                            ORDER BY message_id DESC LIMIT 1 OFFSET coalesce(?5, 0))))",
+            )?
+            .query_map(params![service, channel_id, keep_starred, max_age, max_messages], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        // The ids are handed to SQLite as a JSON array, read with `json_each`.
+        let pruned_ids = serde_json::to_string(&pruned).expect("ids serialize");
+        let doomed_attachments = "FROM attachments WHERE service = ?1 AND channel_id = ?2
+             AND (message_id IN (SELECT value FROM json_each(?3))
+                  OR (SELECT timestamp FROM messages
+                      WHERE messages.service = ?1 AND messages.channel_id = ?2
+                        AND messages.message_id = attachments.message_id) < ?4)";
+        let attachment_params = params![service, channel_id, pruned_ids, attachments_max_age];
+        let files: Vec<String> = transaction
+            .prepare(&format!("SELECT path {}", doomed_attachments))?
+            .query_map(attachment_params, |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        if dry_run {
+            return Ok((pruned.len(), files.len()));
+        }
+
+        transaction.execute(&format!("DELETE {}", doomed_attachments), attachment_params)?;
+        for table in ["messages", "edits", "reactions"] {
+            let sql = format!(
+                "DELETE FROM {} WHERE service = ?1 AND channel_id = ?2 AND message_id IN (SELECT value FROM json_each(?3))",
+                table
+            );
+            transaction.execute(&sql, params![service, channel_id, pruned_ids])?;
+        }
+        if let Some(newest_pruned) = pruned.iter().max() {
+            transaction.execute(
+                "DELETE FROM coverage WHERE service = ?1 AND channel_id = ?2 AND newest <= ?3",
+                params![service, channel_id, newest_pruned],
+            )?;
+            transaction.execute(
+                "UPDATE coverage SET oldest = ?3 + 1 WHERE service = ?1 AND channel_id = ?2 AND oldest <= ?3",
+                params![service, channel_id, newest_pruned],
+            )?;
+        }
+        transaction.commit()?;
+        for file in &files {
+            if let Err(e) = std::fs::remove_file(file) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    eprintln!("Failed to remove attachment {}: {}", file, e);
+                }
+            }
+        }
+        Ok((pruned.len(), files.len()))
+    }
+
+    /// Forgets where the live streams of a service were, since messages may
+    /// be missed until it is connected again.
+    pub fn end_session(&self, service: &str) {
+        self.live.lock().unwrap().retain(|(streamed, _), _| streamed != service);
+    }
+
+    /// Runs `call` on the blocking thread pool, since SQLite calls block the
+    /// thread they run on.
+    pub async fn blocking<T: Send + 'static>(self: &Arc<Self>, call: impl FnOnce(&Store) -> T + Send + 'static) -> T {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || call(&store)).await.expect("store calls do not panic")
+    }
+
+    /// Returns up to `limit` messages of a channel older than message
+    /// `before`, or the latest ones. They are served from the store when it
+    /// has them all; otherwise the missing page is fetched from the backend
+    /// and stored first.
+    pub async fn fetch_history(
+        self: &Arc<Self>,
+        service: &str,
+        backend: &SharedBackend,
+        channel_id: &str,
+        before: Option<u64>,
+        limit: usize,
+    ) -> Result<History, StoreError> {
+        let top = match before {
+            Some(0) => return Ok(History { messages: Vec::new(), complete: true }),
+            Some(before) => Some(before - 1),
+            None => self.live.lock().unwrap().get(&(service.to_string(), channel_id.to_string())).copied(),
+        };
+        let (owned_service, owned_channel_id) = (service.to_string(), channel_id.to_string());
+        if let Some(top) = top {
+            let (service, channel_id) = (owned_service.clone(), owned_channel_id.clone());
+            let stored = self.blocking(move |store| store.stored_history(&service, &channel_id, top, limit)).await?;
+            if stored.complete {
+                return Ok(stored);
+            }
+        }
+
+        let page = match backend.fetch_history(channel_id, before, limit).await {
+            Ok(page) => page,
+            Err(e) => {
+                let stored = self
+                    .blocking(move |store| store.latest_stored_history(&owned_service, &owned_channel_id, top, limit))
+                    .await?;
+                if stored.messages.is_empty() {
+                    return Err(StoreError::History(e));
+                }
+                eprintln!("Serving stored history of {} {}: {}", service, channel_id, e);
+                return Ok(stored);
+            }
+        };
+        let Some(top) = top.or(page.iter().map(|message| message.id).max()) else {
+            return Ok(History { messages: Vec::new(), complete: true });
+        };
+        self.blocking(move |store| {
+            store.store_page(&owned_service, &owned_channel_id, &page, top, limit)?;
+            store.stored_history(&owned_service, &owned_channel_id, top, limit)
+        })
+        .await
+    }
+
+    /// Stores a page of up to `limit` messages fetched from the backend, up
+    /// to id `top`, and records that the store holds every message it spans.
+    fn store_page(&self, service: &str, channel_id: &str, page: &[Message], top: u64, limit: usize) -> Result<(), StoreError> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        for message in page {
+            insert_message(&transaction, service, message)?;
+        }
+        let oldest = match page.iter().map(|message| message.id).min() {
+            Some(oldest) if page.len() >= limit => oldest,
+            _ => 0,
+        };
+        cover(&transaction, service, channel_id, oldest, top)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Reads up to `limit` stored messages up to id `top`, or the newest
+    /// ones, for when the backend cannot fill in what is missing.
+    fn latest_stored_history(&self, service: &str, channel_id: &str, top: Option<u64>, limit: usize) -> Result<History, StoreError> {
+        match top.or(self.newest_stored(service, channel_id)?) {
+            Some(top) => self.stored_history(service, channel_id, top, limit),
+            None => Ok(History { messages: Vec::new(), complete: false }),
+        }
+    }
+
+    /// Fetches every message of a channel missing from the store, back to
+    /// the start of the channel or as far as the backend can go.
+    pub async fn backfill(self: &Arc<Self>, service: &str, backend: &SharedBackend, channel_id: &str) -> Result<(), StoreError> {
+        let mut before = None;
+        loop {
+            let history = self.fetch_history(service, backend, channel_id, before, BACKFILL_PAGE).await?;
+            match history.messages.first() {
+                Some(oldest) if history.complete && history.messages.len() >= BACKFILL_PAGE => before = Some(oldest.id),
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Reads up to `limit` stored messages up to id `top`.
+    fn stored_history(&self, service: &str, channel_id: &str, top: u64, limit: usize) -> Result<History, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let covered: Option<i64> = connection
+            .query_row(
+                "SELECT oldest FROM coverage WHERE service = ?1 AND channel_id = ?2 AND oldest <= ?3 AND newest >= ?3",
+                params![service, channel_id, sql_id(top)],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let mut statement = connection.prepare(
+            "SELECT message_id, author, body, timestamp FROM messages
+             WHERE service = ?1 AND channel_id = ?2 AND message_id <= ?3 AND deleted = 0
+             ORDER BY message_id DESC LIMIT ?4",
+        )?;
+        let mut messages = statement
+            .query_map(params![service, channel_id, sql_id(top), limit as i64], |row| read_message(channel_id, row))?
+            .collect::<Result<Vec<_>, _>>()?;
+        load_attachments(&connection, service, &mut messages)?;
+        let complete = match (covered, messages.last()) {
+            (Some(0), _) => true,
+            (Some(oldest), Some(last)) => messages.len() >= limit && sql_id(last.id) >= oldest,
+            _ => false,
+        };
+        messages.reverse();
+        Ok(History { messages, complete })
+    }
+
+    /// Returns the best `limit` matches of a query, best first, with the
+    /// matched words in their snippets between `highlight` markers.
+    pub fn search(&self, query: &SearchQuery, limit: usize, highlight: (&str, &str)) -> Result<Vec<SearchHit>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT message_search.service, message_search.channel_id, channel, message_search.message_id,
+                    message_search.author, snippet(message_search, -1, ?2, ?3, '…', 16), messages.timestamp
+             FROM message_search JOIN messages ON messages.rowid = message_search.rowid
+             WHERE message_search MATCH ?1
+               AND (?4 IS NULL OR message_search.service = ?4)
+               AND (?5 IS NULL OR messages.timestamp < ?5)
+               AND (?6 IS NULL OR messages.timestamp >= ?6)
+             ORDER BY bm25(message_search) LIMIT ?7",
+        )?;
+        let parameters =
+            params![query.expression, highlight.0, highlight.1, query.service, query.before, query.after, limit as i64];
+        let hits = statement
+            .query_map(parameters, |row| {
+                Ok(SearchHit {
+                    service: row.get(0)?,
+                    channel_id: row.get(1)?,
+                    channel_name: row.get(2)?,
+                    message_id: row.get::<_, i64>(3)? as u64,
+                    author: row.get(4)?,
+                    snippet: row.get(5)?,
+                    timestamp: row.get::<_, Option<i64>>(6)?.map(|timestamp| timestamp as u64),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(hits)
+    }
+
+    /// The channels of a service: those it listed, and any other that
+    /// messages were stored for, named after their id.
+    pub fn channels(&self, service: &str) -> Result<Vec<Channel>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT channel_id, name, direct FROM channels WHERE service = ?1
+             UNION
+             SELECT DISTINCT channel_id, channel_id, 0 FROM messages
+             WHERE service = ?1 AND channel_id NOT IN (SELECT channel_id FROM channels WHERE service = ?1)
+             ORDER BY 2",
+        )?;
+        let channels = statement
+            .query_map(params![service], |row| Ok(Channel { id: row.get(0)?, name: row.get(1)?, direct: row.get(2)? }))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(channels)
+    }
+
+    /// Every stored message of a channel that was not deleted, oldest first.
+    pub fn messages(&self, service: &str, channel_id: &str) -> Result<Vec<Message>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT message_id, author, body, timestamp FROM messages
+             WHERE service = ?1 AND channel_id = ?2 AND deleted = 0
+             ORDER BY message_id",
+        )?;
+        let mut messages = statement
+            .query_map(params![service, channel_id], |row| read_message(channel_id, row))?
+            .collect::<Result<Vec<_>, _>>()?;
+        load_attachments(&connection, service, &mut messages)?;
+        Ok(messages)
+    }
+
+    fn newest_stored(&self, service: &str, channel_id: &str) -> Result<Option<u64>, StoreError> {
+        let connection = self.connection.lock().unwrap();
+        let newest: Option<i64> = connection.query_row(
+            "SELECT max(message_id) FROM messages WHERE service = ?1 AND channel_id = ?2",
+            params![service, channel_id],
+            |row| row.get(0),
+        )?;
+        Ok(newest.map(|id| id as u64))
+    }
+}
+
+/// Reads a row of `id, service, channel_id, body, send_at`.
+fn read_scheduled_message(row: &rusqlite::Row) -> rusqlite::Result<ScheduledMessage> {
+    Ok(ScheduledMessage {
+        id: row.get::<_, i64>(0)? as u64,
+        service: row.get(1)?,
+        channel_id: row.get(2)?,
+        body: row.get(3)?,
+        send_at: row.get::<_, i64>(4)? as u64,
+    })
+}
+
+/// Reads a row of the `inbox` table, in the order of its columns.
+fn read_inbox_item(row: &rusqlite::Row) -> rusqlite::Result<InboxItem> {
+    let reason: String = row.get(8)?;
+    Ok(InboxItem {
+        id: row.get::<_, i64>(0)? as u64,
+        service: row.get(1)?,
+        channel_id: row.get(2)?,
+        channel_name: row.get(3)?,
+        message_id: row.get::<_, i64>(4)? as u64,
+        author: row.get(5)?,
+        body: row.get(6)?,
+        timestamp: row.get::<_, i64>(7)? as u64,
+        reason: InboxReason::parse(&reason).ok_or_else(|| {
+            rusqlite::Error::FromSqlConversionFailure(8, rusqlite::types::Type::Text, format!("unknown inbox reason {reason}").into())
+        })?,
+        read: row.get(9)?,
+    })
+}
+
+/// Reads a row of `message_id, author, body, timestamp`.
+fn read_message(channel_id: &str, row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    Ok(Message {
+        id: row.get::<_, i64>(0)? as u64,
+        channel_id: channel_id.to_string(),
+        author: row.get(1)?,
+        content: row.get(2)?,
+        timestamp: row.get::<_, Option<i64>>(3)?.map(|timestamp| timestamp as u64),
+        attachments: Vec::new(),
+    })
+}
+
+fn load_attachments(connection: &Connection, service: &str, messages: &mut [Message]) -> rusqlite::Result<()> {
+    let mut statement = connection.prepare_cached(
+        "SELECT name, path FROM attachments WHERE service = ?1 AND channel_id = ?2 AND message_id = ?3 ORDER BY rowid",
+    )?;
+    for message in messages {
+        message.attachments = statement
+            .query_map(params![service, message.channel_id, sql_id(message.id)], |row| {
+                Ok(Attachment { name: row.get(0)?, path: row.get(1)? })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+    }
+    Ok(())
+}
+
+fn insert_channel(transaction: &Transaction, service: &str, channel: &Channel) -> rusqlite::Result<()> {
+    transaction.execute(
+        "INSERT INTO channels (service, channel_id, name, direct) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT DO UPDATE SET name = excluded.name, direct = excluded.direct",
+        params![service, channel.id, channel.name, channel.direct],
+    )?;
+    Ok(())
+}
+
+/// Stores a message, unless it was deleted since. Messages without a
+/// timestamp are dated from when they were first recorded.
+fn insert_message(transaction: &Transaction, service: &str, message: &Message) -> rusqlite::Result<()> {
+    let timestamp = message.timestamp.map(|timestamp| timestamp as i64);
+    transaction.execute(
+        "INSERT INTO messages (service, channel_id, message_id, author, body, timestamp)
+         VALUES (?1, ?2, ?3, ?4, ?5, coalesce(?6, unixepoch()))
+         ON CONFLICT DO UPDATE SET author = excluded.author, body = excluded.body, timestamp = coalesce(?6, timestamp, unixepoch())
+         WHERE deleted = 0",
+        params![service, message.channel_id, sql_id(message.id), message.author, message.content, timestamp],
+    )?;
+    for attachment in &message.attachments {
+        transaction.execute(
+            "INSERT OR IGNORE INTO attachments (service, channel_id, message_id, name, path) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![service, message.channel_id, sql_id(message.id), attachment.name, attachment.path],
+        )?;
+    }
+    Ok(())
+}
+
+/// Records that every message from `oldest` to `newest` is stored, merging
+/// the ranges that overlap or touch.
+fn cover(transaction: &Transaction, service: &str, channel_id: &str, oldest: u64, newest: u64) -> rusqlite::Result<()> {
+    let (mut oldest, mut newest) = (sql_id(oldest), sql_id(newest));
+    let touching = "service = ?1 AND channel_id = ?2 AND oldest <= ?4 + 1 AND newest + 1 >= ?3";
+    let (min, max): (Option<i64>, Option<i64>) = transaction.query_row(
+        &format!("SELECT min(oldest), max(newest) FROM coverage WHERE {}", touching),
+        params![service, channel_id, oldest, newest],
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    transaction.execute(
+        &format!("DELETE FROM coverage WHERE {}", touching),
+        params![service, channel_id, oldest, newest],
+    )?;
+    oldest = min.map_or(oldest, |min| min.min(oldest));
+    newest = max.map_or(newest, |max| max.max(newest));
+    transaction.execute(
+        "INSERT INTO coverage (service, channel_id, oldest, newest) VALUES (?1, ?2, ?3, ?4)",
+        params![service, channel_id, oldest, newest],
+    )?;
+    Ok(())
+}
+
+/// Records every service event published on the bus. A service that
+/// reconnects starts a new live session.
+pub async fn record_events(store: Arc<Store>, mut subscription: Subscription) {
+    loop {
+        match subscription.next_event().await {
+            FrontendEvent::Service(ServiceEvent { service, event }) => {
+                let recorded = store.blocking(move |store| store.record(&service, &event).map_err(|e| (service, e))).await;
+                if let Err((service, e)) = recorded {
+                    eprintln!("Failed to store an event of {}: {}", service, e);
+                }
+            }
+            FrontendEvent::Daemon(DaemonEvent::ServiceStatus { service, .. })
+            | FrontendEvent::Daemon(DaemonEvent::ServiceRemoved { service }) => store.end_session(&service),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use async_trait::async_trait;
+    use futures::{Stream, StreamExt};
+    use std::pin::Pin;
+
+    use crate::chat_backend::{Capabilities, ChatBackend, Credentials, LoginError, PostError};
+    use crate::dummy_backend::DummyBackend;
+    use crate::event_bus::{EventBus, OverflowStrategy};
+
+    /// Serves messages 1 to 10 of any channel, counting the fetches.
+    struct HistoryBackend {
+        fetches: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ChatBackend for HistoryBackend {
+        async fn login(&self, _credentials: &Credentials) -> Result<String, LoginError> {
+            Ok("session".to_string())
+        }
+
+        fn list_channels(&self) -> BackendEvent {
+            BackendEvent::ChannelList { channels: vec![] }
+        }
+
+        fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
+            Box::pin(futures::stream::pending())
+        }
+
+        async fn post_message(&self, _channel_id: &str, _content: &str, _idempotency_key: &str) -> Result<(), PostError> {
+            Ok(())
+        }
+
+        async fn fetch_history(&self, channel_id: &str, before: Option<u64>, limit: usize) -> Result<Vec<Message>, HistoryError> {
+            self.fetches.fetch_add(1, Ordering::SeqCst);
+            let end = before.unwrap_or(11).min(11);
+            let start = end.saturating_sub(limit as u64).max(1);
+            Ok((start..end)
+                .map(|id| Message {
+                    id,
+                    channel_id: channel_id.to_string(),
+                    author: "old".to_string(),
+                    content: format!("message {}", id),
+                    timestamp: None,
+                    attachments: Vec::new(),
+                })
+                .collect())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+    }
+
+    fn message(id: u64, body: &str) -> BackendEvent {
+        BackendEvent::Message {
+            channel_id: "general".to_string(),
+            message_id: id,
+            body: body.to_string(),
+            author: "someone".to_string(),
+            attachments: Vec::new(),
+            idempotency_key: None,
+        }
+    }
+
+    fn ids(history: &History) -> Vec<u64> {
+        history.messages.iter().map(|message| message.id).collect()
+    }
+
+    #[tokio::test]
+    async fn test_history_is_served_from_the_store_first() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let backend: SharedBackend = Arc::new(HistoryBackend { fetches: fetches.clone() });
+        let store = Arc::new(Store::in_memory().unwrap());
+        for id in 11..=13 {
+            store.record("chat", &message(id, "live")).unwrap();
+        }
+
+        // The live session covers 11 to 13.
+        let history = store.fetch_history("chat", &backend, "general", None, 3).await.unwrap();
+        assert_eq!((ids(&history), history.complete), (vec![11, 12, 13], true));
+        assert_eq!(fetches.load(Ordering::SeqCst), 0);
+
+        // Older messages are missing, so they are fetched once, then stored.
+        let history = store.fetch_history("chat", &backend, "general", Some(11), 4).await.unwrap();
+        assert_eq!((ids(&history), history.complete), (vec![7, 8, 9, 10], true));
+        store.fetch_history("chat", &backend, "general", Some(11), 4).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+
+        // Reaching the start of the channel is remembered too.
+        let history = store.fetch_history("chat", &backend, "general", Some(7), 10).await.unwrap();
+        assert_eq!(ids(&history), [1, 2, 3, 4, 5, 6]);
+        let history = store.fetch_history("chat", &backend, "general", Some(14), 20).await.unwrap();
+        assert_eq!((history.messages.len(), history.complete), (13, true));
+        assert_eq!(fetches.load(Ordering::SeqCst), 2);
+
+        // A reconnection leaves a gap above the last streamed message.
+        store.end_session("chat");
+        store.record("chat", &message(20, "after reconnecting")).unwrap();
+        store.fetch_history("chat", &backend, "general", None, 5).await.unwrap();
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+    }
+
+    // Test that a burst larger than the bus queues still reaches the store
+    // whole, leaving no gap in the live session.
+    #[tokio::test]
+    async fn test_bursts_are_recorded_without_gaps() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let backend: SharedBackend = Arc::new(HistoryBackend { fetches: fetches.clone() });
+        let store = Arc::new(Store::in_memory().unwrap());
+        let events = EventBus::with_queue(2, OverflowStrategy::Disconnect);
+        let subscription = events.subscribe_lossless("store");
+        for id in 11..=20 {
+            events.publish(ServiceEvent::new("chat", message(id, "burst")));
+        }
+        tokio::spawn(record_events(store.clone(), subscription));
+
+        for _ in 0..100 {
+            if store.newest_stored("chat", "general").unwrap() == Some(20) {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let history = store.fetch_history("chat", &backend, "general", None, 10).await.unwrap();
+        assert_eq!((ids(&history), history.complete), ((11..=20).collect(), true));
+        assert_eq!(fetches.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_posted_messages_are_kept_in_history() {
+        let backend: SharedBackend = Arc::new(DummyBackend::new());
+        let store = Arc::new(Store::in_memory().unwrap());
+        backend.post_message("dummy_channel1", "first", "key 1").await.unwrap();
+        backend.post_message("dummy_channel1", "second", "key 2").await.unwrap();
+        let mut stream = backend.get_messages();
+        for _ in 0..3 {
+            store.record("dummy", &stream.next().await.unwrap()).unwrap();
+        }
+        let history = store.fetch_history("dummy", &backend, "dummy_channel1", None, 4).await.unwrap();
+        let bodies: Vec<_> = history.messages.iter().map(|message| message.content.as_str()).collect();
+        assert_eq!(bodies[..3], ["Old message: 100", "first", "second"]);
+        assert!(bodies[3].starts_with("Random message"));
+
+        // A message without an id neither overwrites another nor covers history.
+        store.record("dummy", &message(0, "no id")).unwrap();
+        let history = store.stored_history("dummy", "general", 0, 10).unwrap();
+        assert_eq!((history.messages.len(), history.complete), (0, false));
+    }
+
+    #[test]
+    fn test_search() {
+        let store = Store::in_memory().unwrap();
+        let channels = vec![crate::chat_backend::Channel { id: "c1".to_string(), name: "Dev Team".to_string(), direct: false }];
+        store.record("work", &BackendEvent::ChannelList { channels }).unwrap();
+        let post = |service: &str, id: u64, author: &str, body: &str, timestamp: u64| {
+            let message = Message {
+                id,
+                channel_id: "c1".to_string(),
+                author: author.to_string(),
+                content: body.to_string(),
+                timestamp: Some(timestamp),
+                attachments: Vec::new(),
+            };
+            let mut connection = store.connection.lock().unwrap();
+            let transaction = connection.transaction().unwrap();
+            insert_message(&transaction, service, &message).unwrap();
+            transaction.commit().unwrap();
+        };
+        post("work", 1, "Alice", "The deployment is done", 1_700_000_000);
+        post("work", 2, "Bob", "Deploying the release notes now", 1_710_000_000);
+        post("home", 3, "Alice", "release notes, release notes everywhere", 1_720_000_000);
+
+        let search = |query: &str| {
+            let hits = store.search(&SearchQuery::parse(query).unwrap(), 10, ("[", "]")).unwrap();
+            hits.into_iter().map(|hit| (hit.message_id, hit.snippet)).collect::<Vec<_>>()
+        };
+        assert_eq!(search("deploy*").len(), 2);
+        assert_eq!(search(r#""release notes""#)[0], (3, "[release notes], [release notes] everywhere".to_string()));
+        assert_eq!(search(r#""release notes" service:work"#), [(2, "Deploying the [release notes] now".to_string())]);
+        assert_eq!(search("deploy* from:bob").len(), 1);
+        assert_eq!(search(r#"in:"dev team" before:2024-03-01"#).len(), 1, "Only the message of 2023");
+        assert_eq!(search("notes after:2024-03-10").len(), 1);
+        assert_eq!(search("in:dev")[0].0, 1, "Channels are searched by their name");
+
+        let edited = BackendEvent::MessageEdited { channel_id: "c1".to_string(), message_id: 1, body: "Rolled back".to_string() };
+        store.record("work", &edited).unwrap();
+        assert_eq!(search("deploy*").len(), 1, "Edits are reindexed");
+        store.record("work", &BackendEvent::MessageDeleted { channel_id: "c1".to_string(), message_id: 2 }).unwrap();
+        assert!(search("deploy*").is_empty(), "Deleted messages are not found");
+    }
+
+    #[test]
+    fn test_edits_deletions_and_reactions() {
+        let store = Store::in_memory().unwrap();
+        let edited = BackendEvent::MessageEdited { channel_id: "general".to_string(), message_id: 1, body: "fixed".to_string() };
+        let reaction = |added| {
+            let (channel_id, author, reaction) = ("general".to_string(), "me".to_string(), "+1".to_string());
+            match added {
+                true => BackendEvent::ReactionAdded { channel_id, message_id: 1, author, reaction },
+                false => BackendEvent::ReactionRemoved { channel_id, message_id: 1, author, reaction },
+            }
+        };
+        store.record("chat", &message(1, "tpyo")).unwrap();
+        store.record("chat", &edited).unwrap();
+        store.record("chat", &reaction(true)).unwrap();
+        store.record("chat", &reaction(true)).unwrap();
+        store.record("chat", &message(2, "bye")).unwrap();
+        store.record("chat", &BackendEvent::MessageDeleted { channel_id: "general".to_string(), message_id: 2 }).unwrap();
+
+        let history = store.stored_history("chat", "general", 2, 10).unwrap();
+        assert_eq!(history.messages.len(), 1, "Deleted messages are left out");
+        assert_eq!(history.messages[0].content, "fixed");
+
+        let count = |sql: &str| store.connection.lock().unwrap().query_row(sql, [], |row| row.get::<_, i64>(0)).unwrap();
+        assert_eq!(count("SELECT count(*) FROM edits WHERE previous_body = 'tpyo'"), 1);
+        assert_eq!(count("SELECT count(*) FROM reactions"), 1);
+        store.record("chat", &reaction(false)).unwrap();
+        assert_eq!(count("SELECT count(*) FROM reactions"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_imported_archives_are_complete() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let backend: SharedBackend = Arc::new(HistoryBackend { fetches: fetches.clone() });
+        let store = Arc::new(Store::in_memory().unwrap());
+        let mbox = "From a\nFrom: Alice <alice@example.org>\nSubject: Budget\n\nFigures attached.\n\n\
+                    From b\nFrom: bob@example.org\n\nThanks\n\nFrom c\nFrom: alice@example.org\n\nWelcome\n";
+        let channel = Channel { id: "finance".to_string(), name: "Finance".to_string(), direct: false };
+        store.import("old_mail", &crate::archive::read_mbox(channel, mbox.as_bytes()).unwrap()).unwrap();
+
+        let history = store.fetch_history("old_mail", &backend, "finance", Some(3), 10).await.unwrap();
+        assert_eq!((ids(&history), history.complete), (vec![1, 2], true));
+        assert_eq!(fetches.load(Ordering::SeqCst), 0);
+        assert_eq!(store.channels("old_mail").unwrap()[0].name, "Finance");
+
+        let query = SearchQuery::parse("figures in:finance").unwrap();
+        let hits = store.search(&query, 10, ("[", "]")).unwrap();
+        assert_eq!((hits.len(), hits[0].author.as_str()), (1, "Alice"));
+    }
+
+    #[test]
+    fn test_prune() {
+        let store = Store::in_memory().unwrap();
+        let directory = tempfile::tempdir().unwrap();
+        let file = directory.path().join("chart.png");
+        std::fs::write(&file, "png").unwrap();
+        let day = 86400;
+        // Messages 1 to 5, sent 5 days ago to 1 day ago; the newest has an attachment.
+        let messages = (1..=5)
+            .map(|id| Message {
+                id,
+                channel_id: "general".to_string(),
+                author: "someone".to_string(),
+                content: format!("message {}", id),
+                timestamp: Some(id * day),
+                attachments: match id {
+                    5 => vec![Attachment { name: "chart.png".to_string(), path: file.display().to_string() }],
+                    _ => Vec::new(),
+                },
+            })
+            .collect();
+        store.import("chat", &Archive { channels: Vec::new(), messages }).unwrap();
+        assert!(store.set_starred("chat", "general", 1, true).unwrap());
+        assert!(!store.set_starred("chat", "general", 9, true).unwrap());
+
+        let now = 6 * day as i64;
+        let rules = RetentionRules { max_messages: Some(2), attachments_max_age_days: Some(0), ..Default::default() };
+        assert_eq!(store.prune("chat", "general", &rules, now, true).unwrap(), (2, 1));
+        assert_eq!(store.messages("chat", "general").unwrap().len(), 5, "A dry run removes nothing");
+
+        assert_eq!(store.prune("chat", "general", &rules, now, false).unwrap(), (2, 1));
+        let kept: Vec<u64> = store.messages("chat", "general").unwrap().iter().map(|message| message.id).collect();
+        assert_eq!(kept, [1, 4, 5], "Starred messages are kept");
+        assert!(!file.exists());
+        assert!(store.stored_history("chat", "general", 5, 2).unwrap().complete);
+        assert!(!store.stored_history("chat", "general", 5, 3).unwrap().complete, "Pruned history is fetched again");
+
+        let rules = RetentionRules { max_age_days: Some(2), keep_starred: Some(false), ..Default::default() };
+        assert_eq!(store.prune("chat", "general", &rules, now, false).unwrap(), (1, 0));
+        assert_eq!(store.messages("chat", "general").unwrap()[0].id, 4);
+    }
+
+    // Test that kept starred messages do not count towards `max_messages`.
+    #[test]
+    fn test_prune_keeps_max_messages_besides_starred() {
+        let store = Store::in_memory().unwrap();
+        for id in 1..=5 {
+            store.record("chat", &message(id, "hello")).unwrap();
+        }
+        for id in [4, 5] {
+            assert!(store.set_starred("chat", "general", id, true).unwrap());
+        }
+        let rules = RetentionRules { max_messages: Some(2), ..Default::default() };
+        assert_eq!(store.prune("chat", "general", &rules, 0, false).unwrap(), (1, 0));
+        let kept: Vec<u64> = store.messages("chat", "general").unwrap().iter().map(|message| message.id).collect();
+        assert_eq!(kept, [2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_inbox() {
+        let store = Store::in_memory().unwrap();
+        let message = |channel_id: &str, id: u64| Message {
+            id,
+            channel_id: channel_id.to_string(),
+            author: "bob".to_string(),
+            content: format!("message {}", id),
+            timestamp: Some(id),
+            attachments: Vec::new(),
+        };
+        for id in 1..=3 {
+            let item = store.add_to_inbox("chat", "bob", &message("D1", id), InboxReason::DirectMessage).unwrap();
+            assert_eq!(item.map(|item| (item.message_id, item.read)), Some((id, false)));
+        }
+        let mention = store.add_to_inbox("mail", "INBOX", &message("f1", 7), InboxReason::Folder).unwrap().unwrap();
+        assert_eq!(mention.id, 4);
+        assert!(store.add_to_inbox("chat", "bob", &message("D1", 2), InboxReason::DirectMessage).unwrap().is_none());
+
+        let (items, unread) = store.inbox(None, 2, false).unwrap();
+        assert_eq!(items.iter().map(|item| item.id).collect::<Vec<_>>(), [4, 3], "Newest first");
+        assert_eq!(unread, 4);
+        let (items, _) = store.inbox(Some(3), 50, false).unwrap();
+        assert_eq!(items.iter().map(|item| item.id).collect::<Vec<_>>(), [2, 1]);
+
+        assert_eq!(store.mark_read("chat", "D1", 2).unwrap(), 2);
+        assert_eq!(store.mark_read("chat", "D1", 2).unwrap(), 0);
+        let (items, unread) = store.inbox(None, 50, true).unwrap();
+        assert_eq!(items.iter().map(|item| (item.service.as_str(), item.message_id)).collect::<Vec<_>>(), [("mail", 7), ("chat", 3)]);
+        assert_eq!(unread, 2);
+    }
+}
@@ -0,0 +1,211 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::Serialize;
+use tokio::time::{sleep, Instant};
+
+use crate::chat_backend::{ConfiguredService, LoginError};
+use crate::event_bus::EventBus;
+use crate::protocol::{DaemonEvent, ServiceEvent};
+
+/// A connection that stayed up this long is considered healthy again, so
+/// the next failure starts over from the shortest delay.
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+
+/// The state of a service's connection, as reported in `service_status` events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ServiceStatus {
+    /// Logging in and subscribing to events.
+    Connecting,
+    Connected,
+    /// The connection was lost or could not be made; a retry is scheduled.
+    Degraded,
+    /// The service was stopped and will not reconnect.
+    Disconnected,
+    /// The credentials were rejected. They are resolved again on the next
+    /// attempt, so fixing the secret is enough.
+    AuthFailed,
+}
+
+/// Jittered exponential backoff between reconnection attempts.
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self { initial, max, attempt: 0 }
+    }
+
+    /// The delay before the next attempt: doubling with every failure up to
+    /// `max`, with the upper half randomized so that services which failed
+    /// together do not all retry at the same moment.
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.initial.saturating_mul(2u32.saturating_pow(self.attempt)).min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = ceiling / 2;
+        half + half.mul_f64(rand::rng().random::<f64>())
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), Duration::from_secs(300))
+    }
+}
+
+fn status_event(service: &str, status: ServiceStatus, reason: Option<String>, retry_in: Option<Duration>) -> DaemonEvent {
+    DaemonEvent::ServiceStatus {
+        service: service.to_string(),
+        status,
+        reason,
+        retry_in_ms: retry_in.map(|delay| delay.as_millis() as u64),
+    }
+}
+
+/// Keeps a service connected for as long as it runs: logs in, streams its
+/// events, and when either fails logs in and subscribes again after a
+/// backoff delay. Stopping the service means aborting this task.
+pub async fn supervise(name: String, service: ConfiguredService, events: EventBus, mut backoff: Backoff) {
+    loop {
+        events.publish(status_event(&name, ServiceStatus::Connecting, None, None));
+        let (status, reason) = match service.login().await {
+            Ok(_) => {
+                events.publish(status_event(&name, ServiceStatus::Connected, None, None));
+                let connected_at = Instant::now();
+                stream_events(&name, &service, &events).await;
+                if connected_at.elapsed() >= STABLE_CONNECTION {
+                    backoff.reset();
+                }
+                (ServiceStatus::Degraded, "Event stream ended".to_string())
+            }
+            Err(e @ LoginError::InvalidCredentials) => (ServiceStatus::AuthFailed, e.to_string()),
+            Err(e) => (ServiceStatus::Degraded, format!("Failed to log in: {}", e)),
+        };
+        let delay = backoff.next_delay();
+        eprintln!("Service {}: {}, retrying in {:?}", name, reason, delay);
+        events.publish(status_event(&name, status, Some(reason), Some(delay)));
+        sleep(delay).await;
+    }
+}
+
+/// Streams events for a single backend instance until its stream ends,
+/// tagging each one with the name of the service it belongs to.
+async fn stream_events(name: &str, service: &ConfiguredService, events: &EventBus) {
+    // Send the initial channel list event.
+    events.publish(ServiceEvent::new(name, service.backend.list_channels()));
+
+    let mut stream = service.backend.get_messages();
+    while let Some(event) = stream.next().await {
+        events.publish(ServiceEvent::new(name, event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use futures::Stream;
+
+    use crate::chat_backend::{BackendEvent, Capabilities, ChatBackend, Credentials, PostError};
+    use crate::protocol::FrontendEvent;
+    use crate::secrets::{CredentialSource, SecretSource};
+
+    /// Fails the first login, then hands out streams that end after one message.
+    struct FlakyBackend {
+        logins: Arc<AtomicU32>,
+    }
+
+    #[async_trait]
+    impl ChatBackend for FlakyBackend {
+        async fn login(&self, _credentials: &Credentials) -> Result<String, LoginError> {
+            match self.logins.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(LoginError::ConnectionError("unreachable".to_string())),
+                _ => Ok("session".to_string()),
+            }
+        }
+
+        fn list_channels(&self) -> BackendEvent {
+            BackendEvent::ChannelList { channels: vec![] }
+        }
+
+        fn get_messages(&self) -> Pin<Box<dyn Stream<Item = BackendEvent> + Send>> {
+            let message = BackendEvent::Message {
+                channel_id: "general".to_string(),
+                message_id: 1,
+                body: "hi".to_string(),
+                author: "someone".to_string(),
+                attachments: Vec::new(),
+                idempotency_key: None,
+            };
+            Box::pin(futures::stream::iter([message]))
+        }
+
+        async fn post_message(&self, _channel_id: &str, _content: &str, _idempotency_key: &str) -> Result<(), PostError> {
+            Ok(())
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+    }
+
+    #[test]
+    fn test_backoff_grows_and_stays_bounded() {
+        let mut backoff = Backoff::new(Duration::from_millis(100), Duration::from_millis(1000));
+        let delays: Vec<Duration> = (0..8).map(|_| backoff.next_delay()).collect();
+        for (attempt, delay) in delays.iter().enumerate() {
+            let ceiling = Duration::from_millis(100 * 2u64.pow(attempt as u32)).min(Duration::from_millis(1000));
+            assert!(*delay >= ceiling / 2 && *delay <= ceiling, "attempt {}: {:?}", attempt, delay);
+        }
+        backoff.reset();
+        assert!(backoff.next_delay() <= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_supervisor_reconnects() {
+        let logins = Arc::new(AtomicU32::new(0));
+        let service = ConfiguredService::new("flaky", Box::new(FlakyBackend { logins: logins.clone() }))
+            .with_credentials(Some(CredentialSource::Token {
+                username: None,
+                source: SecretSource::Env("KBUNIFIED_TEST_SUPERVISOR_TOKEN".to_string()),
+            }));
+        std::env::set_var("KBUNIFIED_TEST_SUPERVISOR_TOKEN", "token");
+        let events = EventBus::new();
+        let mut receiver = events.subscribe("test");
+        let backoff = Backoff::new(Duration::from_millis(1), Duration::from_millis(5));
+        let task = tokio::spawn(supervise("flaky".to_string(), service, events, backoff));
+
+        let mut statuses = Vec::new();
+        let mut messages = 0;
+        while messages < 2 {
+            match receiver.recv().await.unwrap() {
+                FrontendEvent::Daemon(DaemonEvent::ServiceStatus { status, retry_in_ms, .. }) => {
+                    assert_eq!(retry_in_ms.is_some(), status == ServiceStatus::Degraded);
+                    statuses.push(status);
+                }
+                FrontendEvent::Service(ServiceEvent { event: BackendEvent::Message { .. }, .. }) => messages += 1,
+                _ => {}
+            }
+        }
+        task.abort();
+
+        use ServiceStatus::*;
+        assert_eq!(statuses[..6], [Connecting, Degraded, Connecting, Connected, Degraded, Connecting]);
+        assert!(logins.load(Ordering::SeqCst) >= 3, "Every reconnect logs in again");
+    }
+}
@@ -0,0 +1,115 @@
+/// The Unix timestamp of `seconds` into a UTC day, or `None` when the
+/// year is outside 0 to 9999 or the result does not fit.
+pub fn timestamp(year: i64, month: i64, day: i64, seconds: i64) -> Option<i64> {
+    if !(0..=9999).contains(&year) {
+        return None;
+    }
+    days_from_civil(year, month, day).checked_mul(86400)?.checked_add(seconds)
+}
+
+/// Days since the Unix epoch of a date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    // Years are counted from March, so that leap days come last.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// A Unix timestamp broken down into its UTC calendar date and time.
+pub struct Utc {
+    year: i64,
+    month: usize,
+    day: i64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    /// 0 for Sunday.
+    weekday: usize,
+}
+
+const WEEKDAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+impl Utc {
+    pub fn new(timestamp: u64) -> Self {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+        // The inverse of `days_from_civil`.
+        let shifted = days + 719468;
+        let era = shifted.div_euclid(146097);
+        let day_of_era = shifted - era * 146097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let shifted_month = (5 * day_of_year + 2) / 153;
+        let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+        Utc {
+            year: year_of_era + era * 400 + if month <= 2 { 1 } else { 0 },
+            month: month as usize,
+            day: day_of_year - (153 * shifted_month + 2) / 5 + 1,
+            hour: seconds / 3600,
+            minute: seconds / 60 % 60,
+            second: seconds % 60,
+            // 1970-01-01 was a Thursday.
+            weekday: (days + 4).rem_euclid(7) as usize,
+        }
+    }
+
+    /// E.g. `2024-03-01 12:00 UTC`.
+    pub fn readable(&self) -> String {
+        format!("{}-{:02}-{:02} {:02}:{:02} UTC", self.year, self.month, self.day, self.hour, self.minute)
+    }
+
+    /// E.g. `Fri, 01 Mar 2024 12:00:00 +0000`.
+    pub fn rfc2822(&self) -> String {
+        format!(
+            "{}, {:02} {} {} {:02}:{:02}:{:02} +0000",
+            WEEKDAYS[self.weekday],
+            self.day,
+            MONTHS[self.month - 1],
+            self.year,
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// E.g. `Fri Mar  1 12:00:00 2024`, as in mbox separator lines.
+    pub fn asctime(&self) -> String {
+        format!(
+            "{} {} {:2} {:02}:{:02}:{:02} {}",
+            WEEKDAYS[self.weekday],
+            MONTHS[self.month - 1],
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+            self.year
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(1970, 1, 1, 0), Some(0));
+        assert_eq!(timestamp(2024, 3, 1, 12 * 3600), Some(1709294400));
+        assert_eq!(timestamp(1969, 12, 31, 0), Some(-86400));
+        assert_eq!(timestamp(i64::MAX, 1, 1, 0), None);
+        assert_eq!(timestamp(2024, 3, 1, i64::MAX), None);
+    }
+
+    #[test]
+    fn test_dates() {
+        let date = Utc::new(1709294400);
+        assert_eq!(date.readable(), "2024-03-01 12:00 UTC");
+        assert_eq!(date.rfc2822(), "Fri, 01 Mar 2024 12:00:00 +0000");
+        assert_eq!(date.asctime(), "Fri Mar  1 12:00:00 2024");
+        assert_eq!(Utc::new(0).rfc2822(), "Thu, 01 Jan 1970 00:00:00 +0000");
+    }
+}